@@ -1,136 +1,632 @@
 use std::os::unix::ffi::OsStringExt;
 use std::{
     ffi::OsString,
+    os::fd::RawFd,
     sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
 };
 
-use crate::{OutputContext, OutputsContexts};
+use crate::decoration::Decorations;
+use crate::error::{self, Error};
+use crate::theme::{self, Theme};
+use crate::ipc;
+use crate::lifecycle::{Redraw, Shutdown};
+use crate::modules::Module;
+use crate::output::BarConfig;
+use crate::render;
+use crate::schedule::Scheduler;
+use crate::visibility;
+use crate::wakeup::Wakeups;
+use crate::widget::{DrawContext, Widget};
+use crate::{OutputContext, OutputsContexts, SharedConnection, SharedRiverStatus};
 
-fn gcd(mut x: i32, mut y: i32) -> i32 {
-    let mut temp;
-    while y > 0 {
-        temp = x % y;
+pub enum Source {
+    Command(std::process::Command),
+    Module(Box<dyn Module>),
+}
+
+// Text shown when pointer rests on the block
+pub enum TooltipSource {
+    None,
+    Static(String),
+    // Source prints the tooltip on the second line of it's output
+    SecondLine,
+    // Run by main thread only when the tooltip is shown
+    Command(Arc<Mutex<std::process::Command>>),
+}
 
-        x = y;
-        y = temp;
+impl TooltipSource {
+    pub fn command(command: std::process::Command) -> Self {
+        Self::Command(Arc::new(Mutex::new(command)))
     }
-    return x;
 }
 
 pub struct Block {
+    pub name: String,
+    // Prepended to output of a module, commands print their own icons
     pub icon: String,
-    pub command: std::process::Command,
-    pub interval: u32,
+    pub source: Source,
+    // Zero for blocks that aren't updated on time
+    pub interval: Duration,
+    // Updates fall on multiples of interval in wall-clock time
+    pub aligned: bool,
     pub signal: libc::c_int,
+    pub tooltip: TooltipSource,
+    // Opened by left click, taken by main thread before blocks thread starts
+    pub popup: Option<BlockPopup>,
+    // Runtime state that can be changed over IPC
+    output: OsString,
+    tooltip_text: String,
+    hidden: bool,
+    // Last run of the source failed, error marker is shown instead of output
+    failed: bool,
+    // Output changed since it was drawn
+    dirty: bool,
+    // Text and width it was last measured with, it's laid out again only when the text changes
+    measured: Option<(String, i32)>,
+}
+
+// Nerd font warning sign, shown with name of the block whose source failed
+const ERROR_MARKER: &str = "\u{f071}";
+
+/// What left click on a block opens
+pub enum BlockPopup {
+    Calendar,
+    // Labels and commands they run
+    Menu(Vec<(String, std::process::Command)>),
+}
+
+// Block as drawn on an output, main thread uses it for pointer hit testing
+#[derive(Clone, Debug)]
+pub struct BlockSpan {
+    pub start: i32,
+    pub end: i32,
+    pub name: String,
+    pub tooltip: String,
+    // Prints the tooltip, used when text is empty
+    pub tooltip_command: Option<Arc<Mutex<std::process::Command>>>,
+}
+
+impl BlockSpan {
+    pub fn has_tooltip(&self) -> bool {
+        !self.tooltip.is_empty() || self.tooltip_command.is_some()
+    }
 }
 
 type SignalFD = libc::c_int;
 
 impl Block {
-    pub fn run_and_get_output(&mut self) -> OsString {
-        let output = self.command.output().unwrap().stdout;
-        OsString::from_vec(output)
+    pub fn new(name: &str, source: Source) -> Self {
+        Self {
+            name: name.to_string(),
+            icon: String::new(),
+            source,
+            interval: Duration::ZERO,
+            aligned: false,
+            signal: 0,
+            tooltip: TooltipSource::None,
+            popup: None,
+            output: OsString::new(),
+            tooltip_text: String::new(),
+            hidden: false,
+            failed: false,
+            dirty: true,
+            measured: None,
+        }
+    }
+
+    pub fn with_icon(mut self, icon: &str) -> Self {
+        self.icon = icon.to_string();
+        self
+    }
+
+    /// Updates block every interval seconds
+    pub fn with_interval(self, interval: u32) -> Self {
+        self.with_interval_ms(interval as u64 * 1000)
+    }
+
+    pub fn with_interval_ms(mut self, interval: u64) -> Self {
+        self.interval = Duration::from_millis(interval);
+        self
+    }
+
+    /// Updates block on wall-clock boundaries of it's interval, e.g. clock
+    /// with 60 second interval changes exactly on the minute
+    pub fn aligned(mut self) -> Self {
+        self.aligned = true;
+        self
+    }
+
+    /// Updates block on SIGRTMIN+signal
+    pub fn with_signal(mut self, signal: libc::c_int) -> Self {
+        self.signal = signal;
+        self
+    }
+
+    pub fn with_tooltip(mut self, tooltip: TooltipSource) -> Self {
+        self.tooltip = tooltip;
+        self
+    }
+
+    pub fn with_popup(mut self, popup: BlockPopup) -> Self {
+        self.popup = Some(popup);
+        self
+    }
+
+    pub fn run_and_get_output(&mut self) -> error::Result<OsString> {
+        let output = match &mut self.source {
+            Source::Command(command) => {
+                let output = command.output().map_err(|source| Error::Spawn {
+                    block: self.name.clone(),
+                    source,
+                })?;
+                // Scripts may exit with failure after printing something useful
+                if !output.status.success() && output.stdout.is_empty() {
+                    return Err(Error::Exit {
+                        block: self.name.clone(),
+                        status: output.status,
+                    });
+                }
+                OsString::from_vec(output.stdout)
+            }
+            Source::Module(module) => {
+                let output = module.update().map_err(|source| Error::Module {
+                    block: self.name.clone(),
+                    source,
+                })?;
+                if self.icon.is_empty() {
+                    OsString::from(output)
+                } else {
+                    OsString::from(format!("{} {output}", self.icon))
+                }
+            }
+        };
+        Ok(output)
+    }
+
+    /// Runs the source and updates output and tooltip.
+    /// Failed block shows error marker with the error in it's tooltip
+    pub fn refresh(&mut self) {
+        let output = match self.run_and_get_output() {
+            Ok(output) => output,
+            Err(err) => {
+                // Block keeps failing on every update, the log needs it once
+                if self.failed {
+                    log::debug!("{err}");
+                } else {
+                    log::error!("{err}");
+                }
+                self.failed = true;
+                let marker = OsString::from(format!("{ERROR_MARKER} {}", self.name));
+                self.set_output(marker, err.to_string());
+                return;
+            }
+        };
+        if self.failed {
+            self.failed = false;
+            self.dirty = true;
+        }
+        let (output, tooltip) = match &mut self.tooltip {
+            TooltipSource::None | TooltipSource::Command(_) => (output, String::new()),
+            TooltipSource::Static(text) => (output, text.clone()),
+            TooltipSource::SecondLine => {
+                let (text, tooltip) = split_tooltip(&output.to_string_lossy());
+                (OsString::from(text), tooltip)
+            }
+        };
+        self.set_output(output, tooltip);
+    }
+
+    fn set_output(&mut self, output: OsString, tooltip: String) {
+        if output != self.output || tooltip != self.tooltip_text {
+            self.output = output;
+            self.tooltip_text = tooltip;
+            self.dirty = true;
+        }
+    }
+
+    /// Replaces the text until next refresh
+    pub fn set_text(&mut self, text: &str) {
+        if self.failed {
+            self.failed = false;
+            self.dirty = true;
+        }
+        self.set_output(OsString::from(text), self.tooltip_text.clone());
+    }
+
+    /// Hidden blocks keep updating but aren't drawn
+    pub fn set_hidden(&mut self, hidden: bool) {
+        self.hidden = hidden;
+    }
+
+    fn text(&self) -> String {
+        self.output.to_string_lossy().trim().to_string()
     }
 }
 
-pub fn spawn_and_configure_blocks_updates_thread(
+impl Widget for Block {
+    fn measure(&mut self, context: &DrawContext) -> i32 {
+        let text = self.text();
+        if let Some((measured, width)) = &self.measured {
+            if *measured == text {
+                return *width;
+            }
+        }
+        context.pg_layout.set_attributes(None);
+        context.pg_layout.set_text(&text);
+        let (text_width, _) = context.pg_layout.pixel_size();
+        let width = context.decorations.blocks.outer_width(text_width);
+        self.measured = Some((text, width));
+        width
+    }
+
+    fn draw(&mut self, context: &DrawContext, x: i32, width: i32) {
+        let (cr, theme, height) = (context.cr, context.theme, context.height);
+        let decoration = &context.decorations.blocks;
+        context.pg_layout.set_attributes(None);
+        context.pg_layout.set_text(&self.text());
+        let (_, text_height) = context.pg_layout.pixel_size();
+
+        theme::clear_rectangle(cr, &theme.bg, x as f64, 0.0, width as f64, height as f64);
+        decoration.draw_background(cr, x as f64, width as f64, height as f64, &theme.widget_bg);
+        if self.failed {
+            theme.urgent.set_source(cr);
+        } else {
+            theme.accent.set_source(cr);
+        }
+        cr.move_to(
+            (x + decoration.padding) as f64,
+            (height - text_height) as f64 / 2.,
+        );
+        pangocairo::show_layout(cr, context.pg_layout);
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn fd(&self) -> Option<RawFd> {
+        match &self.source {
+            Source::Module(module) => module.fd(),
+            Source::Command(_) => None,
+        }
+    }
+
+    fn update(&mut self) {
+        self.refresh();
+    }
+}
+
+// First line is shown on the bar, the second one is the tooltip
+fn split_tooltip(output: &str) -> (String, String) {
+    let mut lines = output.lines();
+    let text = lines.next().unwrap_or_default().to_string();
+    let tooltip = lines.next().unwrap_or_default().trim().to_string();
+    (text, tooltip)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn spawn_and_configure_blocks_updates_thread(
     mut blocks: Vec<Block>,
     mut draw_contexts: Arc<Mutex<Vec<OutputContext>>>,
-    conn: Arc<Connection>,
-) {
-    std::thread::spawn(move || {
-        let mut block_outputs: Vec<OsString> = Vec::with_capacity(blocks.len());
+    conn: SharedConnection,
+    theme: Theme,
+    decorations: Decorations,
+    river_status: SharedRiverStatus,
+    shutdown: Arc<Shutdown>,
+    redraw: Arc<Redraw>,
+) -> error::Result<JoinHandle<()>> {
+    // Signals are blocked before spawning, so main thread inherits the mask too
+    // and they are only delivered through signalfd
+    let signal_fd = setup_signals(&blocks);
+    let mut scheduler = Scheduler::new(&blocks)?;
+    let mut wakeups = Wakeups::new()?;
+    Ok(std::thread::spawn(move || {
         for block in blocks.iter_mut() {
-            block_outputs.push(block.run_and_get_output());
+            block.refresh();
         }
-        let signal_fd = setup_signals(&blocks);
-        let mut timer_interval = -1;
-        for i in 0..blocks.len() {
-            if blocks[i].interval > 0 {
-                // Check user config correctness please
-                timer_interval = gcd(blocks[i].interval as i32, timer_interval);
+        // First updates take time, intervals are counted after them
+        scheduler.restart(&blocks);
+        let ipc_server = match ipc::Server::bind() {
+            Ok(server) => Some(server),
+            Err(err) => {
+                log::warn!("Control socket is unavailable: {err}");
+                None
             }
+        };
+        let mut pfd = vec![
+            libc::pollfd {
+                fd: signal_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: shutdown.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: redraw.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: scheduler.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: wakeups.clock_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if let Some(server) = &ipc_server {
+            pfd.push(libc::pollfd {
+                fd: server.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            });
         }
-        unsafe { libc::raise(libc::SIGALRM) };
-        let mut pfd = [libc::pollfd {
-            fd: signal_fd,
-            events: libc::POLLIN,
-            revents: 0,
-        }];
+        let bus_index = wakeups.bus_fd().map(|fd| {
+            pfd.push(libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            });
+            pfd.len() - 1
+        });
+        // Modules that are updated on events, e.g. network
+        let mut event_blocks = Vec::new();
+        for (i, block) in blocks.iter().enumerate() {
+            if let Some(fd) = block.fd() {
+                pfd.push(libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+                event_blocks.push(i);
+            }
+        }
+        let event_fds_start = pfd.len() - event_blocks.len();
 
-        let mut time = 0;
-        // Add running var
         loop {
-            // Wait for new signal (poll() blocks thread)
-            // fuck this as casting
-            let poll_result = unsafe {
-                libc::poll(
-                    &mut pfd as *mut _,
-                    (std::mem::size_of_val(&pfd) / std::mem::size_of::<libc::pollfd>()) as u64,
-                    -1,
-                )
-            };
-            if poll_result < 0 || (!(pfd[0].revents & libc::POLLIN) > 0) {
+            // Wait for new signal, deadline or ipc client (poll() blocks thread)
+            let poll_result =
+                unsafe { libc::poll(pfd.as_mut_ptr(), pfd.len() as libc::nfds_t, -1) };
+            if poll_result < 0 {
                 break;
             };
-            let mut signal_info: libc::signalfd_siginfo = unsafe { std::mem::zeroed() };
-            let _read_result = unsafe {
-                libc::read(
-                    signal_fd,
-                    &mut signal_info as *mut _ as *mut libc::c_void,
-                    std::mem::size_of::<libc::signalfd_siginfo>(),
-                )
-            };
-            //if read_result < 0 {
-            //    dbg!("signal handling failed");
-            //}
-            match signal_info.ssi_signo as i32 {
-                libc::SIGALRM => unsafe {
-                    libc::alarm(timer_interval as u32);
-                    for (i, block) in blocks.iter_mut().enumerate() {
-                        if block.interval == 0 {
-                            continue;
+            // Main thread is exiting
+            if pfd[1].revents & libc::POLLIN != 0 {
+                break;
+            }
+            // Surfaces need blocks even if they didn't change
+            let mut redraw_all = false;
+            if pfd[2].revents & libc::POLLIN != 0 {
+                redraw.clear();
+                redraw_all = true;
+            }
+            if pfd[0].revents & libc::POLLIN != 0 {
+                if let Some(signal) = read_signal(signal_fd) {
+                    match signal {
+                        libc::SIGUSR1 => {
+                            // Maybe there should be some system that will check for
+                            // user pointer and send corresponding
+                            // event but I don't know how that should work
+                            // A have nothing to deal with this for now
+                            return;
+                        }
+                        libc::SIGTERM | libc::SIGINT => {
+                            shutdown.trigger();
+                            break;
+                        }
+                        libc::SIGUSR2 => {
+                            let mut outputs_contexts = draw_contexts.lock().unwrap();
+                            let _ = visibility::apply(
+                                &mut outputs_contexts,
+                                None,
+                                visibility::Action::Toggle,
+                            );
+                            redraw_all = true;
                         }
-                        if time % block.interval == 0 {
-                            block_outputs[i] = block.run_and_get_output();
+                        signal => {
+                            for block in blocks.iter_mut() {
+                                if block.signal == signal {
+                                    block.refresh();
+                                }
+                            }
                         }
                     }
-                    time += timer_interval as u32;
-                },
-                libc::SIGUSR1 => {
-                    // Maybe there should be some system that will check for
-                    // user pointer and send corresponding
-                    // event but I don't know how that should work
-                    // A have nothing to deal with this for now
-                    return;
                 }
-                signal => {
-                    for (i, block) in blocks.iter_mut().enumerate() {
-                        if block.signal == signal {
-                            block_outputs[i] = block.run_and_get_output();
-                        }
+            }
+            if pfd[3].revents & libc::POLLIN != 0 {
+                for i in scheduler.due(&blocks) {
+                    blocks[i].refresh();
+                }
+            }
+            // Clock was set, timezone changed or system resumed
+            let mut time_changed = pfd[4].revents & libc::POLLIN != 0 && wakeups.clock_changed();
+            if let Some(index) = bus_index {
+                if pfd[index].revents & libc::POLLIN != 0 {
+                    time_changed |= wakeups.bus_changed();
+                    if wakeups.bus_fd().is_none() {
+                        // Negative fds are ignored by poll
+                        pfd[index].fd = -1;
                     }
                 }
             }
-            display_blocks(&block_outputs, &mut draw_contexts);
-            conn.flush().unwrap();
+            if time_changed {
+                log::debug!("Time changed, updating all blocks");
+                for block in blocks.iter_mut() {
+                    if let Source::Module(module) = &mut block.source {
+                        module.time_changed();
+                    }
+                    block.refresh();
+                }
+                scheduler.restart(&blocks);
+            }
+            for (j, &i) in event_blocks.iter().enumerate() {
+                if pfd[event_fds_start + j].revents & libc::POLLIN != 0 {
+                    blocks[i].refresh();
+                }
+            }
+            if let Some(server) = &ipc_server {
+                if pfd[5].revents & libc::POLLIN != 0 {
+                    redraw_all = true;
+                    for (stream, request) in server.accept() {
+                        let response = match ipc::Command::parse(&request) {
+                            Ok(command) => handle_command(
+                                command,
+                                &mut blocks,
+                                &draw_contexts,
+                                &river_status,
+                            ),
+                            Err(err) => format!("error: {err}"),
+                        };
+                        ipc::respond(stream, &response);
+                    }
+                }
+            }
+            if redraw_all || blocks.iter().any(Widget::is_dirty) {
+                display_blocks(
+                    &mut blocks,
+                    &mut draw_contexts,
+                    &theme,
+                    &decorations,
+                    &river_status,
+                    redraw_all,
+                );
+            }
+            // Errors mean connection is lost, main thread reconnects
+            let _ = conn.lock().unwrap().flush();
         }
-    });
+    }))
+}
+
+// Number of the signal that made signalfd readable
+fn read_signal(signal_fd: RawFd) -> Option<i32> {
+    let mut signal_info: libc::signalfd_siginfo = unsafe { std::mem::zeroed() };
+    let read_result = unsafe {
+        libc::read(
+            signal_fd,
+            &mut signal_info as *mut _ as *mut libc::c_void,
+            std::mem::size_of::<libc::signalfd_siginfo>(),
+        )
+    };
+    if read_result < 0 {
+        log::warn!("Can't read signal: {}", std::io::Error::last_os_error());
+        return None;
+    }
+    Some(signal_info.ssi_signo as i32)
+}
+
+fn find_block(blocks: &[Block], name: &str) -> Result<usize, String> {
+    blocks
+        .iter()
+        .position(|block| block.name == name)
+        .ok_or_else(|| format!("error: no block named {name}"))
+}
+
+fn handle_command(
+    command: ipc::Command,
+    blocks: &mut [Block],
+    draw_contexts: &OutputsContexts,
+    river_status: &SharedRiverStatus,
+) -> String {
+    use ipc::Command;
+    let result = match command {
+        Command::Set { block, text } => find_block(blocks, &block).map(|i| {
+            blocks[i].set_text(&text);
+        }),
+        Command::Refresh { block: None } => {
+            for block in blocks.iter_mut() {
+                block.refresh();
+            }
+            Ok(())
+        }
+        Command::Refresh { block: Some(block) } => find_block(blocks, &block).map(|i| {
+            blocks[i].refresh();
+        }),
+        Command::Hide { block } => find_block(blocks, &block).map(|i| {
+            blocks[i].set_hidden(true);
+        }),
+        Command::Show { block } => find_block(blocks, &block).map(|i| {
+            blocks[i].set_hidden(false);
+        }),
+        Command::Toggle => {
+            let mut outputs_contexts = draw_contexts.lock().unwrap();
+            visibility::apply(&mut outputs_contexts, None, visibility::Action::Toggle)
+        }
+        Command::Bar { action, output } => {
+            let mut outputs_contexts = draw_contexts.lock().unwrap();
+            visibility::apply(&mut outputs_contexts, output.as_deref(), action)
+        }
+        Command::State => {
+            return state_json(blocks, draw_contexts, river_status);
+        }
+    };
+    match result {
+        Ok(()) => String::from("ok"),
+        Err(err) => err,
+    }
 }
 
-pub fn setup_signals(blocks: &[Block]) -> SignalFD {
+fn state_json(
+    blocks: &[Block],
+    draw_contexts: &OutputsContexts,
+    river_status: &SharedRiverStatus,
+) -> String {
+    let visible = draw_contexts
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|context| context.visible);
+    let river_status = river_status.lock().unwrap();
+    let view_tags: Vec<String> = river_status
+        .view_tags
+        .iter()
+        .map(|tags| tags.to_string())
+        .collect();
+    let blocks: Vec<String> = blocks
+        .iter()
+        .map(|block| {
+            format!(
+                r#"{{"name":{},"text":{},"hidden":{}}}"#,
+                ipc::json_string(&block.name),
+                ipc::json_string(&block.text()),
+                block.hidden
+            )
+        })
+        .collect();
+    format!(
+        r#"{{"visible":{},"focused_tags":{},"urgent_tags":{},"view_tags":[{}],"title":{},"blocks":[{}]}}"#,
+        visible,
+        river_status.focused_tags,
+        river_status.urgent_tags,
+        view_tags.join(","),
+        ipc::json_string(&river_status.title),
+        blocks.join(",")
+    )
+}
+
+pub(crate) fn setup_signals(blocks: &[Block]) -> SignalFD {
     unsafe {
         let mut signals: libc::sigset_t = std::mem::zeroed();
         libc::sigemptyset(&mut signals as *mut _);
-        // Process time based events
-        libc::sigaddset(&mut signals as *mut _, libc::SIGALRM);
         // Process button events (todo!())
         libc::sigaddset(&mut signals as *mut _, libc::SIGUSR1);
+        // Toggle bar visibility
+        libc::sigaddset(&mut signals as *mut _, libc::SIGUSR2);
+        // Graceful shutdown
+        libc::sigaddset(&mut signals as *mut _, libc::SIGTERM);
+        libc::sigaddset(&mut signals as *mut _, libc::SIGINT);
 
         // Process all signals decalred in blocks
-        for i in 0..blocks.len() {
-            if blocks[i].signal > 0 {
-                libc::sigaddset(&mut signals as *mut _, libc::SIGRTMIN() + blocks[i].signal);
+        for block in blocks {
+            if block.signal > 0 {
+                libc::sigaddset(&mut signals as *mut _, libc::SIGRTMIN() + block.signal);
             }
         }
         // Create signal file descriptor for pooling
@@ -140,76 +636,162 @@ pub fn setup_signals(blocks: &[Block]) -> SignalFD {
         for i in libc::SIGRTMIN()..=libc::SIGRTMAX() {
             libc::sigaddset(&mut signals as *mut _, i);
         }
-        libc::sigprocmask(libc::SIG_BLOCK, &mut signals as *mut _, 0 as *mut _);
+        libc::sigprocmask(libc::SIG_BLOCK, &mut signals as *mut _, std::ptr::null_mut());
         signal_fd
     }
 }
-use pangocairo::cairo as cr;
-use pangocairo::pango as pango;
-use wayland_client::Connection;
-fn display_blocks(block_outputs: &[OsString], outputs_contexts: &mut OutputsContexts) {
+/// Draws visible blocks that config of the output shows with separators from right to the left.
+/// Returns spans of drawn blocks
+pub fn draw_blocks(
+    context: &DrawContext,
+    blocks: &mut [Block],
+    right: i32,
+    config: &BarConfig,
+) -> Vec<BlockSpan> {
+    draw_changed_blocks(context, blocks, right, config, &[]).0
+}
+
+/// Like draw_blocks, but only blocks whose output changed or that moved since
+/// previous spans were drawn are drawn again. Returns new spans and ranges of the bar that were drawn
+pub(crate) fn draw_changed_blocks(
+    context: &DrawContext,
+    blocks: &mut [Block],
+    right: i32,
+    config: &BarConfig,
+    previous_spans: &[BlockSpan],
+) -> (Vec<BlockSpan>, Vec<(i32, i32)>) {
+    let (cr, pg_layout, theme) = (context.cr, context.pg_layout, context.theme);
+    let height = context.height;
+    let decoration = &context.decorations.blocks;
+    let separator_width = decoration.separator_width(pg_layout);
+    let mut previous_offset = right;
+    let mut block_spans = Vec::new();
+    let mut damage = Vec::new();
+    let shown = |block: &&mut Block| !block.hidden && config.shows_block(&block.name);
+    for (i, block) in blocks.iter_mut().filter(shown).enumerate() {
+        let end = previous_offset;
+        if i != 0 {
+            previous_offset -= separator_width;
+        }
+        let block_width = block.measure(context);
+        let offset = previous_offset - block_width;
+        let span = BlockSpan {
+            start: offset,
+            end: previous_offset,
+            name: block.name.clone(),
+            tooltip: block.tooltip_text.clone(),
+            // Failed block shows the error instead
+            tooltip_command: match &block.tooltip {
+                TooltipSource::Command(command) if !block.failed => Some(command.clone()),
+                _ => None,
+            },
+        };
+        previous_offset = offset;
+        // Block keeps what was drawn for it if it's text and place are the same.
+        // Width change of a block moves all blocks left of it
+        let moved = previous_spans.get(i).is_none_or(|previous| {
+            (previous.start, previous.end, &previous.name) != (span.start, span.end, &span.name)
+        });
+        if block.dirty || moved {
+            if i != 0 {
+                theme::clear_rectangle(
+                    cr,
+                    &theme.bg,
+                    span.end as f64,
+                    0.0,
+                    separator_width as f64,
+                    height as f64,
+                );
+                decoration.draw_separator(cr, pg_layout, span.end as f64, height as f64, &theme.fg);
+            }
+            block.draw(context, offset, block_width);
+            damage.push((offset, end));
+        }
+        block_spans.push(span);
+    }
+    (block_spans, damage)
+}
+
+// Draws blocks that changed on every bar. Everything is drawn when surfaces need it
+pub(crate) fn display_blocks(
+    blocks: &mut [Block],
+    outputs_contexts: &mut OutputsContexts,
+    theme: &Theme,
+    decorations: &Decorations,
+    river_status: &SharedRiverStatus,
+    redraw_all: bool,
+) {
+    let river = river_status.lock().unwrap().clone();
     let mut outputs_contexts = outputs_contexts.lock().unwrap();
 
     for output_index in 0..outputs_contexts.len() {
         let output_context = outputs_contexts.get_mut(output_index).unwrap();
-        if output_context.ready_to_draw == false {
+        if !output_context.ready_to_draw {
             continue;
         }
+        let Some(buffers) = output_context.buffers.as_mut() else {
+            continue;
+        };
         let width = output_context.width;
         let height = output_context.height;
         let surface = &output_context.surface;
-        let buffer = output_context
-            .buffers
-            .get(output_context.current_buffer_index)
-            .unwrap();
-        let mmap_ptr = output_context.canvases[output_context.current_buffer_index].as_mut_ptr();
-        let image_surface = unsafe {
-            cr::ImageSurface::create_for_data_unsafe(
-                mmap_ptr,
-                cr::Format::ARgb32,
-                width,
-                height,
-                width * 4,
-            )
-            .unwrap()
+        // Buffer drawn now holds the bar as it was two commits ago,
+        // blocks that don't change are taken from the last one
+        buffers.copy_previous(output_context.current_buffer_index);
+        let previous_spans: &[BlockSpan] = if redraw_all {
+            &[]
+        } else {
+            &output_context.block_spans
+        };
+        let buffer = &buffers.buffers[output_context.current_buffer_index];
+        let mmap_ptr = buffers.canvases[output_context.current_buffer_index].as_mut_ptr();
+        let cr = unsafe { render::buffer_context(mmap_ptr, width, height) };
+        let pg_layout = render::create_layout(&cr);
+        let context = DrawContext {
+            cr: &cr,
+            pg_layout: &pg_layout,
+            theme,
+            decorations,
+            river: &river,
+            height,
+            space: width,
         };
-        let cr = cr::Context::new(image_surface).unwrap();
-
-        let pg_layout = pangocairo::create_layout(&cr);
-        let mut font_desc = pango::FontDescription::new();
-        font_desc.set_family("IosevkaNerdFontMono");
-        font_desc.set_weight(pango::Weight::Bold);
-        font_desc.set_style(pango::Style::Normal);
-        pg_layout.set_font_description(Some(&font_desc));
-        let mut previous_offset = output_context.width;
-        for block_output in block_outputs {
-            //cr.rectangle(offset, 0.0, 20., 20.);
-            //cr.fill().unwrap();
-            pg_layout.set_text(block_output.to_str().unwrap().trim());
-            let offset = previous_offset - pg_layout.pixel_size().0 - 10;
-
-            cr.set_source_rgb(0.0, 0.0, 0.0);
-            cr.rectangle(
-                offset as f64,
-                0.0,
-                pg_layout.pixel_size().0 as f64,
-                height as f64,
-            );
-            cr.fill().unwrap();
-
-            cr.set_source_rgb(1., 0.0, 0.);
-            cr.move_to(offset as f64, 0.);
-            pangocairo::show_layout(&cr, &pg_layout);
-            previous_offset = offset;
-            //cr.select_font_face("monospace", cr::FontSlant::Normal, cr::FontWeight::Bold);
-            //cr.set_font_size(1.2);
-
-            //cr.text_extents("1").unwrap();
-            //cr.show_text("1").unwrap();
+        let right = output_context.width - output_context.tray_width;
+        let (block_spans, mut damage) = draw_changed_blocks(
+            &context,
+            blocks,
+            right,
+            &output_context.config,
+            previous_spans,
+        );
+        let previous_offset = block_spans.last().map_or(right, |span| span.start);
+        output_context.block_spans = block_spans;
+
+        // Clear what is left from wider or hidden blocks
+        let damage_offset = previous_offset.min(output_context.blocks_offset);
+        theme::clear_rectangle(
+            &cr,
+            &theme.bg,
+            damage_offset as f64,
+            0.0,
+            (previous_offset - damage_offset) as f64,
+            height as f64,
+        );
+        output_context.blocks_offset = previous_offset;
+        if redraw_all {
+            damage = vec![(damage_offset, width)];
+        } else if damage_offset < previous_offset {
+            damage.push((damage_offset, previous_offset));
+        }
+        // Nothing shown on this bar changed
+        if damage.is_empty() {
+            continue;
         }
 
         surface.attach(Some(buffer), 0, 0);
-        surface.damage(previous_offset, 0, width - previous_offset, height);
+        for (start, end) in damage {
+            surface.damage(start, 0, end - start, height);
+        }
         surface.commit();
 
         outputs_contexts
@@ -221,4 +803,94 @@ fn display_blocks(block_outputs: &[OsString], outputs_contexts: &mut OutputsCont
             .unwrap()
             .current_buffer_index %= 2;
     }
+    // Every output got the new text, hidden blocks included
+    for block in blocks.iter_mut() {
+        block.dirty = false;
+    }
+}
+
+#[test]
+fn test_split_tooltip() {
+    assert_eq!(
+        split_tooltip("85%\n2:10 left\n"),
+        (String::from("85%"), String::from("2:10 left"))
+    );
+    assert_eq!(split_tooltip("85%"), (String::from("85%"), String::new()));
+}
+
+#[test]
+fn test_failed_block_shows_marker() {
+    use std::process::Command;
+    let mut block = Block::new("battery", Source::Command(Command::new("/nonexistent/battery")));
+    block.refresh();
+    assert!(block.failed);
+    assert_eq!(block.text(), format!("{ERROR_MARKER} battery"));
+    assert!(block.tooltip_text.contains("can't run command of block battery"));
+
+    block.source = Source::Command(Command::new("false"));
+    block.refresh();
+    assert!(block.failed);
+
+    block.source = Source::Command(Command::new("true"));
+    block.refresh();
+    assert!(!block.failed);
+    assert_eq!(block.text(), "");
+}
+
+#[test]
+fn test_only_changed_blocks_are_drawn() {
+    use pangocairo::cairo;
+    use std::process::Command;
+    let text_block = |name: &str, text: &str| {
+        let mut block = Block::new(name, Source::Command(Command::new("true")));
+        block.set_text(text);
+        block
+    };
+    let mut blocks = vec![
+        text_block("date", "12:00"),
+        text_block("layout", "us"),
+        text_block("battery", "85%"),
+    ];
+    let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, 300, 20).unwrap();
+    let cr = cairo::Context::new(&surface).unwrap();
+    let pg_layout = render::create_layout(&cr);
+    let river = crate::RiverStatus::default();
+    let (theme, decorations) = (Theme::default(), Decorations::default());
+    let context = DrawContext {
+        cr: &cr,
+        pg_layout: &pg_layout,
+        theme: &theme,
+        decorations: &decorations,
+        river: &river,
+        height: 20,
+        space: 300,
+    };
+    let config = BarConfig::default();
+    let draw = |blocks: &mut [Block], previous_spans: &[BlockSpan]| {
+        let drawn = draw_changed_blocks(&context, blocks, 300, &config, previous_spans);
+        for block in blocks.iter_mut() {
+            block.dirty = false;
+        }
+        drawn
+    };
+    let (spans, damage) = draw(&mut blocks, &[]);
+    assert_eq!(damage.len(), 3);
+    let (spans, damage) = draw(&mut blocks, &spans);
+    assert!(damage.is_empty());
+
+    // Same text is not drawn again
+    blocks[2].set_text("85%");
+    let (spans, damage) = draw(&mut blocks, &spans);
+    assert!(damage.is_empty());
+
+    // Wider text moves blocks left of it
+    blocks[1].set_text("us(intl)");
+    let (spans, damage) = draw(&mut blocks, &spans);
+    assert_eq!(
+        damage,
+        [
+            (spans[1].start, spans[0].start),
+            (spans[2].start, spans[1].start)
+        ]
+    );
 }
@@ -0,0 +1,215 @@
+// Month calendar dropdown, usually opened by clicking the clock
+
+use pangocairo::cairo as cr;
+use pangocairo::pango;
+
+use crate::modules;
+use crate::popup;
+use crate::render;
+use crate::theme::{self, Theme};
+
+const PADDING: i32 = 6;
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+const WEEKDAYS: &str = "Mo Tu We Th Fr Sa Su";
+
+pub struct Calendar {
+    pub year: i32,
+    // 1 to 12
+    pub month: u32,
+    // Year, month and day of today
+    today: (i32, u32, u32),
+}
+
+fn is_leap_year(year: i32) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+pub fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Day of week of the date, 0 is Monday
+pub fn weekday(year: i32, month: u32, day: u32) -> u32 {
+    // Sakamoto's method, it counts from Sunday
+    const OFFSETS: [i32; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+    let year = if month < 3 { year - 1 } else { year };
+    let sunday_based = (year + year.div_euclid(4) - year.div_euclid(100)
+        + year.div_euclid(400)
+        + OFFSETS[month as usize - 1]
+        + day as i32)
+        .rem_euclid(7);
+    ((sunday_based + 6) % 7) as u32
+}
+
+impl Calendar {
+    pub fn new(today: (i32, u32, u32)) -> Self {
+        Self {
+            year: today.0,
+            month: today.1,
+            today,
+        }
+    }
+
+    /// Calendar of current month in local time
+    pub fn local() -> Self {
+        let now = unsafe { libc::time(std::ptr::null_mut()) };
+        // Zone is loaded every time, the popup opens rarely
+        let (offset, _) =
+            modules::offset_at(&modules::local_timezone(), now as i64).unwrap_or_default();
+        let local = now + offset as libc::time_t;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        unsafe { libc::gmtime_r(&local, &mut tm) };
+        Self::new((tm.tm_year + 1900, tm.tm_mon as u32 + 1, tm.tm_mday as u32))
+    }
+
+    /// Moves shown month by amount of months, negative goes back
+    pub fn shift(&mut self, months: i32) {
+        let index = self.year * 12 + self.month as i32 - 1 + months;
+        self.year = index.div_euclid(12);
+        self.month = index.rem_euclid(12) as u32 + 1;
+    }
+
+    pub fn reset(&mut self) {
+        self.year = self.today.0;
+        self.month = self.today.1;
+    }
+
+    /// Title and rows of weeks, days are right aligned in 3 character cells
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("{} {}", MONTHS[self.month as usize - 1], self.year),
+            String::from(WEEKDAYS),
+        ];
+        let mut week = "   ".repeat(weekday(self.year, self.month, 1) as usize);
+        for day in 1..=days_in_month(self.year, self.month) {
+            week.push_str(&format!("{day:>2} "));
+            if weekday(self.year, self.month, day) == 6 {
+                lines.push(week.trim_end().to_string());
+                week.clear();
+            }
+        }
+        if !week.is_empty() {
+            lines.push(week.trim_end().to_string());
+        }
+        lines
+    }
+
+    /// Column and row of today's cell when current month is shown
+    fn today_cell(&self) -> Option<(u32, u32)> {
+        let (year, month, day) = self.today;
+        if (self.year, self.month) != (year, month) {
+            return None;
+        }
+        let row = (weekday(year, month, 1) + day - 1) / 7;
+        Some((weekday(year, month, day), row))
+    }
+
+    /// Size of popup needed to show the calendar
+    pub fn size(&self) -> (i32, i32) {
+        let cr = popup::measure_context();
+        let pg_layout = render::create_layout(&cr);
+        pg_layout.set_text(WEEKDAYS);
+        let (width, line_height) = pg_layout.pixel_size();
+        // Months have up to 6 weeks, size doesn't change while browsing them
+        (width + PADDING * 2, line_height * 8 + PADDING * 2)
+    }
+
+    pub fn draw(
+        &self,
+        cr: &cr::Context,
+        pg_layout: &pango::Layout,
+        theme: &Theme,
+        (width, height): (i32, i32),
+    ) {
+        theme::clear_rectangle(cr, &theme.bg, 0., 0., width as f64, height as f64);
+        pg_layout.set_text(WEEKDAYS);
+        let (weekdays_width, line_height) = pg_layout.pixel_size();
+        // Font is monospace, so every character has the same width
+        let cell_width = weekdays_width as f64 / WEEKDAYS.chars().count() as f64;
+        // Weeks start on third line
+        let today = self.today_cell().map(|(column, row)| {
+            (
+                PADDING as f64 + cell_width * (column * 3) as f64,
+                (PADDING + line_height * (row as i32 + 2)) as f64,
+            )
+        });
+        if let Some((x, y)) = today {
+            theme.focused.set_source(cr);
+            cr.rectangle(x, y, cell_width * 2., line_height as f64);
+            cr.fill().unwrap();
+        }
+        for (index, line) in self.lines().iter().enumerate() {
+            let color = match index {
+                0 => theme.accent,
+                1 => theme.fg.with_alpha(0.6),
+                _ => theme.fg,
+            };
+            color.set_source(cr);
+            pg_layout.set_text(line);
+            let x = if index == 0 {
+                // Title is centered
+                (width - pg_layout.pixel_size().0) / 2
+            } else {
+                PADDING
+            };
+            cr.move_to(x as f64, (PADDING + line_height * index as i32) as f64);
+            pangocairo::show_layout(cr, pg_layout);
+        }
+        // Today's number is drawn over the highlight with it's own color
+        if let Some((x, y)) = today {
+            theme.focused_fg.set_source(cr);
+            pg_layout.set_text(&format!("{:>2}", self.today.2));
+            cr.move_to(x, y);
+            pangocairo::show_layout(cr, pg_layout);
+        }
+    }
+}
+
+#[test]
+fn test_calendar_lines() {
+    assert_eq!(weekday(2026, 10, 19), 0);
+    assert_eq!(weekday(2000, 1, 1), 5);
+    assert_eq!(days_in_month(2024, 2), 29);
+    assert_eq!(days_in_month(1900, 2), 28);
+
+    let mut calendar = Calendar::new((2026, 2, 14));
+    assert_eq!(
+        calendar.lines(),
+        [
+            "February 2026",
+            "Mo Tu We Th Fr Sa Su",
+            "                   1",
+            " 2  3  4  5  6  7  8",
+            " 9 10 11 12 13 14 15",
+            "16 17 18 19 20 21 22",
+            "23 24 25 26 27 28",
+        ]
+    );
+    calendar.shift(-2);
+    assert_eq!((calendar.year, calendar.month), (2025, 12));
+    calendar.shift(13);
+    assert_eq!((calendar.year, calendar.month), (2027, 1));
+    assert_eq!(calendar.today_cell(), None);
+    calendar.reset();
+    assert_eq!((calendar.year, calendar.month), (2026, 2));
+    // Saturday of the second week
+    assert_eq!(calendar.today_cell(), Some((5, 2)));
+}
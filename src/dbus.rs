@@ -0,0 +1,584 @@
+// D-Bus connection for the tray and system bus wakeups on top of libdbus.
+// Messages are kept as plain values, so the rest of the bar doesn't deal with
+// libdbus iterators and can build replies without holding the call around
+
+use std::{collections::VecDeque, ffi::CString, io, os::fd::RawFd, time::Duration};
+
+use dbus::arg::{ArgType, Iter, IterAppend};
+use dbus::channel::{BusType, Channel};
+use dbus::message::MessageType;
+use dbus::strings::{BusName, ErrorName, Interface, Member, Path, Signature};
+
+pub const METHOD_CALL: u8 = 1;
+pub const METHOD_RETURN: u8 = 2;
+pub const ERROR: u8 = 3;
+pub const SIGNAL: u8 = 4;
+
+// Used by blocking calls, which are only made while connecting
+const CALL_TIMEOUT: Duration = Duration::from_millis(500);
+// Calls that are never answered are forgotten after this many newer ones
+const MAX_UNANSWERED_CALLS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Byte(u8),
+    Bool(bool),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Str(String),
+    ObjectPath(String),
+    Signature(String),
+    // Signature of elements is kept so empty arrays can be written too
+    Array(String, Vec<Value>),
+    Struct(Vec<Value>),
+    Variant(Box<Value>),
+    DictEntry(Box<Value>, Box<Value>),
+}
+
+impl Value {
+    pub fn signature(&self) -> String {
+        match self {
+            Value::Byte(_) => String::from("y"),
+            Value::Bool(_) => String::from("b"),
+            Value::I16(_) => String::from("n"),
+            Value::U16(_) => String::from("q"),
+            Value::I32(_) => String::from("i"),
+            Value::U32(_) => String::from("u"),
+            Value::I64(_) => String::from("x"),
+            Value::U64(_) => String::from("t"),
+            Value::F64(_) => String::from("d"),
+            Value::Str(_) => String::from("s"),
+            Value::ObjectPath(_) => String::from("o"),
+            Value::Signature(_) => String::from("g"),
+            Value::Array(element, _) => format!("a{element}"),
+            Value::Struct(fields) => {
+                let fields: String = fields.iter().map(Value::signature).collect();
+                format!("({fields})")
+            }
+            Value::Variant(_) => String::from("v"),
+            Value::DictEntry(key, value) => {
+                format!("{{{}{}}}", key.signature(), value.signature())
+            }
+        }
+    }
+
+    /// Looks through variants, they wrap every property value
+    pub fn inner(&self) -> &Value {
+        match self {
+            Value::Variant(value) => value.inner(),
+            value => value,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self.inner() {
+            Value::Str(text) | Value::ObjectPath(text) | Value::Signature(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_i32(&self) -> Option<i32> {
+        match self.inner() {
+            Value::I32(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match self.inner() {
+            Value::U32(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.inner() {
+            Value::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self.inner() {
+            Value::Array(_, values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_fields(&self) -> Option<&[Value]> {
+        match self.inner() {
+            Value::Struct(fields) => Some(fields),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<Vec<u8>> {
+        self.as_array()?
+            .iter()
+            .map(|value| match value {
+                Value::Byte(byte) => Some(*byte),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Value for key in a{sv} dictionary
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_array()?.iter().find_map(|entry| match entry {
+            Value::DictEntry(entry_key, value) if entry_key.as_str() == Some(key) => {
+                Some(value.inner())
+            }
+            _ => None,
+        })
+    }
+
+    pub fn dict(entries: Vec<(&str, Value)>) -> Value {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| {
+                Value::DictEntry(
+                    Box::new(Value::Str(key.to_string())),
+                    Box::new(Value::Variant(Box::new(value))),
+                )
+            })
+            .collect();
+        Value::Array(String::from("{sv}"), entries)
+    }
+
+    pub fn strings(values: &[String]) -> Value {
+        let values = values.iter().cloned().map(Value::Str).collect();
+        Value::Array(String::from("s"), values)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Message {
+    pub kind: u8,
+    pub serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub destination: Option<String>,
+    pub sender: Option<String>,
+    pub body: Vec<Value>,
+}
+
+impl Message {
+    pub fn method_call(
+        destination: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+        body: Vec<Value>,
+    ) -> Self {
+        Self {
+            kind: METHOD_CALL,
+            path: Some(path.to_string()),
+            interface: Some(interface.to_string()),
+            member: Some(member.to_string()),
+            destination: Some(destination.to_string()),
+            body,
+            ..Default::default()
+        }
+    }
+
+    pub fn signal(path: &str, interface: &str, member: &str, body: Vec<Value>) -> Self {
+        Self {
+            kind: SIGNAL,
+            path: Some(path.to_string()),
+            interface: Some(interface.to_string()),
+            member: Some(member.to_string()),
+            body,
+            ..Default::default()
+        }
+    }
+
+    pub fn method_return(call: &Message, body: Vec<Value>) -> Self {
+        Self {
+            kind: METHOD_RETURN,
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            body,
+            ..Default::default()
+        }
+    }
+
+    pub fn error(call: &Message, name: &str, text: &str) -> Self {
+        Self {
+            kind: ERROR,
+            error_name: Some(name.to_string()),
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            body: vec![Value::Str(text.to_string())],
+            ..Default::default()
+        }
+    }
+
+    pub fn is_call(&self, interface: &str, member: &str) -> bool {
+        self.kind == METHOD_CALL
+            && self.interface.as_deref() == Some(interface)
+            && self.member.as_deref() == Some(member)
+    }
+
+    pub fn is_signal(&self, interface: &str, member: &str) -> bool {
+        self.kind == SIGNAL
+            && self.interface.as_deref() == Some(interface)
+            && self.member.as_deref() == Some(member)
+    }
+}
+
+fn invalid(err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
+
+fn bus_error(err: dbus::Error) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+fn append_value(iter: &mut IterAppend, value: &Value) -> io::Result<()> {
+    // Containers are filled in closures, their errors are carried out through this
+    let mut result = Ok(());
+    match value {
+        Value::Byte(value) => iter.append(*value),
+        Value::Bool(value) => iter.append(*value),
+        Value::I16(value) => iter.append(*value),
+        Value::U16(value) => iter.append(*value),
+        Value::I32(value) => iter.append(*value),
+        Value::U32(value) => iter.append(*value),
+        Value::I64(value) => iter.append(*value),
+        Value::U64(value) => iter.append(*value),
+        Value::F64(value) => iter.append(*value),
+        Value::Str(text) => iter.append(text.as_str()),
+        Value::ObjectPath(path) => iter.append(Path::new(path.as_str()).map_err(invalid)?),
+        Value::Signature(signature) => {
+            iter.append(Signature::new(signature.as_str()).map_err(invalid)?)
+        }
+        // Dict entry alone isn't a valid signature, keys are always basic types
+        Value::Array(element, values) if element.starts_with('{') && element.len() > 3 => {
+            let key = Signature::new(&element[1..2]).map_err(invalid)?;
+            let value = Signature::new(&element[2..element.len() - 1]).map_err(invalid)?;
+            iter.append_dict(&key, &value, |dict| {
+                result = values
+                    .iter()
+                    .try_for_each(|value| append_value(dict, value));
+            });
+        }
+        Value::Array(element, values) => {
+            let signature = Signature::new(element.as_str()).map_err(invalid)?;
+            iter.append_array(&signature, |array| {
+                result = values
+                    .iter()
+                    .try_for_each(|value| append_value(array, value));
+            });
+        }
+        Value::Struct(fields) => iter.append_struct(|fields_iter| {
+            result = fields
+                .iter()
+                .try_for_each(|field| append_value(fields_iter, field));
+        }),
+        Value::Variant(inner) => {
+            let signature = Signature::new(inner.signature()).map_err(invalid)?;
+            iter.append_variant(&signature, |variant| result = append_value(variant, inner));
+        }
+        Value::DictEntry(key, entry_value) => iter.append_dict_entry(|entry| {
+            result = append_value(entry, key).and_then(|()| append_value(entry, entry_value));
+        }),
+    }
+    result
+}
+
+// Read with the message iterator, not as RefArg which keeps dictionaries in
+// a HashMap and loses their order
+fn read_value(iter: &mut Iter) -> Option<Value> {
+    let value = match iter.arg_type() {
+        ArgType::Byte => Value::Byte(iter.get()?),
+        ArgType::Boolean => Value::Bool(iter.get()?),
+        ArgType::Int16 => Value::I16(iter.get()?),
+        ArgType::UInt16 => Value::U16(iter.get()?),
+        ArgType::Int32 => Value::I32(iter.get()?),
+        ArgType::UInt32 => Value::U32(iter.get()?),
+        ArgType::Int64 => Value::I64(iter.get()?),
+        ArgType::UInt64 => Value::U64(iter.get()?),
+        ArgType::Double => Value::F64(iter.get()?),
+        ArgType::String => Value::Str(iter.get::<&str>()?.to_string()),
+        ArgType::ObjectPath => Value::ObjectPath(iter.get::<Path>()?.to_string()),
+        ArgType::Signature => Value::Signature(iter.get::<Signature>()?.to_string()),
+        ArgType::Array => {
+            let element = iter.signature().get(1..)?.to_string();
+            Value::Array(element, read_values(&mut iter.recurse(ArgType::Array)?)?)
+        }
+        ArgType::DictEntry => {
+            let mut entry = iter.recurse(ArgType::DictEntry)?;
+            let key = read_value(&mut entry)?;
+            entry.next();
+            Value::DictEntry(Box::new(key), Box::new(read_value(&mut entry)?))
+        }
+        ArgType::Struct => Value::Struct(read_values(&mut iter.recurse(ArgType::Struct)?)?),
+        ArgType::Variant => {
+            Value::Variant(Box::new(read_value(&mut iter.recurse(ArgType::Variant)?)?))
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+// Values from the current one to the end of the message or container
+fn read_values(iter: &mut Iter) -> Option<Vec<Value>> {
+    let mut values = Vec::new();
+    while iter.arg_type() != ArgType::Invalid {
+        values.push(read_value(iter)?);
+        iter.next();
+    }
+    Some(values)
+}
+
+fn from_dbus(message: &mut dbus::Message) -> io::Result<Message> {
+    let body = read_values(&mut message.iter_init())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unsupported D-Bus type"))?;
+    let error_name = match message.as_result() {
+        Err(err) => err.name().map(String::from),
+        Ok(_) => None,
+    };
+    let kind = match message.msg_type() {
+        MessageType::MethodCall => METHOD_CALL,
+        MessageType::MethodReturn => METHOD_RETURN,
+        MessageType::Error => ERROR,
+        MessageType::Signal => SIGNAL,
+    };
+    Ok(Message {
+        kind,
+        serial: message.get_serial().unwrap_or(0),
+        path: message.path().map(|path| path.to_string()),
+        interface: message.interface().map(|interface| interface.to_string()),
+        member: message.member().map(|member| member.to_string()),
+        error_name,
+        reply_serial: message.get_reply_serial(),
+        destination: message.destination().map(|name| name.to_string()),
+        sender: message.sender().map(|name| name.to_string()),
+        body,
+    })
+}
+
+pub struct Connection {
+    channel: Channel,
+    // Calls from other clients that may still be answered, replies are made from them
+    calls: VecDeque<dbus::Message>,
+    // Messages that libdbus has read while sending, poll doesn't see them anymore
+    queue: VecDeque<Message>,
+}
+
+impl Connection {
+    /// Connects to the bus in DBUS_SESSION_BUS_ADDRESS
+    pub fn session() -> io::Result<Self> {
+        Self::new(Channel::get_private(BusType::Session).map_err(bus_error)?)
+    }
+
+    /// Connects to the bus in DBUS_SYSTEM_BUS_ADDRESS or the well-known system bus socket
+    pub fn system() -> io::Result<Self> {
+        Self::new(Channel::get_private(BusType::System).map_err(bus_error)?)
+    }
+
+    /// Connects to address like "unix:path=/run/user/1000/bus"
+    #[cfg(test)]
+    pub fn connect(address: &str) -> io::Result<Self> {
+        let mut channel = Channel::open_private(address).map_err(bus_error)?;
+        channel.register().map_err(bus_error)?;
+        Self::new(channel)
+    }
+
+    fn new(mut channel: Channel) -> io::Result<Self> {
+        // Lets fd be polled
+        channel.set_watch_enabled(true);
+        Ok(Self {
+            channel,
+            calls: VecDeque::new(),
+            queue: VecDeque::new(),
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.channel.watch().fd
+    }
+
+    fn encode(&mut self, message: &Message) -> io::Result<dbus::Message> {
+        let text = |field: &Option<String>| -> io::Result<String> {
+            field
+                .clone()
+                .ok_or_else(|| invalid("D-Bus message misses a header field"))
+        };
+        let mut dbus_message = match message.kind {
+            METHOD_CALL => dbus::Message::method_call(
+                &BusName::new(text(&message.destination)?).map_err(invalid)?,
+                &Path::new(text(&message.path)?).map_err(invalid)?,
+                &Interface::new(text(&message.interface)?).map_err(invalid)?,
+                &Member::new(text(&message.member)?).map_err(invalid)?,
+            ),
+            SIGNAL => dbus::Message::signal(
+                &Path::new(text(&message.path)?).map_err(invalid)?,
+                &Interface::new(text(&message.interface)?).map_err(invalid)?,
+                &Member::new(text(&message.member)?).map_err(invalid)?,
+            ),
+            METHOD_RETURN | ERROR => {
+                let position = self
+                    .calls
+                    .iter()
+                    .position(|call| call.get_serial() == message.reply_serial)
+                    .ok_or_else(|| invalid("reply to unknown call"))?;
+                let call = self.calls.remove(position).unwrap();
+                if message.kind == METHOD_RETURN {
+                    call.method_return()
+                } else {
+                    let name = ErrorName::new(text(&message.error_name)?).map_err(invalid)?;
+                    let description = message.body.first().and_then(Value::as_str);
+                    let description =
+                        CString::new(description.unwrap_or_default()).map_err(invalid)?;
+                    // Error text is it's only argument
+                    return Ok(call.error(&name, &description));
+                }
+            }
+            kind => return Err(invalid(format!("unknown message type {kind}"))),
+        };
+        let mut iter = IterAppend::new(&mut dbus_message);
+        for value in &message.body {
+            append_value(&mut iter, value)?;
+        }
+        Ok(dbus_message)
+    }
+
+    // Moves messages read by libdbus into our queue
+    fn drain(&mut self) {
+        while let Some(mut message) = self.channel.pop_message() {
+            match from_dbus(&mut message) {
+                Ok(decoded) => self.queue.push_back(decoded),
+                Err(err) => log::debug!("Dropped D-Bus message: {err}"),
+            }
+            // Copies lose their serial, so the call itself is kept for replies
+            if matches!(message.msg_type(), MessageType::MethodCall) {
+                if self.calls.len() == MAX_UNANSWERED_CALLS {
+                    self.calls.pop_front();
+                }
+                self.calls.push_back(message);
+            }
+        }
+    }
+
+    /// Sends message without waiting for reply and returns it's serial
+    pub fn send(&mut self, message: &Message) -> io::Result<u32> {
+        let dbus_message = self.encode(message)?;
+        let serial = self.channel.send(dbus_message).map_err(|()| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "D-Bus connection is closed")
+        })?;
+        // Flushing may read incoming messages too
+        self.channel.flush();
+        self.drain();
+        Ok(serial)
+    }
+
+    /// Sends method call and waits for it's reply. Error replies become errors.
+    /// Blocks for up to half a second, so it's only used while connecting
+    pub fn call(&mut self, message: Message) -> io::Result<Message> {
+        let dbus_message = self.encode(&message)?;
+        let reply = self
+            .channel
+            .send_with_reply_and_block(dbus_message, CALL_TIMEOUT)
+            .map_err(bus_error);
+        self.drain();
+        from_dbus(&mut reply?)
+    }
+
+    /// Reads messages after fd became readable, including ones queued during sends
+    pub fn read_messages(&mut self) -> io::Result<Vec<Message>> {
+        if self.queue.is_empty() {
+            self.channel
+                .read_write(Some(Duration::ZERO))
+                .map_err(|()| {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "D-Bus connection is closed")
+                })?;
+            self.drain();
+        }
+        Ok(self.queue.drain(..).collect())
+    }
+
+    /// Messages that arrived during sends and are not seen by poll anymore
+    pub fn has_queued(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    fn bus_call(&mut self, member: &str, body: Vec<Value>) -> io::Result<Message> {
+        self.call(Message::method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            member,
+            body,
+        ))
+    }
+
+    /// Returns true if the name is ours now
+    pub fn request_name(&mut self, name: &str) -> io::Result<bool> {
+        // DBUS_NAME_FLAG_DO_NOT_QUEUE
+        let reply = self.bus_call(
+            "RequestName",
+            vec![Value::Str(name.to_string()), Value::U32(4)],
+        )?;
+        // DBUS_REQUEST_NAME_REPLY_PRIMARY_OWNER
+        Ok(reply.body.first().and_then(Value::as_u32) == Some(1))
+    }
+
+    pub fn add_match(&mut self, rule: &str) -> io::Result<()> {
+        self.bus_call("AddMatch", vec![Value::Str(rule.to_string())])?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_values_survive_libdbus() {
+    let body = vec![
+        Value::Str(String::from("/StatusNotifierItem")),
+        Value::dict(vec![
+            ("IconName", Value::Str(String::from("nm-applet"))),
+            ("ItemIsMenu", Value::Bool(true)),
+        ]),
+        Value::Struct(vec![
+            Value::I32(-1),
+            Value::Array(String::from("y"), vec![Value::Byte(1), Value::Byte(2)]),
+            Value::Array(String::from("(iiay)"), Vec::new()),
+            Value::U64(1 << 40),
+            Value::ObjectPath(String::from("/Menu")),
+        ]),
+    ];
+    let mut message = dbus::Message::new_method_call(
+        "org.kde.StatusNotifierWatcher",
+        "/StatusNotifierWatcher",
+        "org.kde.StatusNotifierWatcher",
+        "RegisterStatusNotifierItem",
+    )
+    .unwrap();
+    let mut iter = IterAppend::new(&mut message);
+    for value in &body {
+        append_value(&mut iter, value).unwrap();
+    }
+    let decoded = from_dbus(&mut message).unwrap();
+    assert_eq!(decoded.kind, METHOD_CALL);
+    assert_eq!(
+        decoded.member.as_deref(),
+        Some("RegisterStatusNotifierItem")
+    );
+    assert_eq!(decoded.body, body);
+    assert_eq!(
+        decoded.body[1].get("IconName").and_then(Value::as_str),
+        Some("nm-applet")
+    );
+
+    // Invalid path is an error instead of a panic in libdbus
+    let mut message = dbus::Message::new_signal("/", "org.example.Test", "Test").unwrap();
+    let mut iter = IterAppend::new(&mut message);
+    assert!(append_value(&mut iter, &Value::ObjectPath(String::from("no slash"))).is_err());
+}
@@ -0,0 +1,176 @@
+use std::f64::consts::PI;
+
+use pangocairo::cairo as cr;
+use pangocairo::pango as pango;
+
+use crate::theme::Color;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Rect,
+    Rounded { radius: f64 },
+    // Rounded with radius of half the height
+    Pill,
+    // Only a line of given thickness under or over the widget is filled
+    Underline { thickness: f64 },
+    Overline { thickness: f64 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Separator {
+    None,
+    Glyph(String),
+    Line { width: f64 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decoration {
+    pub shape: Shape,
+    pub border_width: f64,
+    pub border_color: Color,
+    // Horizontal space between background edge and text
+    pub padding: i32,
+    // Vertical space between bar edge and background
+    pub margin: i32,
+    pub separator: Separator,
+}
+
+impl Default for Decoration {
+    fn default() -> Self {
+        Self {
+            shape: Shape::Rect,
+            border_width: 0.,
+            border_color: Color::rgba(0., 0., 0., 0.),
+            padding: 5,
+            margin: 0,
+            separator: Separator::None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decorations {
+    pub tags: Decoration,
+    pub title: Decoration,
+    pub blocks: Decoration,
+}
+
+impl Default for Decorations {
+    fn default() -> Self {
+        Self {
+            tags: Decoration::default(),
+            title: Decoration::default(),
+            blocks: Decoration {
+                separator: Separator::Line { width: 1. },
+                ..Decoration::default()
+            },
+        }
+    }
+}
+
+impl Decoration {
+    pub fn outer_width(&self, content_width: i32) -> i32 {
+        content_width + self.padding * 2
+    }
+
+    /// Draws background and border for widget occupying x..x+width of the bar
+    pub fn draw_background(&self, cr: &cr::Context, x: f64, width: f64, height: f64, fill: &Color) {
+        let y = self.margin as f64;
+        let height = height - self.margin as f64 * 2.;
+        cr.save().unwrap();
+        match self.shape {
+            Shape::Underline { thickness } => {
+                fill.set_source(cr);
+                cr.rectangle(x, y + height - thickness, width, thickness);
+                cr.fill().unwrap();
+            }
+            Shape::Overline { thickness } => {
+                fill.set_source(cr);
+                cr.rectangle(x, y, width, thickness);
+                cr.fill().unwrap();
+            }
+            shape => {
+                let radius = match shape {
+                    Shape::Rounded { radius } => radius,
+                    Shape::Pill => height / 2.,
+                    _ => 0.,
+                };
+                // Keep border inside of the widget
+                let inset = self.border_width / 2.;
+                rounded_rectangle(
+                    cr,
+                    x + inset,
+                    y + inset,
+                    width - inset * 2.,
+                    height - inset * 2.,
+                    radius,
+                );
+                fill.set_source(cr);
+                if self.border_width > 0. {
+                    cr.fill_preserve().unwrap();
+                    self.border_color.set_source(cr);
+                    cr.set_line_width(self.border_width);
+                    cr.stroke().unwrap();
+                } else {
+                    cr.fill().unwrap();
+                }
+            }
+        }
+        cr.restore().unwrap();
+    }
+
+    pub fn separator_width(&self, pg_layout: &pango::Layout) -> i32 {
+        match &self.separator {
+            Separator::None => 0,
+            Separator::Glyph(glyph) => {
+                pg_layout.set_attributes(None);
+                pg_layout.set_text(glyph);
+                pg_layout.pixel_size().0 + self.padding * 2
+            }
+            Separator::Line { width } => width.ceil() as i32 + self.padding * 2,
+        }
+    }
+
+    /// Draws separator starting at x. Returns it's width
+    pub fn draw_separator(
+        &self,
+        cr: &cr::Context,
+        pg_layout: &pango::Layout,
+        x: f64,
+        height: f64,
+        color: &Color,
+    ) -> i32 {
+        let width = self.separator_width(pg_layout);
+        color.set_source(cr);
+        match &self.separator {
+            Separator::None => (),
+            Separator::Glyph(glyph) => {
+                pg_layout.set_text(glyph);
+                let text_height = pg_layout.pixel_size().1 as f64;
+                cr.move_to(x + self.padding as f64, (height - text_height) / 2.);
+                pangocairo::show_layout(cr, pg_layout);
+            }
+            Separator::Line { width: line_width } => {
+                let margin = height * 0.2;
+                cr.rectangle(
+                    x + self.padding as f64,
+                    margin,
+                    *line_width,
+                    height - margin * 2.,
+                );
+                cr.fill().unwrap();
+            }
+        }
+        width
+    }
+}
+
+pub fn rounded_rectangle(cr: &cr::Context, x: f64, y: f64, width: f64, height: f64, radius: f64) {
+    let radius = radius.min(width / 2.).min(height / 2.).max(0.);
+    cr.new_sub_path();
+    cr.arc(x + width - radius, y + radius, radius, -PI / 2., 0.);
+    cr.arc(x + width - radius, y + height - radius, radius, 0., PI / 2.);
+    cr.arc(x + radius, y + height - radius, radius, PI / 2., PI);
+    cr.arc(x + radius, y + radius, radius, PI, PI * 1.5);
+    cr.close_path();
+}
@@ -0,0 +1,51 @@
+use std::fmt;
+use std::io;
+use std::process::ExitStatus;
+
+/// Failures the bar recovers from. They are logged and the part of the bar
+/// that failed is skipped or shows an error marker
+#[derive(Debug)]
+pub enum Error {
+    // Compositor didn't advertise a global that is needed to put bar on an output
+    MissingGlobal(&'static str),
+    // Command of a block couldn't be started
+    Spawn { block: String, source: io::Error },
+    // Command of a block exited with failure and printed nothing
+    Exit { block: String, status: ExitStatus },
+    // Module of a block couldn't read it's state
+    Module { block: String, source: io::Error },
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingGlobal(interface) => write!(f, "compositor doesn't support {interface}"),
+            Self::Spawn { block, source } => {
+                write!(f, "can't run command of block {block}: {source}")
+            }
+            Self::Exit { block, status } => write!(f, "command of block {block} {status}"),
+            Self::Module { block, source } => write!(f, "block {block} failed: {source}"),
+            Self::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Spawn { source, .. } | Self::Module { source, .. } | Self::Io(source) => {
+                Some(source)
+            }
+            Self::MissingGlobal(_) | Self::Exit { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
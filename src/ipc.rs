@@ -0,0 +1,220 @@
+use std::{
+    ffi::OsString,
+    io::{BufRead, BufReader, Write},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::visibility;
+
+// Protocol is line based. Client sends one command per connection:
+//   set <block> <text>     replace block's text until it's next update
+//   refresh [<block>]      rerun command of one or all blocks
+//   hide <block>
+//   show <block>
+//   toggle                 toggle bar on all outputs
+//   bar <show|hide|toggle> [<output>]
+//                          change bar visibility on output with given name or index
+//   state                  query state as JSON
+// and receives one line back: "ok", "error: <reason>" or JSON
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Set { block: String, text: String },
+    Refresh { block: Option<String> },
+    Hide { block: String },
+    Show { block: String },
+    Toggle,
+    Bar {
+        action: visibility::Action,
+        output: Option<String>,
+    },
+    State,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim_end_matches(['\n', '\r']);
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        let block = |args: &str| {
+            if args.is_empty() {
+                Err(format!("{command} requires block name"))
+            } else {
+                Ok(args.to_string())
+            }
+        };
+        match command {
+            "set" => {
+                let (name, text) = args.split_once(' ').unwrap_or((args, ""));
+                Ok(Self::Set {
+                    block: block(name)?,
+                    text: text.to_string(),
+                })
+            }
+            "refresh" => Ok(Self::Refresh {
+                block: block(args).ok(),
+            }),
+            "hide" => Ok(Self::Hide { block: block(args)? }),
+            "show" => Ok(Self::Show { block: block(args)? }),
+            "toggle" => Ok(Self::Toggle),
+            "bar" => {
+                let (action, output) = match args.split_once(' ') {
+                    Some((action, output)) => (action, Some(output.to_string())),
+                    None => (args, None),
+                };
+                let action = visibility::Action::parse(action)
+                    .ok_or_else(|| String::from("bar requires show, hide or toggle"))?;
+                Ok(Self::Bar { action, output })
+            }
+            "state" => Ok(Self::State),
+            "" => Err(String::from("empty command")),
+            unknown => Err(format!("unknown command {unknown}")),
+        }
+    }
+}
+
+/// Socket in XDG_RUNTIME_DIR. There is no fallback, a shared directory like /tmp
+/// would let other users take the socket before the bar does
+pub fn socket_path() -> std::io::Result<PathBuf> {
+    let display = std::env::var("WAYLAND_DISPLAY").unwrap_or_else(|_| "wayland-0".into());
+    socket_in(std::env::var_os("XDG_RUNTIME_DIR"), &display)
+}
+
+fn socket_in(runtime_dir: Option<OsString>, display: &str) -> std::io::Result<PathBuf> {
+    let runtime_dir = runtime_dir.filter(|dir| !dir.is_empty()).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "XDG_RUNTIME_DIR is not set")
+    })?;
+    Ok(PathBuf::from(runtime_dir).join(format!("tagsandblocks-{display}.sock")))
+}
+
+pub struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Server {
+    pub fn bind() -> std::io::Result<Self> {
+        let path = socket_path()?;
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    "another instance is listening",
+                ));
+            }
+            // Left from instance that crashed
+            std::fs::remove_file(&path)?;
+        }
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener, path })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// Accepts all pending connections and reads their requests
+    pub fn accept(&self) -> Vec<(UnixStream, String)> {
+        let mut requests = Vec::new();
+        while let Ok((stream, _)) = self.listener.accept() {
+            // Don't let slow client stall blocks updates
+            if stream.set_nonblocking(false).is_err()
+                || stream
+                    .set_read_timeout(Some(Duration::from_millis(100)))
+                    .is_err()
+            {
+                continue;
+            }
+            let mut line = String::new();
+            if BufReader::new(&stream).read_line(&mut line).is_ok() {
+                requests.push((stream, line));
+            }
+        }
+        requests
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub fn respond(mut stream: UnixStream, response: &str) {
+    let _ = writeln!(stream, "{response}");
+}
+
+/// Sends command to running bar and returns it's response
+pub fn send(args: &[String]) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(socket_path()?)?;
+    writeln!(stream, "{}", args.join(" "))?;
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response)?;
+    Ok(response.trim_end().to_string())
+}
+
+pub fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[test]
+fn test_command_parse() {
+    assert_eq!(
+        Command::parse("set date 12:00 AM\n"),
+        Ok(Command::Set {
+            block: String::from("date"),
+            text: String::from("12:00 AM"),
+        })
+    );
+    assert_eq!(Command::parse("refresh"), Ok(Command::Refresh { block: None }));
+    assert_eq!(
+        Command::parse("hide battery"),
+        Ok(Command::Hide {
+            block: String::from("battery")
+        })
+    );
+    assert_eq!(
+        Command::parse("bar hide DP-1"),
+        Ok(Command::Bar {
+            action: visibility::Action::Hide,
+            output: Some(String::from("DP-1")),
+        })
+    );
+    assert!(Command::parse("bar").is_err());
+    assert!(Command::parse("show").is_err());
+    assert!(Command::parse("reboot").is_err());
+}
+
+#[test]
+fn test_json_string() {
+    assert_eq!(json_string("a \"b\"\n\\"), r#""a \"b\"\n\\""#);
+    assert_eq!(json_string("\u{1}"), r#""\u0001""#);
+}
+
+#[test]
+fn test_socket_needs_runtime_dir() {
+    assert!(socket_in(None, "wayland-1").is_err());
+    assert!(socket_in(Some(OsString::new()), "wayland-1").is_err());
+    assert_eq!(
+        socket_in(Some(OsString::from("/run/user/1000")), "wayland-1").unwrap(),
+        PathBuf::from("/run/user/1000/tagsandblocks-wayland-1.sock")
+    );
+}
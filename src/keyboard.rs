@@ -0,0 +1,304 @@
+// Keyboard layout indicator fed by wl_keyboard keymap and modifiers events.
+// Keymap is sent to every client, but modifiers and the active group only to the
+// one with keyboard focus. Layer surface of the bar doesn't take focus, it has it
+// only while a popup is open. So with several groups the shown one is the last
+// known and gets a stale marker when the bar loses focus. Caps and Num Lock are
+// read from keyboard LEDs in sysfs, which don't depend on focus
+
+use std::{
+    io,
+    os::fd::{AsRawFd, OwnedFd, RawFd},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::lifecycle;
+use crate::modules::{self, Module};
+
+// Real modifiers come first in xkb modifier mask, so their bits are fixed
+const CAPS_LOCK_MASK: u32 = 1 << 1;
+const NUM_LOCK_MASK: u32 = 1 << 4;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Group {
+    // Name of symbols file, e.g. "us" or "ru"
+    pub short: String,
+    // Description from keymap, e.g. "English (US)"
+    pub long: String,
+}
+
+#[derive(Default)]
+pub struct KeyboardState {
+    pub groups: Vec<Group>,
+    pub group: u32,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    // Modifiers are up to date only while bar has keyboard focus
+    pub focused: bool,
+}
+
+/// Used by wl_keyboard handler to update the indicator
+#[derive(Clone)]
+pub struct KeyboardHandle {
+    state: Arc<Mutex<KeyboardState>>,
+    // Wakes blocks thread up, without it changes are shown on the next interval
+    notify: Option<Arc<OwnedFd>>,
+}
+
+impl KeyboardHandle {
+    pub fn set_keymap(&self, keymap: &str) {
+        self.state.lock().unwrap().groups = parse_groups(keymap);
+        self.notify();
+    }
+
+    pub fn set_modifiers(&self, mods_locked: u32, group: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.group = group;
+        state.caps_lock = mods_locked & CAPS_LOCK_MASK != 0;
+        state.num_lock = mods_locked & NUM_LOCK_MASK != 0;
+        drop(state);
+        self.notify();
+    }
+
+    pub fn set_focused(&self, focused: bool) {
+        self.state.lock().unwrap().focused = focused;
+        self.notify();
+    }
+
+    fn notify(&self) {
+        let Some(notify) = &self.notify else {
+            return;
+        };
+        let value: u64 = 1;
+        unsafe {
+            libc::write(
+                notify.as_raw_fd(),
+                &value as *const u64 as *const libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+    }
+}
+
+pub struct KeyboardLayout {
+    // Placeholders: {layout} (short name), {name} (long name), {caps}, {num}
+    format: String,
+    caps_indicator: String,
+    num_indicator: String,
+    // Appended to layout that may have changed while bar had no keyboard focus
+    stale_marker: String,
+    handle: KeyboardHandle,
+}
+
+impl KeyboardLayout {
+    pub fn new(format: &str) -> Self {
+        let notify = match lifecycle::eventfd() {
+            Ok(notify) => Some(Arc::new(notify)),
+            Err(err) => {
+                log::warn!("Keyboard layout is updated only on interval: {err}");
+                None
+            }
+        };
+        Self {
+            format: format.to_string(),
+            caps_indicator: String::from("CAPS"),
+            num_indicator: String::from("NUM"),
+            stale_marker: String::from("?"),
+            handle: KeyboardHandle {
+                state: Arc::default(),
+                notify,
+            },
+        }
+    }
+
+    pub fn with_indicators(mut self, caps: &str, num: &str) -> Self {
+        self.caps_indicator = caps.to_string();
+        self.num_indicator = num.to_string();
+        self
+    }
+
+    pub fn with_stale_marker(mut self, marker: &str) -> Self {
+        self.stale_marker = marker.to_string();
+        self
+    }
+
+    pub fn handle(&self) -> KeyboardHandle {
+        self.handle.clone()
+    }
+}
+
+impl Module for KeyboardLayout {
+    fn update(&mut self) -> io::Result<String> {
+        if let Some(notify) = &self.handle.notify {
+            let mut counter: u64 = 0;
+            unsafe {
+                libc::read(
+                    notify.as_raw_fd(),
+                    &mut counter as *mut u64 as *mut libc::c_void,
+                    std::mem::size_of::<u64>(),
+                )
+            };
+        }
+        let state = self.handle.state.lock().unwrap();
+        let mut group = state
+            .groups
+            .get(state.group as usize)
+            .cloned()
+            .unwrap_or_default();
+        // Only group can't change unnoticed
+        if !state.focused && state.groups.len() > 1 {
+            group.short.push_str(&self.stale_marker);
+            group.long.push_str(&self.stale_marker);
+        }
+        // Modifiers are the fallback when there are no LEDs
+        let caps_lock = led_state(Path::new(LEDS), "capslock").unwrap_or(state.caps_lock);
+        let num_lock = led_state(Path::new(LEDS), "numlock").unwrap_or(state.num_lock);
+        let indicator = |enabled: bool, indicator: &str| {
+            if enabled {
+                indicator.to_string()
+            } else {
+                String::new()
+            }
+        };
+        Ok(modules::format(
+            &self.format,
+            &[
+                ("layout", group.short),
+                ("name", group.long),
+                ("caps", indicator(caps_lock, &self.caps_indicator)),
+                ("num", indicator(num_lock, &self.num_indicator)),
+            ],
+        ))
+    }
+
+    fn fd(&self) -> Option<RawFd> {
+        self.handle.notify.as_ref().map(|notify| notify.as_raw_fd())
+    }
+}
+
+const LEDS: &str = "/sys/class/leds";
+
+/// True if any keyboard has the lock LED on, e.g. "input3::capslock".
+/// None when there is no such LED or it can't be read
+fn led_state(leds: &Path, name: &str) -> Option<bool> {
+    let suffix = format!("::{name}");
+    let mut found = None;
+    for entry in std::fs::read_dir(leds).ok()?.flatten() {
+        if !entry.file_name().to_string_lossy().ends_with(&suffix) {
+            continue;
+        }
+        let Ok(brightness) = std::fs::read_to_string(entry.path().join("brightness")) else {
+            continue;
+        };
+        let on = brightness
+            .trim()
+            .parse::<u32>()
+            .is_ok_and(|brightness| brightness > 0);
+        found = Some(found.unwrap_or(false) || on);
+    }
+    found
+}
+
+/// Extracts layout groups from xkb_symbols section of the keymap
+pub fn parse_groups(keymap: &str) -> Vec<Group> {
+    let Some(symbols_start) = keymap.find("xkb_symbols") else {
+        return Vec::new();
+    };
+    let symbols = &keymap[symbols_start..];
+    let mut groups: Vec<Group> = Vec::new();
+
+    // Section header looks like: xkb_symbols "pc+us+ru:2+inet(evdev)+group(alt_shift_toggle)" {
+    let header = &symbols[..symbols.find('{').unwrap_or(symbols.len())];
+    if let Some(include) = header.split('"').nth(1) {
+        let mut first_layout = true;
+        for part in include.split('+') {
+            // Model specific symbols
+            if part == "pc" {
+                continue;
+            }
+            let (name, index) = match part.split_once(':') {
+                Some((name, index)) => (name, index.parse().ok()),
+                None if first_layout => (part, Some(1)),
+                // Option like inet(evdev)
+                None => continue,
+            };
+            first_layout = false;
+            let short = name.split('(').next().unwrap_or(name);
+            if let Some(index) = index.filter(|&index| index > 0) {
+                group_mut(&mut groups, index).short = short.to_string();
+            }
+        }
+    }
+
+    // name[Group1]="English (US)";
+    let mut rest = symbols;
+    while let Some(position) = rest.find("name[") {
+        rest = &rest[position + 5..];
+        let Some((index, tail)) = rest.split_once(']') else {
+            break;
+        };
+        let index = index
+            .trim_start_matches(|c: char| c.is_ascii_alphabetic())
+            .parse::<usize>()
+            .ok();
+        let name = tail.split('"').nth(1);
+        if let (Some(index), Some(name)) = (index.filter(|&index| index > 0), name) {
+            group_mut(&mut groups, index).long = name.to_string();
+        }
+    }
+    groups
+}
+
+// Groups are numbered from 1
+fn group_mut(groups: &mut Vec<Group>, index: usize) -> &mut Group {
+    if groups.len() < index {
+        groups.resize(index, Group::default());
+    }
+    &mut groups[index - 1]
+}
+
+#[test]
+fn test_parse_groups() {
+    let keymap = r#"xkb_keymap {
+xkb_keycodes "evdev+aliases(qwerty)" { minimum = 8; };
+xkb_types "complete" { };
+xkb_symbols "pc+us+ru:2+inet(evdev)+group(alt_shift_toggle)" {
+    name[Group1]="English (US)";
+    name[Group2]="Russian";
+    key <ESC> { [ Escape ] };
+};
+};"#;
+    assert_eq!(
+        parse_groups(keymap),
+        vec![
+            Group {
+                short: String::from("us"),
+                long: String::from("English (US)"),
+            },
+            Group {
+                short: String::from("ru"),
+                long: String::from("Russian"),
+            },
+        ]
+    );
+    assert!(parse_groups("xkb_keymap { };").is_empty());
+}
+
+#[test]
+fn test_led_state() {
+    let leds = std::env::temp_dir().join(format!("tagsandblocks-leds-{}", std::process::id()));
+    let led = |name: &str, brightness: &str| {
+        let dir = leds.join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("brightness"), brightness).unwrap();
+    };
+    led("input3::capslock", "0\n");
+    led("input3::numlock", "0\n");
+    led("input7::numlock", "1\n");
+    led("input3::scrolllock", "1\n");
+    assert_eq!(led_state(&leds, "capslock"), Some(false));
+    // Any keyboard with the LED on
+    assert_eq!(led_state(&leds, "numlock"), Some(true));
+    assert_eq!(led_state(&leds, "kanalock"), None);
+    std::fs::remove_dir_all(&leds).unwrap();
+}
@@ -0,0 +1,715 @@
+pub mod blocks;
+mod calendar;
+mod dbus;
+pub mod decoration;
+pub mod error;
+pub mod ipc;
+pub mod keyboard;
+mod lifecycle;
+mod menu;
+#[cfg(test)]
+mod mock;
+pub mod modules;
+pub mod network;
+pub mod output;
+mod popup;
+pub mod render;
+mod schedule;
+mod river_status_protocol;
+mod supervisor;
+pub mod tags;
+pub mod taskbar;
+pub mod theme;
+mod timer;
+pub mod tooltip;
+mod toplevels;
+mod tray;
+mod useless;
+pub mod visibility;
+mod wakeup;
+pub mod widget;
+
+use std::{
+    fs::File,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::river_status_protocol::{
+    zriver_output_status_v1, zriver_seat_status_v1, zriver_status_manager_v1,
+};
+use wayland_client::{
+    delegate_noop,
+    protocol::{
+        wl_buffer,
+        wl_compositor, wl_keyboard, wl_pointer, wl_registry, wl_seat, wl_shm, wl_shm_pool,
+        wl_surface,
+    },
+    Connection, Dispatch, QueueHandle,
+};
+
+use wayland_protocols::xdg::shell::client::xdg_wm_base;
+use wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1;
+use wayland_protocols_wlr::layer_shell::v1::client::{zwlr_layer_shell_v1, zwlr_layer_surface_v1};
+
+use blocks::{Block, BlockPopup};
+use decoration::Decorations;
+use keyboard::KeyboardHandle;
+use output::{BarConfig, OutputConfig};
+use tags::{TagLabels, Tags};
+use taskbar::{Taskbar, TaskbarEntry};
+use theme::Theme;
+use tooltip::Tooltips;
+use toplevels::Toplevel;
+use tray::Tray;
+use visibility::Visibility;
+use widget::{DrawContext, Title, Widget};
+
+const TYPICAL_OUTPUT_AMOUNT: usize = 3;
+type OutputsContexts = Arc<Mutex<Vec<OutputContext>>>;
+type SharedRiverStatus = Arc<Mutex<RiverStatus>>;
+// Replaced by main thread after reconnect
+type SharedConnection = Arc<Mutex<Connection>>;
+
+/// Everything that makes a bar, passed to run
+#[derive(Default)]
+pub struct Config {
+    // Drawn from right to left
+    pub blocks: Vec<Block>,
+    pub theme: Theme,
+    pub decorations: Decorations,
+    pub tag_labels: TagLabels,
+    pub taskbar: Taskbar,
+    pub tooltips: Tooltips,
+    pub visibility: Visibility,
+    // Lets keyboard layout block know about keymap of the seat
+    pub keyboard: Option<KeyboardHandle>,
+    // Drawn after title or taskbar from left to right
+    pub widgets: Vec<Box<dyn Widget>>,
+    // Bar on outputs by their names, first match is used
+    pub outputs: Vec<OutputConfig>,
+}
+
+/// Runs the bar until it's closed or gets SIGTERM. Fails if the bar can't start
+pub fn run(config: Config) -> error::Result<()> {
+    let outputs_contexts: OutputsContexts =
+        Arc::new(Mutex::new(Vec::with_capacity(TYPICAL_OUTPUT_AMOUNT)));
+
+    let mut blocks = config.blocks;
+    // Popups are opened by main thread, the rest of the block lives in blocks thread
+    let block_popups = blocks
+        .iter_mut()
+        .filter_map(|block| Some((block.name.clone(), block.popup.take()?)))
+        .collect();
+    let theme = config.theme;
+    let decorations = config.decorations;
+    let tray = match Tray::connect() {
+        Ok(tray) => Some(tray),
+        Err(err) => {
+            log::warn!("System tray is unavailable: {err}");
+            None
+        }
+    };
+    let river_status: SharedRiverStatus = Arc::new(Mutex::new(RiverStatus::default()));
+    let shutdown = Arc::new(lifecycle::Shutdown::new()?);
+    let redraw = Arc::new(lifecycle::Redraw::new()?);
+    let mut backoff = supervisor::Backoff::new(Duration::from_millis(250), Duration::from_secs(10));
+    let Some(mut conn) = supervisor::retry(&mut backoff, &shutdown, Connection::connect_to_env)
+    else {
+        return Ok(());
+    };
+    let shared_conn: SharedConnection = Arc::new(Mutex::new(conn.clone()));
+
+    let blocks_thread = blocks::spawn_and_configure_blocks_updates_thread(
+        blocks,
+        Arc::clone(&outputs_contexts),
+        Arc::clone(&shared_conn),
+        theme,
+        decorations.clone(),
+        Arc::clone(&river_status),
+        Arc::clone(&shutdown),
+        Arc::clone(&redraw),
+    )?;
+    let mut bar = Bar::new(
+        Arc::clone(&outputs_contexts),
+        theme,
+        decorations,
+        config.tag_labels,
+        config.taskbar,
+        tray,
+        config.tooltips,
+        block_popups,
+        config.widgets,
+        config.visibility,
+        config.outputs,
+        config.keyboard,
+        river_status,
+        redraw,
+    )?;
+
+    loop {
+        let mut event_queue = conn.new_event_queue();
+        let qhandle = event_queue.handle();
+        conn.display().get_registry(&qhandle, ());
+
+        match supervisor::run(&mut event_queue, &mut bar, &shutdown) {
+            Ok(()) => break,
+            Err(err) => log::error!("Lost connection to compositor: {err}"),
+        }
+        bar.forget();
+        match supervisor::retry(&mut backoff, &shutdown, Connection::connect_to_env) {
+            Some(new_conn) => conn = new_conn,
+            None => break,
+        }
+        *shared_conn.lock().unwrap() = conn.clone();
+    }
+
+    // Blocks thread draws on our surfaces, so it should be stopped first
+    shutdown.trigger();
+    if blocks_thread.join().is_err() {
+        log::error!("Blocks thread panicked");
+    }
+    bar.destroy();
+    let _ = conn.flush();
+    Ok(())
+}
+
+pub struct OutputContext {
+    // wl_output name, e.g. "DP-1"
+    name: String,
+    ready_to_draw: bool,
+    visible: bool,
+    // Shown because river entered a reveal mode, hidden again when it leaves
+    revealed_by_mode: bool,
+    auto_hide: bool,
+    width: i32,
+    height: i32,
+    // Leftmost pixel occupied by blocks on last draw
+    blocks_offset: i32,
+    // Tray icons are drawn by main thread at the right edge, blocks go left of them
+    tray_width: i32,
+    // Blocks drawn last time, published by blocks thread
+    block_spans: Vec<blocks::BlockSpan>,
+    // Drawn last time by main thread, for pointer hit testing. Tags as spans and tag bits
+    tag_spans: Vec<(i32, i32, u32)>,
+    widget_spans: Vec<(i32, i32)>,
+    // Tray icons as spans from the right edge and item indices
+    tray_spans: Vec<(i32, i32, usize)>,
+    taskbar_entries: Vec<TaskbarEntry>,
+    // Where tags and title ended last time, -1 when the bar has to be drawn whole
+    previous_tags_and_title_length: i32,
+    config: BarConfig,
+    current_buffer_index: usize,
+    surface: wl_surface::WlSurface,
+    layer_surface: zwlr_layer_surface_v1::ZwlrLayerSurfaceV1,
+    // Created after layer surface is configured
+    buffers: Option<output::Buffers>,
+}
+
+/// Copy of river state that widgets are drawn from, shared with blocks thread
+#[derive(Clone, Default)]
+pub struct RiverStatus {
+    pub focused_tags: u32,
+    // Sorted tags of views, one entry per distinct tags
+    pub view_tags: Vec<u32>,
+    pub urgent_tags: u32,
+    // Title of focused view
+    pub title: String,
+    // Tags and app_id of views from zwlr_foreign_toplevel_manager_v1
+    pub views: Vec<(u32, String)>,
+}
+
+impl RiverStatus {
+    pub fn app_ids_on_tag(&self, tag: u32) -> impl Iterator<Item = &str> {
+        self.views
+            .iter()
+            .filter(move |(tags, _)| tags & tag != 0)
+            .map(|(_, app_id)| app_id.as_str())
+    }
+}
+
+struct Bar {
+    ready_to_draw: bool,
+    // SHould be purished
+    running: bool,
+
+    file: File,
+    shm: Option<wl_shm::WlShm>,
+    layer_shell: Option<zwlr_layer_shell_v1::ZwlrLayerShellV1>,
+    compositor: Option<wl_compositor::WlCompositor>,
+    xdg_wm_base: Option<xdg_wm_base::XdgWmBase>,
+    foreign_toplevel_manager: Option<zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1>,
+    river_status_manager: Option<zriver_status_manager_v1::ZriverStatusManagerV1>,
+    seat: Option<wl_seat::WlSeat>,
+    // Devices of the seat, present while it has their capability
+    wl_pointer: Option<wl_pointer::WlPointer>,
+    wl_keyboard: Option<wl_keyboard::WlKeyboard>,
+    river_seat_status: Option<zriver_seat_status_v1::ZriverSeatStatusV1>,
+
+    theme: Theme,
+    decorations: Decorations,
+    tags_widget: Tags,
+    title_widget: Title,
+    taskbar: Taskbar,
+    // Accumulated scroll that didn't reach a step yet
+    taskbar_scroll: f64,
+    tray: Option<Tray>,
+    popup: Option<popup::Popup>,
+    // Block names and what clicking them opens
+    block_popups: Vec<(String, BlockPopup)>,
+    // Custom widgets, their spans are kept by outputs
+    widgets: Vec<Box<dyn Widget>>,
+    tooltips: Tooltips,
+    // None if it couldn't be created, tooltips are disabled then
+    tooltip_timer: Option<tooltip::Timer>,
+    // Widget under pointer and output it's on
+    hovered: Option<(usize, tooltip::Hover)>,
+    // Pointer position on the bar surface, negative when pointer is elsewhere
+    pointer_x: f64,
+    pointer_y: f64,
+    pointer_surface: Option<wl_surface::WlSurface>,
+    visibility: Visibility,
+    output_configs: Vec<OutputConfig>,
+    revealed_by_mode: bool,
+    keyboard: Option<KeyboardHandle>,
+    tags: Vec<u32>,
+    focused_tag: u32,
+    urgent_tags: u32,
+    title: String,
+    toplevels: Vec<Toplevel>,
+    river_status: SharedRiverStatus,
+    redraw: Arc<lifecycle::Redraw>,
+
+    // All subsequent variables depends on outputs in some way and therefore should be configured inside wl_output's events
+    // Announced outputs, with or without bar
+    outputs: Vec<output::Output>,
+    pool: Option<(wl_shm_pool::WlShmPool, i32)>,
+    layer_surfaces: Vec<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1>,
+    outputs_contexts: OutputsContexts,
+}
+
+impl Bar {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        outputs_contexts: OutputsContexts,
+        theme: Theme,
+        decorations: Decorations,
+        tag_labels: TagLabels,
+        taskbar: Taskbar,
+        tray: Option<Tray>,
+        mut tooltips: Tooltips,
+        block_popups: Vec<(String, BlockPopup)>,
+        widgets: Vec<Box<dyn Widget>>,
+        visibility: Visibility,
+        output_configs: Vec<OutputConfig>,
+        keyboard: Option<KeyboardHandle>,
+        river_status: SharedRiverStatus,
+        redraw: Arc<lifecycle::Redraw>,
+    ) -> error::Result<Self> {
+        let mut tags = Vec::with_capacity(9);
+        tags.push(1);
+        let tooltip_timer = match tooltip::Timer::new() {
+            Ok(timer) => Some(timer),
+            Err(err) => {
+                log::warn!("Tooltips are disabled, can't create their timer: {err}");
+                tooltips.enabled = false;
+                None
+            }
+        };
+
+        // Pool of all bars grows in it, pages are only used once they are drawn on
+        let file = output::shared_memory(c"tagsandblocks", 2u64.pow(30))?;
+        Ok(Self {
+            ready_to_draw: false,
+            running: true,
+            file,
+            shm: None,
+            layer_shell: None,
+            compositor: None,
+            xdg_wm_base: None,
+            foreign_toplevel_manager: None,
+            river_status_manager: None,
+            seat: None,
+            wl_pointer: None,
+            wl_keyboard: None,
+            river_seat_status: None,
+            theme,
+            decorations,
+            tags_widget: Tags::new(tag_labels),
+            title_widget: Title,
+            taskbar,
+            taskbar_scroll: 0.,
+            tray,
+            popup: None,
+            block_popups,
+            widgets,
+            tooltips,
+            tooltip_timer,
+            hovered: None,
+            pointer_x: -1.,
+            pointer_y: -1.,
+            pointer_surface: None,
+            visibility,
+            output_configs,
+            revealed_by_mode: false,
+            keyboard,
+            tags,
+            focused_tag: 1,
+            urgent_tags: 0,
+            title: String::from("Have a nice day!"),
+            toplevels: Vec::new(),
+            river_status,
+            redraw,
+            outputs: Vec::with_capacity(3),
+            pool: None,
+            layer_surfaces: Vec::with_capacity(3),
+            outputs_contexts,
+        })
+    }
+}
+
+impl Dispatch<wl_registry::WlRegistry, ()> for Bar {
+    fn event(
+        state: &mut Self,
+        registry: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } => {
+                match &interface[..] {
+                    "wl_shm" => {
+                        state.shm = Some(registry.bind::<wl_shm::WlShm, _, _>(name, 1, qh, ()));
+                    }
+                    "zwlr_layer_shell_v1" => {
+                        state.layer_shell = Some(
+                            // Version 4 allows on demand keyboard focus for popups
+                            registry.bind::<zwlr_layer_shell_v1::ZwlrLayerShellV1, _, _>(
+                                name,
+                                version.min(4),
+                                qh,
+                                (),
+                            ),
+                        );
+                    }
+                    "wl_compositor" => {
+                        state.compositor = Some(
+                            registry.bind::<wl_compositor::WlCompositor, _, _>(name, 1, qh, ()),
+                        );
+                    }
+                    "zwlr_foreign_toplevel_manager_v1" => {
+                        state.foreign_toplevel_manager = Some(
+                            registry
+                                .bind::<zwlr_foreign_toplevel_manager_v1::ZwlrForeignToplevelManagerV1, _, _>(
+                                    name,
+                                    version.min(3),
+                                    qh,
+                                    (),
+                                ),
+                        );
+                    }
+                    "zriver_status_manager_v1" => {
+                        state.river_status_manager = Some(
+                            registry.bind::<zriver_status_manager_v1::ZriverStatusManagerV1, _, _>(
+                                name,
+                                version,
+                                qh,
+                                (),
+                            ),
+                        );
+                    }
+                    "xdg_wm_base" => {
+                        state.xdg_wm_base = Some(
+                            registry.bind::<xdg_wm_base::XdgWmBase, _, _>(name, 1, qh, ()),
+                        );
+                    }
+                    "wl_seat" => {
+                        state.seat =
+                            Some(registry.bind::<wl_seat::WlSeat, _, _>(name, version, qh, ()));
+                    }
+                    "wl_output" => state.add_output(registry, name, version, qh),
+                    _ => (),
+                }
+                // Outputs may have waited for this global
+                state.setup_outputs(qh);
+            }
+            wl_registry::Event::GlobalRemove { name } => state.remove_output(name),
+            _ => (),
+        }
+        // Optimize later to not process this every event
+        if state.river_seat_status.is_none() {
+            if let (Some(manager), Some(seat)) = (&state.river_status_manager, &state.seat) {
+                state.river_seat_status = Some(manager.get_river_seat_status(seat, qh, ()));
+            }
+        }
+    }
+}
+
+// Ignore events from these object types in this example.
+delegate_noop!(Bar: ignore wl_compositor::WlCompositor);
+delegate_noop!(Bar: ignore wl_surface::WlSurface);
+delegate_noop!(Bar: ignore wl_shm::WlShm);
+delegate_noop!(Bar: ignore wl_shm_pool::WlShmPool);
+delegate_noop!(Bar: ignore wl_buffer::WlBuffer);
+delegate_noop!(Bar: ignore zriver_status_manager_v1::ZriverStatusManagerV1);
+delegate_noop!(Bar: ignore zwlr_layer_shell_v1::ZwlrLayerShellV1);
+
+impl Bar {
+    fn publish_river_status(&self) {
+        let mut river_status = self.river_status.lock().unwrap();
+        river_status.focused_tags = self.focused_tag;
+        river_status.view_tags.clone_from(&self.tags);
+        river_status.urgent_tags = self.urgent_tags;
+        river_status.title.clone_from(&self.title);
+        river_status.views = self
+            .toplevels
+            .iter()
+            .map(|toplevel| (toplevel.tags, toplevel.app_id.clone()))
+            .collect();
+    }
+
+    fn draw_tags_and_title(&mut self) {
+        // Widgets and IPC queries see the same state as the bar
+        self.publish_river_status();
+        if !self.ready_to_draw {
+            return;
+        }
+        let river = self.river_status.lock().unwrap().clone();
+        let mut outputs_contexts = self.outputs_contexts.lock().unwrap();
+
+        for output_context in outputs_contexts.iter_mut() {
+            if !output_context.ready_to_draw {
+                continue;
+            }
+            let Some(buffers) = output_context.buffers.as_mut() else {
+                continue;
+            };
+            let previous_length = output_context.previous_tags_and_title_length;
+            let width = output_context.width;
+            let height = output_context.height;
+            let surface = &output_context.surface;
+            // Blocks drawn by blocks thread are kept from the last commit
+            buffers.copy_previous(output_context.current_buffer_index);
+            let buffer = &buffers.buffers[output_context.current_buffer_index];
+            let mmap_ptr = buffers.canvases[output_context.current_buffer_index].as_mut_ptr();
+            let cr = unsafe { render::buffer_context(mmap_ptr, width, height) };
+            let pg_layout = render::create_layout(&cr);
+
+            let theme = &self.theme;
+            if previous_length == -1 {
+                theme::clear_rectangle(&cr, &theme.bg, 0.0, 0.0, width as f64, height as f64);
+            }
+            theme::clear_rectangle(
+                &cr,
+                &theme.bg,
+                0.0,
+                0.0,
+                previous_length as f64,
+                height as f64,
+            );
+
+            // Title is ellipsized to end where blocks were drawn last time
+            let right = output_context.blocks_offset;
+            let mut context = DrawContext {
+                cr: &cr,
+                pg_layout: &pg_layout,
+                theme,
+                decorations: &self.decorations,
+                river: &river,
+                height,
+                space: right,
+            };
+            // Parts left out by config of the output aren't drawn
+            let config = &output_context.config;
+            let mut offset = 0;
+            output_context.tag_spans.clear();
+            if config.tags {
+                offset = render::draw_widget(&mut context, &mut self.tags_widget, 0, right);
+                output_context.tag_spans = self.tags_widget.spans().to_vec();
+            }
+
+            output_context.taskbar_entries.clear();
+            if config.title {
+                context.space = right - offset;
+                let mut space = right - offset;
+                if config.widgets {
+                    space -= render::widgets_width(&context, &mut self.widgets);
+                }
+                if self.taskbar.enabled {
+                    let (taskbar_width, entries) =
+                        self.draw_taskbar(&cr, &pg_layout, offset, space, height);
+                    output_context.taskbar_entries = entries;
+                    offset += taskbar_width;
+                } else {
+                    let title = &mut self.title_widget;
+                    offset += render::draw_widget(&mut context, title, offset, space);
+                }
+            }
+
+            output_context.widget_spans.clear();
+            if config.widgets {
+                let widget_spans =
+                    render::draw_widgets(&mut context, &mut self.widgets, offset, right);
+                offset = widget_spans.last().map_or(offset, |span| span.1);
+                output_context.widget_spans = widget_spans;
+            }
+
+            let previous_tray_width = output_context.tray_width;
+            let (tray_width, tray_spans) = if config.tray {
+                self.draw_tray(&cr, width, height, previous_tray_width)
+            } else {
+                (0, Vec::new())
+            };
+            output_context.tray_spans = tray_spans;
+            if tray_width != previous_tray_width {
+                output_context.tray_width = tray_width;
+                // Blocks have to move
+                self.redraw.request();
+            }
+
+            let damage_width = if previous_length == -1 {
+                width
+            } else {
+                offset.max(previous_length)
+            };
+            let tray_damage = tray_width.max(previous_tray_width);
+            surface.attach(Some(buffer), 0, 0);
+            surface.damage(0, 0, damage_width, height);
+            surface.damage(width - tray_damage, 0, tray_damage, height);
+            surface.commit();
+
+            output_context.previous_tags_and_title_length = offset;
+
+            output_context.current_buffer_index += 1;
+            output_context.current_buffer_index %= 2;
+        }
+    }
+}
+
+impl Dispatch<zriver_seat_status_v1::ZriverSeatStatusV1, ()> for Bar {
+    fn event(
+        state: &mut Self,
+        _: &zriver_seat_status_v1::ZriverSeatStatusV1,
+        event: <zriver_seat_status_v1::ZriverSeatStatusV1 as wayland_client::Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zriver_seat_status_v1::Event::FocusedView { title } => {
+                state.title = title;
+                state.draw_tags_and_title();
+            }
+            zriver_seat_status_v1::Event::Mode { name } => {
+                state.set_river_mode(name);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<zriver_output_status_v1::ZriverOutputStatusV1, ()> for Bar {
+    fn event(
+        state: &mut Self,
+        _: &zriver_output_status_v1::ZriverOutputStatusV1,
+        event: <zriver_output_status_v1::ZriverOutputStatusV1 as wayland_client::Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use zriver_output_status_v1::Event;
+        match event {
+            Event::FocusedTags { tags } => {
+                state.focused_tag = tags;
+                state.draw_tags_and_title();
+            }
+            Event::UrgentTags { tags } => {
+                state.urgent_tags = tags;
+                state.draw_tags_and_title();
+            }
+            Event::ViewTags { tags } => {
+                let mut tags: Vec<u32> = tags
+                    .chunks_exact(4)
+                    .map(|bytes_4| u32::from_ne_bytes(bytes_4.try_into().unwrap()))
+                    .collect();
+                tags.sort();
+                tags.dedup();
+                state.tags = tags;
+                state.draw_tags_and_title();
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1, ()> for Bar {
+    fn event(
+        state: &mut Self,
+        proxy: &zwlr_layer_surface_v1::ZwlrLayerSurfaceV1,
+        event: <zwlr_layer_surface_v1::ZwlrLayerSurfaceV1 as wayland_client::Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        use zwlr_layer_surface_v1::Event;
+        match event {
+            Event::Configure {
+                serial,
+                width,
+                height,
+            } => {
+                let Some(output_index) = state
+                    .layer_surfaces
+                    .iter()
+                    .position(|layer_surface| layer_surface == proxy)
+                else {
+                    log::warn!("Configure of unknown layer surface");
+                    return;
+                };
+                proxy.ack_configure(serial);
+                if !state.configure_bar(output_index, width, height, qh) {
+                    return;
+                }
+                // Should be purished
+                state.ready_to_draw = true;
+                let mut outputs_contexts = state.outputs_contexts.lock().unwrap();
+                let output_context = &mut outputs_contexts[output_index];
+                if !output_context.visible {
+                    output_context.present_strip();
+                    return;
+                }
+                // Blocks thread has nothing new to draw on fresh surface otherwise
+                if !output_context.ready_to_draw {
+                    state.redraw.request();
+                }
+                output_context.ready_to_draw = true;
+                drop(outputs_contexts);
+                state.draw_tags_and_title();
+            }
+            // Compositor won't show this surface anymore, e.g. when output was removed
+            Event::Closed => state.close_bar(proxy),
+            _ => (),
+        }
+    }
+}
+
+fn bitflag_to_pos(mut bitflag: u32) -> u32 {
+    let mut pos = 0;
+    while bitflag != 0 {
+        bitflag >>= 1;
+        pos += 1;
+    }
+    pos
+}
+
+#[test]
+fn test_bitflag() {
+    assert_eq!(bitflag_to_pos(0b1), 1);
+    assert_eq!(bitflag_to_pos(0b100), 3);
+}
@@ -0,0 +1,171 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    process::Command,
+    time::Duration,
+};
+
+use wayland_client::Proxy;
+
+use crate::Bar;
+
+pub(crate) fn eventfd() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn write_eventfd(fd: RawFd) {
+    let value: u64 = 1;
+    unsafe {
+        libc::write(
+            fd,
+            &value as *const u64 as *const libc::c_void,
+            std::mem::size_of::<u64>(),
+        )
+    };
+}
+
+/// Level triggered shutdown flag that both threads can poll.
+/// It's never reset, so every poller sees it once it's triggered
+pub struct Shutdown(OwnedFd);
+
+impl Shutdown {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self(eventfd()?))
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+
+    pub fn trigger(&self) {
+        write_eventfd(self.fd());
+    }
+
+    /// Sleeps for timeout unless shutdown is triggered. Returns true if it was
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let mut pfd = libc::pollfd {
+            fd: self.fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        unsafe { libc::poll(&mut pfd, 1, timeout) > 0 }
+    }
+}
+
+/// Asks blocks thread to draw blocks again, e.g. on freshly configured surfaces
+pub struct Redraw(OwnedFd);
+
+impl Redraw {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self(eventfd()?))
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+
+    pub fn request(&self) {
+        write_eventfd(self.fd());
+    }
+
+    pub fn clear(&self) {
+        let mut counter: u64 = 0;
+        unsafe {
+            libc::read(
+                self.fd(),
+                &mut counter as *mut u64 as *mut libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+    }
+}
+
+/// Runs command in background. Thread waits for the child, so only children of
+/// the bar are reaped and the application embedding it keeps SIGCHLD for itself
+pub fn spawn(command: &mut Command) -> io::Result<()> {
+    let mut child = command.spawn()?;
+    std::thread::spawn(move || {
+        let _ = child.wait();
+    });
+    Ok(())
+}
+
+impl Bar {
+    /// Destroys Wayland objects, children before their parents
+    pub fn destroy(&mut self) {
+        self.close_popup();
+        let mut outputs_contexts = self.outputs_contexts.lock().unwrap();
+        for output_context in outputs_contexts.drain(..) {
+            output_context.layer_surface.destroy();
+            output_context.surface.destroy();
+            if let Some(buffers) = &output_context.buffers {
+                buffers.destroy();
+            }
+        }
+        drop(outputs_contexts);
+        self.layer_surfaces.clear();
+        if let Some((pool, _)) = self.pool.take() {
+            pool.destroy();
+        }
+
+        for toplevel in self.toplevels.drain(..) {
+            toplevel.handle.destroy();
+        }
+        if let Some(manager) = self.foreign_toplevel_manager.take() {
+            manager.stop();
+        }
+
+        for output in self.outputs.drain(..) {
+            output.release();
+        }
+        if let Some(seat_status) = self.river_seat_status.take() {
+            seat_status.destroy();
+        }
+        if let Some(manager) = self.river_status_manager.take() {
+            manager.destroy();
+        }
+        if let Some(wm_base) = self.xdg_wm_base.take() {
+            wm_base.destroy();
+        }
+        self.release_pointer();
+        self.release_keyboard();
+        if let Some(seat) = self.seat.take() {
+            if seat.version() >= 5 {
+                seat.release();
+            }
+        }
+    }
+
+    /// Forgets objects of a dead connection without sending destructors,
+    /// so everything is created again on the next one
+    pub fn forget(&mut self) {
+        self.outputs_contexts.lock().unwrap().clear();
+        self.layer_surfaces.clear();
+        self.pool = None;
+        self.toplevels.clear();
+        self.popup = None;
+        self.hovered = None;
+        if let Some(timer) = &mut self.tooltip_timer {
+            timer.disarm();
+        }
+        self.pointer_surface = None;
+        self.foreign_toplevel_manager = None;
+        self.river_seat_status = None;
+        self.river_status_manager = None;
+        self.outputs.clear();
+        self.seat = None;
+        self.wl_pointer = None;
+        self.wl_keyboard = None;
+        self.shm = None;
+        self.layer_shell = None;
+        self.compositor = None;
+        self.xdg_wm_base = None;
+        self.ready_to_draw = false;
+        self.revealed_by_mode = false;
+    }
+}
@@ -0,0 +1,281 @@
+// Menus shown in popups: dbusmenu of tray items and commands configured for blocks
+
+use pangocairo::cairo as cr;
+use pangocairo::pango;
+use wayland_client::QueueHandle;
+
+use crate::blocks::BlockPopup;
+use crate::lifecycle;
+use crate::popup::{self, Content};
+use crate::render;
+use crate::theme::{self, Theme};
+use crate::Bar;
+
+// Space around menu entries
+const PADDING: i32 = 6;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MenuItem {
+    pub id: i32,
+    pub label: String,
+    pub enabled: bool,
+    pub separator: bool,
+    // State of checkbox or radio item
+    pub toggle: Option<bool>,
+    pub children: Vec<MenuItem>,
+}
+
+impl MenuItem {
+    pub fn new(id: i32, label: &str) -> Self {
+        Self {
+            id,
+            label: label.to_string(),
+            enabled: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Who is told about clicked item
+#[derive(Clone)]
+pub enum MenuSource {
+    // dbusmenu object of a tray item
+    Tray { service: String, path: String },
+    // Name of the block, item ids are indices of it's commands
+    Block(String),
+}
+
+pub struct Menu {
+    pub source: MenuSource,
+    pub items: Vec<MenuItem>,
+    pub hovered: Option<usize>,
+    item_height: i32,
+}
+
+impl Menu {
+    pub fn new(source: MenuSource, items: Vec<MenuItem>) -> Self {
+        Self {
+            source,
+            items,
+            hovered: None,
+            item_height: 0,
+        }
+    }
+
+    /// Size of popup needed to show the menu
+    pub fn size(&mut self) -> (i32, i32) {
+        let cr = popup::measure_context();
+        let pg_layout = render::create_layout(&cr);
+        let mut width = 0;
+        pg_layout.set_text("Ag");
+        self.item_height = pg_layout.pixel_size().1 + PADDING * 2;
+        for item in &self.items {
+            pg_layout.set_text(&Self::label(item));
+            width = width.max(pg_layout.pixel_size().0);
+        }
+        let height = self
+            .items
+            .iter()
+            .map(|item| self.row_height(item))
+            .sum::<i32>();
+        (width + PADDING * 2, height.max(1))
+    }
+
+    fn row_height(&self, item: &MenuItem) -> i32 {
+        if item.separator {
+            PADDING
+        } else {
+            self.item_height
+        }
+    }
+
+    fn label(item: &MenuItem) -> String {
+        let mut label = match item.toggle {
+            Some(true) => format!("● {}", item.label),
+            Some(false) => format!("○ {}", item.label),
+            None => item.label.clone(),
+        };
+        if !item.children.is_empty() {
+            label.push_str(" ▸");
+        }
+        label
+    }
+
+    fn is_selectable(item: &MenuItem) -> bool {
+        !item.separator && item.enabled
+    }
+
+    pub fn item_at(&self, y: f64) -> Option<usize> {
+        let mut top = 0;
+        for (index, item) in self.items.iter().enumerate() {
+            let bottom = top + self.row_height(item);
+            if (top as f64..bottom as f64).contains(&y) {
+                return Self::is_selectable(item).then_some(index);
+            }
+            top = bottom;
+        }
+        None
+    }
+
+    /// Returns true if hovered item has changed
+    pub fn hover(&mut self, y: f64) -> bool {
+        let hovered = self.item_at(y);
+        let changed = hovered != self.hovered;
+        self.hovered = hovered;
+        changed
+    }
+
+    /// Moves hover to next selectable item in direction of step, wrapping around
+    pub fn select(&mut self, step: i32) {
+        let len = self.items.len() as i32;
+        let mut index = match self.hovered {
+            Some(index) => index as i32,
+            None if step > 0 => -1,
+            None => len,
+        };
+        for _ in 0..len {
+            index = (index + step).rem_euclid(len);
+            if Self::is_selectable(&self.items[index as usize]) {
+                self.hovered = Some(index as usize);
+                return;
+            }
+        }
+    }
+
+    pub fn draw(&self, cr: &cr::Context, pg_layout: &pango::Layout, theme: &Theme, width: i32) {
+        let height: i32 = self.items.iter().map(|item| self.row_height(item)).sum();
+        theme::clear_rectangle(cr, &theme.bg, 0., 0., width as f64, height as f64);
+        let mut top = 0;
+        for (index, item) in self.items.iter().enumerate() {
+            let row_height = self.row_height(item);
+            if item.separator {
+                theme.fg.with_alpha(0.3).set_source(cr);
+                cr.rectangle(
+                    PADDING as f64,
+                    (top + row_height / 2) as f64,
+                    (width - PADDING * 2) as f64,
+                    1.,
+                );
+                cr.fill().unwrap();
+                top += row_height;
+                continue;
+            }
+            let foreground = if self.hovered == Some(index) {
+                theme.focused.set_source(cr);
+                cr.rectangle(0., top as f64, width as f64, row_height as f64);
+                cr.fill().unwrap();
+                theme.focused_fg
+            } else if item.enabled {
+                theme.fg
+            } else {
+                theme.fg.with_alpha(0.5)
+            };
+            pg_layout.set_text(&Self::label(item));
+            foreground.set_source(cr);
+            cr.move_to(PADDING as f64, (top + PADDING) as f64);
+            pangocairo::show_layout(cr, pg_layout);
+            top += row_height;
+        }
+    }
+}
+
+impl Bar {
+    /// Handles press inside of the menu popup
+    pub fn menu_button(&mut self, serial: u32, qh: &QueueHandle<Self>) {
+        let Some(popup) = &self.popup else {
+            return;
+        };
+        let Content::Menu(menu) = &popup.content else {
+            return;
+        };
+        if let Some(index) = menu.item_at(self.pointer_y) {
+            self.activate_menu_item(index, serial, qh);
+        }
+    }
+
+    /// Runs leaf item or opens submenu of the item in opened menu
+    pub fn activate_menu_item(&mut self, index: usize, serial: u32, qh: &QueueHandle<Self>) {
+        let Some(popup) = &self.popup else {
+            return;
+        };
+        let Content::Menu(menu) = &popup.content else {
+            return;
+        };
+        let item = menu.items[index].clone();
+        let source = menu.source.clone();
+        if item.children.is_empty() {
+            self.close_popup();
+            self.menu_item_clicked(source, item.id);
+            return;
+        }
+        // Submenu replaces the menu in a new popup of the right size,
+        // it's opened where the menu was because pointer may be over the popup
+        let output_index = popup.output_index;
+        let (anchor_x, anchor_width) = popup.anchor;
+        let mut submenu = Menu::new(source, item.children);
+        let size = submenu.size();
+        self.open_popup(
+            output_index,
+            anchor_x,
+            anchor_width,
+            size,
+            Some(serial),
+            Content::Menu(submenu),
+            qh,
+        );
+    }
+
+    fn menu_item_clicked(&mut self, source: MenuSource, id: i32) {
+        match source {
+            MenuSource::Tray { service, path } => {
+                if let Some(tray) = &mut self.tray {
+                    tray.menu_clicked(&service, &path, id);
+                }
+            }
+            MenuSource::Block(name) => {
+                let command = self
+                    .block_popups
+                    .iter_mut()
+                    .find(|(block, _)| *block == name)
+                    .and_then(|(_, popup)| match popup {
+                        BlockPopup::Menu(commands) => commands.get_mut(id as usize),
+                        BlockPopup::Calendar => None,
+                    });
+                if let Some((label, command)) = command {
+                    if let Err(err) = lifecycle::spawn(command) {
+                        log::error!("Can't run {label} from menu of {name}: {err}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_select_skips_separators_and_disabled() {
+    let separator = MenuItem {
+        separator: true,
+        ..Default::default()
+    };
+    let disabled = MenuItem {
+        enabled: false,
+        ..MenuItem::new(2, "Disabled")
+    };
+    let mut menu = Menu::new(
+        MenuSource::Block(String::from("date")),
+        vec![
+            MenuItem::new(0, "First"),
+            separator,
+            disabled,
+            MenuItem::new(3, "Last"),
+        ],
+    );
+    menu.select(1);
+    assert_eq!(menu.hovered, Some(0));
+    menu.select(1);
+    assert_eq!(menu.hovered, Some(3));
+    menu.select(1);
+    assert_eq!(menu.hovered, Some(0));
+    menu.select(-1);
+    assert_eq!(menu.hovered, Some(3));
+}
@@ -0,0 +1,1263 @@
+// In-process compositor that talks to the bar over a socket pair like river would.
+// Tests script river events with it and look at what the bar has committed
+
+use std::{
+    fs::File,
+    os::unix::net::UnixStream,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use wayland_client::{Connection, EventQueue};
+use wayland_protocols_wlr::layer_shell::v1::server::{zwlr_layer_shell_v1, zwlr_layer_surface_v1};
+use wayland_server::{
+    backend::{ClientData, GlobalId},
+    protocol::{
+        wl_buffer, wl_compositor, wl_keyboard, wl_output, wl_pointer, wl_seat, wl_shm, wl_shm_pool,
+        wl_surface,
+    },
+    Client, DataInit, Dispatch, Display, DisplayHandle, GlobalDispatch, New, Resource, WEnum,
+};
+
+use crate::blocks::{self, Block};
+use crate::decoration::Decorations;
+use crate::lifecycle;
+use crate::output::{BarConfig, OutputConfig, Position};
+use crate::tags::TagLabels;
+use crate::taskbar::Taskbar;
+use crate::theme::{Color, Theme};
+use crate::tooltip::Tooltips;
+use crate::visibility::Visibility;
+use crate::{Bar, RiverStatus};
+
+#[allow(non_camel_case_types)]
+#[allow(non_upper_case_globals)]
+mod river_server {
+    #[allow(clippy::single_component_path_imports)]
+    use wayland_server;
+    use wayland_server::protocol::*;
+
+    pub mod __interfaces {
+        use wayland_server::protocol::__interfaces::*;
+        wayland_scanner::generate_interfaces!("./protocols/river-status-unstable-v1.xml");
+    }
+    use self::__interfaces::*;
+
+    wayland_scanner::generate_server_code!("./protocols/river-status-unstable-v1.xml");
+}
+use river_server::{zriver_output_status_v1, zriver_seat_status_v1, zriver_status_manager_v1};
+
+// Bars of different tests run one at a time, like the bar runs alone on a seat
+static BAR_LOCK: Mutex<()> = Mutex::new(());
+
+/// What the bar has sent to the compositor
+#[derive(Default)]
+pub struct Server {
+    pub surfaces: Vec<Surface>,
+    pub layer_surfaces: Vec<LayerSurface>,
+    pools: Vec<Pool>,
+    // River statuses and indices of outputs they were requested for
+    output_statuses: Vec<(usize, zriver_output_status_v1::ZriverOutputStatusV1)>,
+    seat_statuses: Vec<zriver_seat_status_v1::ZriverSeatStatusV1>,
+    // Bound outputs with their indices and sizes for surfaces on them
+    outputs: Vec<(usize, wl_output::WlOutput, (u32, u32))>,
+    seats: Vec<wl_seat::WlSeat>,
+    // Pointers and keyboards the bar got and didn't release
+    pub pointers: usize,
+    pub keyboards: usize,
+    serial: u32,
+}
+
+pub struct Surface {
+    buffer: Option<wl_buffer::WlBuffer>,
+    // Layer surface that gave the surface it's role
+    layer: Option<usize>,
+    pub commits: Vec<Commit>,
+}
+
+/// Contents of attached buffer at the moment of commit
+pub struct Commit {
+    pub width: i32,
+    pub height: i32,
+    pixels: Vec<u32>,
+}
+
+impl Commit {
+    /// ARGB8888 pixel
+    pub fn pixel(&self, x: i32, y: i32) -> u32 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+}
+
+pub struct LayerSurface {
+    resource: zwlr_layer_surface_v1::ZwlrLayerSurfaceV1,
+    surface: usize,
+    // Index of the output bar asked for
+    pub output: Option<usize>,
+    // Size sent in the last configure
+    configured: Option<(u32, u32)>,
+    pub requests: Vec<zwlr_layer_surface_v1::Request>,
+}
+
+impl LayerSurface {
+    // Size from the last set_size, zero width is filled with width of the output
+    fn size(&self, outputs: &[(usize, wl_output::WlOutput, (u32, u32))]) -> (u32, u32) {
+        let (width, height) = self
+            .requests
+            .iter()
+            .rev()
+            .find_map(|request| match request {
+                zwlr_layer_surface_v1::Request::SetSize { width, height } => {
+                    Some((*width, *height))
+                }
+                _ => None,
+            })
+            .unwrap_or((0, 0));
+        let output_width = outputs
+            .iter()
+            .find(|(index, ..)| Some(*index) == self.output)
+            .map_or(0, |(_, _, (width, _))| *width);
+        (if width == 0 { output_width } else { width }, height)
+    }
+}
+
+struct Pool {
+    file: File,
+    size: i32,
+}
+
+struct BufferData {
+    pool: usize,
+    offset: i32,
+    width: i32,
+    height: i32,
+    stride: i32,
+}
+
+// Data of wl_output global
+struct OutputInfo {
+    index: usize,
+    name: String,
+    width: i32,
+    height: i32,
+}
+
+struct ClientState;
+
+impl ClientData for ClientState {}
+
+impl Server {
+    fn commit(&mut self, index: usize) {
+        let surface = &mut self.surfaces[index];
+        // Layer surface is configured after it's first commit and when it asks for other size
+        if let Some(layer) = surface.layer {
+            let layer_surface = &mut self.layer_surfaces[layer];
+            let size = layer_surface.size(&self.outputs);
+            if layer_surface.configured != Some(size) {
+                let initial = layer_surface.configured.is_none();
+                layer_surface.configured = Some(size);
+                self.serial += 1;
+                layer_surface
+                    .resource
+                    .configure(self.serial, size.0, size.1);
+                // Initial commit has no buffer
+                if initial {
+                    return;
+                }
+            }
+        }
+        let Some(buffer) = &surface.buffer else {
+            return;
+        };
+        let data = buffer.data::<BufferData>().unwrap();
+        let pool = &self.pools[data.pool];
+        let map = unsafe {
+            memmap2::MmapOptions::new()
+                .len(pool.size as usize)
+                .map(&pool.file)
+                .unwrap()
+        };
+        let mut pixels = Vec::with_capacity((data.width * data.height) as usize);
+        for y in 0..data.height {
+            let row = (data.offset + y * data.stride) as usize;
+            for x in 0..data.width as usize {
+                let bytes = &map[row + x * 4..row + x * 4 + 4];
+                pixels.push(u32::from_le_bytes(bytes.try_into().unwrap()));
+            }
+        }
+        surface.commits.push(Commit {
+            width: data.width,
+            height: data.height,
+            pixels,
+        });
+    }
+}
+
+/// Bar connected to the mock compositor. Scripting methods send events
+/// and pass messages until both sides are done
+pub struct MockRiver {
+    display: Display<Server>,
+    pub server: Server,
+    pub bar: Bar,
+    event_queue: EventQueue<Bar>,
+    connection: Connection,
+    // Names and sizes of outputs, they are announced again after restart
+    outputs: Vec<(String, i32, i32)>,
+    outputs_first: bool,
+    output_globals: Vec<GlobalId>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl MockRiver {
+    /// Starts bar on compositor with outputs of given names and sizes
+    pub fn new(outputs: &[(&str, i32, i32)]) -> Self {
+        Self::with_visibility(outputs, Visibility::default())
+    }
+
+    pub fn with_visibility(outputs: &[(&str, i32, i32)], visibility: Visibility) -> Self {
+        Self::start(outputs, visibility, false, Vec::new())
+    }
+
+    /// Announces outputs before globals the bar needs for them
+    pub fn outputs_first(outputs: &[(&str, i32, i32)]) -> Self {
+        Self::start(outputs, Visibility::default(), true, Vec::new())
+    }
+
+    pub fn with_output_configs(
+        outputs: &[(&str, i32, i32)],
+        output_configs: Vec<OutputConfig>,
+    ) -> Self {
+        Self::start(outputs, Visibility::default(), false, output_configs)
+    }
+
+    fn start(
+        outputs: &[(&str, i32, i32)],
+        visibility: Visibility,
+        outputs_first: bool,
+        output_configs: Vec<OutputConfig>,
+    ) -> Self {
+        let lock = BAR_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let outputs: Vec<(String, i32, i32)> = outputs
+            .iter()
+            .map(|(name, width, height)| (name.to_string(), *width, *height))
+            .collect();
+        let display = Display::<Server>::new().unwrap();
+        let (output_globals, connection, event_queue) = serve(&display, &outputs, outputs_first);
+
+        let bar = Bar::new(
+            Arc::new(Mutex::new(Vec::new())),
+            Theme::default(),
+            Decorations::default(),
+            TagLabels::default(),
+            Taskbar {
+                enabled: false,
+                ..Taskbar::default()
+            },
+            None,
+            Tooltips::default(),
+            Vec::new(),
+            Vec::new(),
+            visibility,
+            output_configs,
+            None,
+            Arc::new(Mutex::new(RiverStatus::default())),
+            Arc::new(lifecycle::Redraw::new().unwrap()),
+        )
+        .unwrap();
+        let mut mock = Self {
+            display,
+            server: Server::default(),
+            bar,
+            event_queue,
+            connection,
+            outputs,
+            outputs_first,
+            output_globals,
+            _lock: lock,
+        };
+        mock.roundtrip();
+        mock
+    }
+
+    /// Compositor goes away, the bar sees it's socket closed
+    pub fn drop_connection(&mut self) {
+        self.display = Display::new().unwrap();
+        self.server = Server::default();
+        self.output_globals.clear();
+    }
+
+    /// Compositor is back with the same globals and the bar connects to it
+    /// on a new event queue like after supervisor's retry
+    pub fn reconnect(&mut self) {
+        let (output_globals, connection, event_queue) =
+            serve(&self.display, &self.outputs, self.outputs_first);
+        self.output_globals = output_globals;
+        self.connection = connection;
+        self.event_queue = event_queue;
+        self.roundtrip();
+    }
+
+    /// Passes messages between bar and compositor until both have nothing to say
+    pub fn roundtrip(&mut self) {
+        for _ in 0..100 {
+            let events = self.event_queue.dispatch_pending(&mut self.bar).unwrap();
+            self.connection.flush().unwrap();
+            let requests = self.display.dispatch_clients(&mut self.server).unwrap();
+            self.display.flush_clients().unwrap();
+            // Guard isn't given while there are events in the queue
+            let read = match self.event_queue.prepare_read() {
+                Some(guard) => guard.read().unwrap_or(0),
+                None => 1,
+            };
+            if events == 0 && requests == 0 && read == 0 {
+                return;
+            }
+        }
+        panic!("Bar and compositor didn't stop talking");
+    }
+
+    fn output_statuses(
+        &self,
+        output: usize,
+    ) -> impl Iterator<Item = &zriver_output_status_v1::ZriverOutputStatusV1> {
+        self.server
+            .output_statuses
+            .iter()
+            .filter(move |(index, _)| *index == output)
+            .map(|(_, status)| status)
+    }
+
+    pub fn focused_tags(&mut self, output: usize, tags: u32) {
+        for status in self.output_statuses(output) {
+            status.focused_tags(tags);
+        }
+        self.roundtrip();
+    }
+
+    pub fn view_tags(&mut self, output: usize, tags: &[u32]) {
+        let bytes: Vec<u8> = tags.iter().flat_map(|tags| tags.to_ne_bytes()).collect();
+        for status in self.output_statuses(output) {
+            status.view_tags(bytes.clone());
+        }
+        self.roundtrip();
+    }
+
+    pub fn urgent_tags(&mut self, output: usize, tags: u32) {
+        for status in self.output_statuses(output) {
+            status.urgent_tags(tags);
+        }
+        self.roundtrip();
+    }
+
+    /// Title of focused view
+    pub fn title(&mut self, title: &str) {
+        for status in &self.server.seat_statuses {
+            status.focused_view(title.to_string());
+        }
+        self.roundtrip();
+    }
+
+    /// Devices of the seat changed, e.g. mouse was plugged in
+    pub fn capabilities(&mut self, capabilities: wl_seat::Capability) {
+        for seat in &self.server.seats {
+            seat.capabilities(capabilities);
+        }
+        self.roundtrip();
+    }
+
+    /// River mode of the seat, e.g. "normal"
+    pub fn mode(&mut self, name: &str) {
+        for status in &self.server.seat_statuses {
+            status.mode(name.to_string());
+        }
+        self.roundtrip();
+    }
+
+    /// Draws blocks on the bars like blocks thread does
+    pub fn display_blocks(&mut self, blocks: &mut [Block], redraw_all: bool) {
+        blocks::display_blocks(
+            blocks,
+            &mut self.bar.outputs_contexts,
+            &self.bar.theme,
+            &self.bar.decorations,
+            &self.bar.river_status,
+            redraw_all,
+        );
+        self.roundtrip();
+    }
+
+    /// Plugs in output while the bar runs
+    pub fn add_output(&mut self, name: &str, width: i32, height: i32) {
+        let info = OutputInfo {
+            index: self.outputs.len(),
+            name: name.to_string(),
+            width,
+            height,
+        };
+        let global = self
+            .display
+            .handle()
+            .create_global::<Server, wl_output::WlOutput, _>(4, info);
+        self.output_globals.push(global);
+        self.outputs.push((name.to_string(), width, height));
+        self.roundtrip();
+    }
+
+    /// Unplugs output, it's layer surfaces are closed like river does
+    pub fn remove_output(&mut self, output: usize) {
+        for layer_surface in &self.server.layer_surfaces {
+            if layer_surface.output == Some(output) {
+                layer_surface.resource.closed();
+            }
+        }
+        let global = self.output_globals[output].clone();
+        self.display.handle().remove_global::<Server>(global);
+        self.roundtrip();
+    }
+
+    /// Turns output by 90 degrees, bars on it are configured to the new width
+    pub fn rotate(&mut self, output: usize) {
+        let server = &mut self.server;
+        for (index, resource, size) in server.outputs.iter_mut() {
+            if *index != output {
+                continue;
+            }
+            *size = (size.1, size.0);
+            resource.geometry(
+                0,
+                0,
+                0,
+                0,
+                wl_output::Subpixel::Unknown,
+                String::from("mock"),
+                String::from("mock"),
+                wl_output::Transform::_90,
+            );
+            resource.done();
+        }
+        for layer_surface in server.layer_surfaces.iter_mut() {
+            if layer_surface.output != Some(output) {
+                continue;
+            }
+            let size = layer_surface.size(&server.outputs);
+            layer_surface.configured = Some(size);
+            server.serial += 1;
+            layer_surface
+                .resource
+                .configure(server.serial, size.0, size.1);
+        }
+        self.roundtrip();
+    }
+
+    /// Tags the bar on the output drew last time
+    pub fn tag_spans(&self, output: usize) -> Vec<(i32, i32, u32)> {
+        self.bar.outputs_contexts.lock().unwrap()[output]
+            .tag_spans
+            .clone()
+    }
+
+    /// Last buffer committed on the layer surface of the output
+    pub fn last_commit(&self, output: usize) -> &Commit {
+        let surface = self.server.layer_surfaces[output].surface;
+        self.server.surfaces[surface].commits.last().unwrap()
+    }
+
+    pub fn commit_count(&self, output: usize) -> usize {
+        let surface = self.server.layer_surfaces[output].surface;
+        self.server.surfaces[surface].commits.len()
+    }
+}
+
+// Creates globals and a client connected to them that has asked for the registry
+fn serve(
+    display: &Display<Server>,
+    outputs: &[(String, i32, i32)],
+    outputs_first: bool,
+) -> (Vec<GlobalId>, Connection, EventQueue<Bar>) {
+    let mut handle = display.handle();
+    let create_outputs = |handle: &mut DisplayHandle| -> Vec<GlobalId> {
+        outputs
+            .iter()
+            .enumerate()
+            .map(|(index, (name, width, height))| {
+                let info = OutputInfo {
+                    index,
+                    name: name.clone(),
+                    width: *width,
+                    height: *height,
+                };
+                handle.create_global::<Server, wl_output::WlOutput, _>(4, info)
+            })
+            .collect()
+    };
+    let mut output_globals = Vec::new();
+    if outputs_first {
+        output_globals = create_outputs(&mut handle);
+    }
+    handle.create_global::<Server, wl_shm::WlShm, ()>(1, ());
+    handle.create_global::<Server, wl_compositor::WlCompositor, ()>(4, ());
+    handle.create_global::<Server, zwlr_layer_shell_v1::ZwlrLayerShellV1, ()>(4, ());
+    handle.create_global::<Server, zriver_status_manager_v1::ZriverStatusManagerV1, ()>(4, ());
+    handle.create_global::<Server, wl_seat::WlSeat, ()>(5, ());
+    if !outputs_first {
+        output_globals = create_outputs(&mut handle);
+    }
+
+    let (client_stream, server_stream) = UnixStream::pair().unwrap();
+    handle
+        .insert_client(server_stream, Arc::new(ClientState))
+        .unwrap();
+    let connection = Connection::from_socket(client_stream).unwrap();
+    let event_queue = connection.new_event_queue();
+    connection.display().get_registry(&event_queue.handle(), ());
+    (output_globals, connection, event_queue)
+}
+
+impl GlobalDispatch<wl_compositor::WlCompositor, ()> for Server {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<wl_compositor::WlCompositor>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<wl_compositor::WlCompositor, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &wl_compositor::WlCompositor,
+        request: wl_compositor::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_compositor::Request::CreateSurface { id } = request {
+            data_init.init(id, state.surfaces.len());
+            state.surfaces.push(Surface {
+                buffer: None,
+                layer: None,
+                commits: Vec::new(),
+            });
+        }
+    }
+}
+
+impl Dispatch<wl_surface::WlSurface, usize> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &wl_surface::WlSurface,
+        request: wl_surface::Request,
+        index: &usize,
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            wl_surface::Request::Attach { buffer, .. } => state.surfaces[*index].buffer = buffer,
+            wl_surface::Request::Commit => state.commit(*index),
+            _ => (),
+        }
+    }
+}
+
+impl GlobalDispatch<wl_shm::WlShm, ()> for Server {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<wl_shm::WlShm>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let shm = data_init.init(resource, ());
+        shm.format(wl_shm::Format::Argb8888);
+        shm.format(wl_shm::Format::Xrgb8888);
+    }
+}
+
+impl Dispatch<wl_shm::WlShm, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &wl_shm::WlShm,
+        request: wl_shm::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_shm::Request::CreatePool { id, fd, size } = request {
+            data_init.init(id, state.pools.len());
+            state.pools.push(Pool {
+                file: File::from(fd),
+                size,
+            });
+        }
+    }
+}
+
+impl Dispatch<wl_shm_pool::WlShmPool, usize> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &wl_shm_pool::WlShmPool,
+        request: wl_shm_pool::Request,
+        pool: &usize,
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            wl_shm_pool::Request::CreateBuffer {
+                id,
+                offset,
+                width,
+                height,
+                stride,
+                ..
+            } => {
+                let data = BufferData {
+                    pool: *pool,
+                    offset,
+                    width,
+                    height,
+                    stride,
+                };
+                data_init.init(id, data);
+            }
+            wl_shm_pool::Request::Resize { size } => state.pools[*pool].size = size,
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<wl_buffer::WlBuffer, BufferData> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &wl_buffer::WlBuffer,
+        _: wl_buffer::Request,
+        _: &BufferData,
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl GlobalDispatch<wl_output::WlOutput, OutputInfo> for Server {
+    fn bind(
+        state: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<wl_output::WlOutput>,
+        info: &OutputInfo,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        let output = data_init.init(resource, info.index);
+        output.geometry(
+            0,
+            0,
+            0,
+            0,
+            wl_output::Subpixel::Unknown,
+            String::from("mock"),
+            String::from("mock"),
+            wl_output::Transform::Normal,
+        );
+        output.mode(wl_output::Mode::Current, info.width, info.height, 60000);
+        if output.version() >= 2 {
+            output.scale(1);
+        }
+        if output.version() >= 4 {
+            output.name(info.name.clone());
+        }
+        if output.version() >= 2 {
+            output.done();
+        }
+        let size = (info.width as u32, info.height as u32);
+        state.outputs.push((info.index, output, size));
+    }
+}
+
+impl Dispatch<wl_output::WlOutput, usize> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &wl_output::WlOutput,
+        _: wl_output::Request,
+        _: &usize,
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl GlobalDispatch<wl_seat::WlSeat, ()> for Server {
+    fn bind(
+        state: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<wl_seat::WlSeat>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        // No pointer or keyboard until a test plugs them in, bar is only drawn
+        let seat = data_init.init(resource, ());
+        seat.capabilities(wl_seat::Capability::empty());
+        if seat.version() >= 2 {
+            seat.name(String::from("seat0"));
+        }
+        state.seats.push(seat);
+    }
+}
+
+impl Dispatch<wl_seat::WlSeat, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &wl_seat::WlSeat,
+        request: wl_seat::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        match request {
+            wl_seat::Request::GetPointer { id } => {
+                data_init.init(id, ());
+                state.pointers += 1;
+            }
+            wl_seat::Request::GetKeyboard { id } => {
+                data_init.init(id, ());
+                state.keyboards += 1;
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<wl_pointer::WlPointer, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &wl_pointer::WlPointer,
+        request: wl_pointer::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_pointer::Request::Release = request {
+            state.pointers -= 1;
+        }
+    }
+}
+
+impl Dispatch<wl_keyboard::WlKeyboard, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &wl_keyboard::WlKeyboard,
+        request: wl_keyboard::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+        if let wl_keyboard::Request::Release = request {
+            state.keyboards -= 1;
+        }
+    }
+}
+
+impl GlobalDispatch<zwlr_layer_shell_v1::ZwlrLayerShellV1, ()> for Server {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<zwlr_layer_shell_v1::ZwlrLayerShellV1>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<zwlr_layer_shell_v1::ZwlrLayerShellV1, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &zwlr_layer_shell_v1::ZwlrLayerShellV1,
+        request: zwlr_layer_shell_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        if let zwlr_layer_shell_v1::Request::GetLayerSurface {
+            id,
+            surface,
+            output,
+            ..
+        } = request
+        {
+            let index = state.layer_surfaces.len();
+            let surface = *surface.data::<usize>().unwrap();
+            state.surfaces[surface].layer = Some(index);
+            state.layer_surfaces.push(LayerSurface {
+                resource: data_init.init(id, index),
+                surface,
+                output: output.map(|output| *output.data::<usize>().unwrap()),
+                configured: None,
+                requests: Vec::new(),
+            });
+        }
+    }
+}
+
+impl Dispatch<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1, usize> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &zwlr_layer_surface_v1::ZwlrLayerSurfaceV1,
+        request: zwlr_layer_surface_v1::Request,
+        index: &usize,
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+        state.layer_surfaces[*index].requests.push(request);
+    }
+}
+
+impl GlobalDispatch<zriver_status_manager_v1::ZriverStatusManagerV1, ()> for Server {
+    fn bind(
+        _: &mut Self,
+        _: &DisplayHandle,
+        _: &Client,
+        resource: New<zriver_status_manager_v1::ZriverStatusManagerV1>,
+        _: &(),
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        data_init.init(resource, ());
+    }
+}
+
+impl Dispatch<zriver_status_manager_v1::ZriverStatusManagerV1, ()> for Server {
+    fn request(
+        state: &mut Self,
+        _: &Client,
+        _: &zriver_status_manager_v1::ZriverStatusManagerV1,
+        request: zriver_status_manager_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        data_init: &mut DataInit<'_, Self>,
+    ) {
+        use zriver_status_manager_v1::Request;
+        match request {
+            Request::GetRiverOutputStatus { id, output } => {
+                let index = *output.data::<usize>().unwrap();
+                state.output_statuses.push((index, data_init.init(id, ())));
+            }
+            Request::GetRiverSeatStatus { id, .. } => {
+                state.seat_statuses.push(data_init.init(id, ()));
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Dispatch<zriver_output_status_v1::ZriverOutputStatusV1, ()> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &zriver_output_status_v1::ZriverOutputStatusV1,
+        _: zriver_output_status_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+impl Dispatch<zriver_seat_status_v1::ZriverSeatStatusV1, ()> for Server {
+    fn request(
+        _: &mut Self,
+        _: &Client,
+        _: &zriver_seat_status_v1::ZriverSeatStatusV1,
+        _: zriver_seat_status_v1::Request,
+        _: &(),
+        _: &DisplayHandle,
+        _: &mut DataInit<'_, Self>,
+    ) {
+    }
+}
+
+// Opaque color as ARGB8888 pixel
+fn argb(color: Color) -> u32 {
+    let channel = |value: f64| (value * 255.).round() as u32;
+    (channel(color.a) << 24) | (channel(color.r) << 16) | (channel(color.g) << 8) | channel(color.b)
+}
+
+#[test]
+fn test_layer_surface_is_configured_and_drawn() {
+    use zwlr_layer_surface_v1::Request;
+    let mock = MockRiver::new(&[("DP-1", 1920, 1080)]);
+    let requests = &mock.server.layer_surfaces[0].requests;
+    assert!(requests.iter().any(|request| matches!(
+        request,
+        Request::SetSize {
+            width: 0,
+            height: 16
+        }
+    )));
+    assert!(requests
+        .iter()
+        .any(|request| matches!(request, Request::SetExclusiveZone { zone: 16 })));
+    assert!(requests
+        .iter()
+        .any(|request| matches!(request, Request::AckConfigure { serial: 1 })));
+
+    let commit = mock.last_commit(0);
+    assert_eq!((commit.width, commit.height), (1920, 16));
+    assert_eq!(mock.bar.outputs_contexts.lock().unwrap()[0].name, "DP-1");
+}
+
+#[test]
+fn test_tags_follow_river() {
+    let mut mock = MockRiver::new(&[("DP-1", 1920, 1080)]);
+    mock.view_tags(0, &[0b1, 0b100, 0b1]);
+    mock.focused_tags(0, 0b10);
+    mock.urgent_tags(0, 0b100);
+
+    let tag_spans = mock.tag_spans(0);
+    let tags: Vec<u32> = tag_spans.iter().map(|span| span.2).collect();
+    assert_eq!(tags, [0b1, 0b10, 0b100]);
+    let theme = Theme::default();
+    let commit = mock.last_commit(0);
+    // Left edge of the tag is in padding, so it has background of the tag
+    let color_of = |tag| {
+        let span = tag_spans.iter().find(|span| span.2 == tag).unwrap();
+        commit.pixel(span.0 + 1, 0)
+    };
+    assert_eq!(color_of(0b1), argb(theme.occupied));
+    assert_eq!(color_of(0b10), argb(theme.focused));
+    assert_eq!(color_of(0b100), argb(theme.urgent));
+}
+
+#[test]
+fn test_title_is_shared_and_drawn() {
+    let mut mock = MockRiver::new(&[("DP-1", 1920, 1080)]);
+    let commits = mock.commit_count(0);
+    mock.title("Terminal");
+    assert_eq!(mock.bar.title, "Terminal");
+    assert_eq!(mock.bar.river_status.lock().unwrap().title, "Terminal");
+    assert!(mock.commit_count(0) > commits);
+}
+
+#[test]
+fn test_mode_reveals_auto_hidden_bar() {
+    let visibility = Visibility {
+        auto_hide: true,
+        reveal_modes: vec![String::from("passthrough")],
+    };
+    let mut mock = MockRiver::with_visibility(&[("DP-1", 1920, 1080)], visibility);
+    assert_eq!(mock.last_commit(0).height, 1);
+    mock.mode("passthrough");
+    assert_eq!(mock.last_commit(0).height, 16);
+    mock.mode("normal");
+    assert_eq!(mock.last_commit(0).height, 1);
+}
+
+#[test]
+fn test_mode_keeps_bar_hidden_on_request() {
+    use crate::visibility::{apply, Action};
+    let visibility = Visibility {
+        auto_hide: false,
+        reveal_modes: vec![String::from("passthrough")],
+    };
+    let mut mock = MockRiver::with_visibility(&[("DP-1", 1920, 1080)], visibility);
+    apply(
+        &mut mock.bar.outputs_contexts.lock().unwrap(),
+        None,
+        Action::Hide,
+    )
+    .unwrap();
+    mock.mode("passthrough");
+    assert!(!mock.bar.outputs_contexts.lock().unwrap()[0].visible);
+    mock.mode("normal");
+    assert!(!mock.bar.outputs_contexts.lock().unwrap()[0].visible);
+}
+
+#[test]
+fn test_mode_hides_only_bars_it_revealed() {
+    use crate::visibility::{apply, Action};
+    let visibility = Visibility {
+        auto_hide: true,
+        reveal_modes: vec![String::from("passthrough")],
+    };
+    let mut mock =
+        MockRiver::with_visibility(&[("DP-1", 1920, 1080), ("HDMI-A-1", 1280, 720)], visibility);
+    mock.mode("passthrough");
+    // Shown on request while the mode is active, so it stays
+    apply(
+        &mut mock.bar.outputs_contexts.lock().unwrap(),
+        Some("HDMI-A-1"),
+        Action::Show,
+    )
+    .unwrap();
+    mock.mode("normal");
+    let outputs_contexts = mock.bar.outputs_contexts.lock().unwrap();
+    assert!(!outputs_contexts[0].visible);
+    assert!(outputs_contexts[1].visible);
+}
+
+#[test]
+fn test_apply_selects_bars() {
+    use crate::visibility::{apply, Action};
+    let mock = MockRiver::new(&[("DP-1", 1920, 1080), ("HDMI-A-1", 1280, 720)]);
+    let mut outputs_contexts = mock.bar.outputs_contexts.lock().unwrap();
+    let visible = |contexts: &[crate::OutputContext]| -> Vec<bool> {
+        contexts.iter().map(|context| context.visible).collect()
+    };
+    apply(&mut outputs_contexts, Some("HDMI-A-1"), Action::Hide).unwrap();
+    assert_eq!(visible(&outputs_contexts), [true, false]);
+    // Index works as well as name
+    apply(&mut outputs_contexts, Some("1"), Action::Show).unwrap();
+    assert_eq!(visible(&outputs_contexts), [true, true]);
+    assert!(apply(&mut outputs_contexts, Some("DP-9"), Action::Hide).is_err());
+    // Toggle of all bars leaves them in the same state
+    apply(&mut outputs_contexts, Some("DP-1"), Action::Hide).unwrap();
+    apply(&mut outputs_contexts, None, Action::Toggle).unwrap();
+    assert_eq!(visible(&outputs_contexts), [false, false]);
+    apply(&mut outputs_contexts, None, Action::Toggle).unwrap();
+    assert_eq!(visible(&outputs_contexts), [true, true]);
+}
+
+#[test]
+fn test_removed_output_closes_bar() {
+    let mut mock = MockRiver::new(&[("DP-1", 1920, 1080)]);
+    mock.remove_output(0);
+    assert!(mock.bar.outputs_contexts.lock().unwrap().is_empty());
+    assert!(mock.server.layer_surfaces[0]
+        .requests
+        .iter()
+        .any(|request| matches!(request, zwlr_layer_surface_v1::Request::Destroy)));
+    // Bar waits for the output to come back
+    assert!(mock.bar.running);
+    mock.add_output("DP-1", 1920, 1080);
+    assert_eq!(mock.server.layer_surfaces[1].output, Some(1));
+    assert_eq!(mock.last_commit(1).width, 1920);
+}
+
+#[test]
+fn test_second_output_gets_own_buffers() {
+    let mock = MockRiver::new(&[("DP-1", 1920, 1080), ("HDMI-A-1", 1280, 720)]);
+    let names: Vec<String> = mock
+        .bar
+        .outputs_contexts
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|context| context.name.clone())
+        .collect();
+    assert_eq!(names, ["DP-1", "HDMI-A-1"]);
+    assert_eq!(mock.last_commit(0).width, 1920);
+    let commit = mock.last_commit(1);
+    assert_eq!((commit.width, commit.height), (1280, 10));
+}
+
+#[test]
+fn test_late_output_gets_bar() {
+    let mut mock = MockRiver::new(&[("DP-1", 1920, 1080)]);
+    mock.add_output("HDMI-A-1", 1280, 720);
+    assert_eq!(mock.server.layer_surfaces[1].output, Some(1));
+    assert_eq!(mock.server.layer_surfaces[1].configured, Some((1280, 10)));
+    let commit = mock.last_commit(1);
+    assert_eq!((commit.width, commit.height), (1280, 10));
+    assert_eq!(
+        mock.bar.outputs_contexts.lock().unwrap()[1].name,
+        "HDMI-A-1"
+    );
+    // It follows river like the first one
+    mock.focused_tags(1, 0b100);
+    assert!(mock.tag_spans(1).iter().any(|span| span.2 == 0b100));
+}
+
+#[test]
+fn test_outputs_announced_before_globals() {
+    let mut mock = MockRiver::outputs_first(&[("DP-1", 1920, 1080), ("HDMI-A-1", 1280, 720)]);
+    let outputs: Vec<Option<usize>> = mock
+        .server
+        .layer_surfaces
+        .iter()
+        .map(|layer_surface| layer_surface.output)
+        .collect();
+    assert_eq!(outputs, [Some(0), Some(1)]);
+    assert_eq!(mock.last_commit(1).width, 1280);
+    // River statuses were requested once the manager was bound
+    mock.focused_tags(1, 0b100);
+    assert_eq!(mock.bar.focused_tag, 0b100);
+}
+
+#[test]
+fn test_rotated_output_resizes_bar() {
+    let mut mock = MockRiver::new(&[("DP-1", 1920, 1080)]);
+    mock.rotate(0);
+    // Bar is as wide as the output is now and as high as it's new height asks for
+    let commit = mock.last_commit(0);
+    assert_eq!((commit.width, commit.height), (1080, 28));
+    let outputs_contexts = mock.bar.outputs_contexts.lock().unwrap();
+    assert_eq!(
+        (outputs_contexts[0].width, outputs_contexts[0].height),
+        (1080, 28)
+    );
+}
+
+#[test]
+fn test_output_configs() {
+    let output_configs = vec![
+        OutputConfig::disabled("HDMI-A-1"),
+        OutputConfig::with_bars(
+            "DP-2",
+            vec![BarConfig {
+                height: Some(24),
+                position: Position::Bottom,
+                tags: false,
+                ..Default::default()
+            }],
+        ),
+    ];
+    let mut mock = MockRiver::with_output_configs(
+        &[
+            ("DP-1", 1920, 1080),
+            ("HDMI-A-1", 1920, 1080),
+            ("DP-2", 2560, 1440),
+        ],
+        output_configs,
+    );
+    // No bar on HDMI-A-1
+    let outputs: Vec<Option<usize>> = mock
+        .server
+        .layer_surfaces
+        .iter()
+        .map(|layer_surface| layer_surface.output)
+        .collect();
+    assert_eq!(outputs, [Some(0), Some(2)]);
+
+    use zwlr_layer_surface_v1::{Anchor, Request};
+    let requests = &mock.server.layer_surfaces[1].requests;
+    assert!(requests.iter().any(|request| matches!(
+        request,
+        Request::SetAnchor { anchor: WEnum::Value(anchor) }
+            if *anchor == Anchor::Bottom | Anchor::Left | Anchor::Right
+    )));
+    let commit = mock.last_commit(1);
+    assert_eq!((commit.width, commit.height), (2560, 24));
+
+    mock.focused_tags(0, 0b10);
+    assert!(!mock.tag_spans(0).is_empty());
+    assert!(mock.tag_spans(1).is_empty());
+}
+
+#[test]
+fn test_top_and_bottom_bars() {
+    let output_configs = vec![OutputConfig::with_bars(
+        "DP-1",
+        vec![
+            BarConfig {
+                blocks: false,
+                tray: false,
+                ..Default::default()
+            },
+            BarConfig {
+                position: Position::Bottom,
+                tags: false,
+                title: false,
+                widgets: false,
+                ..Default::default()
+            },
+        ],
+    )];
+    let mut mock = MockRiver::with_output_configs(&[("DP-1", 1920, 1080)], output_configs);
+    // Both bars are on the same output and get their own buffers
+    let outputs: Vec<Option<usize>> = mock
+        .server
+        .layer_surfaces
+        .iter()
+        .map(|layer_surface| layer_surface.output)
+        .collect();
+    assert_eq!(outputs, [Some(0), Some(0)]);
+    assert_eq!(mock.last_commit(0).width, 1920);
+    assert_eq!(mock.last_commit(1).width, 1920);
+
+    mock.focused_tags(0, 0b10);
+    assert!(!mock.tag_spans(0).is_empty());
+    assert!(mock.tag_spans(1).is_empty());
+
+    // Output is released with it's last bar
+    mock.remove_output(0);
+    assert!(mock.bar.outputs.is_empty());
+    assert!(mock.bar.running);
+}
+
+#[test]
+fn test_bar_rebuilds_after_compositor_restart() {
+    let mut mock = MockRiver::new(&[("DP-1", 1920, 1080)]);
+    mock.drop_connection();
+    let shutdown = lifecycle::Shutdown::new().unwrap();
+    assert!(crate::supervisor::run(&mut mock.event_queue, &mut mock.bar, &shutdown).is_err());
+    mock.bar.forget();
+    assert!(mock.bar.outputs_contexts.lock().unwrap().is_empty());
+
+    mock.reconnect();
+    assert!(mock.bar.compositor.is_some());
+    assert!(mock.bar.layer_shell.is_some());
+    assert!(mock.bar.river_seat_status.is_some());
+    assert_eq!(mock.bar.outputs.len(), 1);
+    assert_eq!(mock.server.layer_surfaces.len(), 1);
+    assert_eq!(mock.server.output_statuses.len(), 1);
+    assert_eq!(mock.server.seat_statuses.len(), 1);
+    // New surface is configured and drawn, river status reaches it
+    assert_eq!(mock.last_commit(0).width, 1920);
+    mock.focused_tags(0, 0b100);
+    assert!(!mock.tag_spans(0).is_empty());
+    assert!(mock.bar.running);
+}
+
+#[test]
+fn test_blocks_survive_tags_and_title_draws() {
+    use crate::blocks::Source;
+    use std::process::Command;
+    let text_block = |name: &str, text: &str| {
+        let mut block = Block::new(name, Source::Command(Command::new("true")));
+        block.set_text(text);
+        block
+    };
+    let mut blocks = vec![text_block("date", "12:00"), text_block("layout", "us")];
+    let mut mock = MockRiver::new(&[("DP-1", 1920, 1080)]);
+    mock.display_blocks(&mut blocks, true);
+    blocks[0].set_text("12:01");
+    mock.display_blocks(&mut blocks, false);
+    // Committed on the canvas that still had blocks drawn before the last update
+    mock.title("Terminal");
+    blocks[1].set_text("de");
+    mock.display_blocks(&mut blocks, false);
+    let commits = mock.commit_count(0);
+    let pixels = mock.last_commit(0).pixels.clone();
+
+    mock.display_blocks(&mut blocks, true);
+    assert_eq!(mock.commit_count(0), commits + 1);
+    assert!(mock.last_commit(0).pixels == pixels);
+}
+
+#[test]
+fn test_seat_devices_follow_capabilities() {
+    use wl_seat::Capability;
+    let mut mock = MockRiver::new(&[("DP-1", 1920, 1080)]);
+    mock.capabilities(Capability::Pointer | Capability::Keyboard);
+    // Hotplug resends capabilities, devices that stayed aren't got again
+    mock.capabilities(Capability::Pointer | Capability::Keyboard);
+    assert_eq!((mock.server.pointers, mock.server.keyboards), (1, 1));
+    mock.capabilities(Capability::Keyboard);
+    assert_eq!((mock.server.pointers, mock.server.keyboards), (0, 1));
+    mock.capabilities(Capability::Pointer | Capability::Keyboard);
+    assert_eq!((mock.server.pointers, mock.server.keyboards), (1, 1));
+}
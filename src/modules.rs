@@ -0,0 +1,443 @@
+// Blocks that are computed inside of the bar instead of forking a command every update
+
+use std::{ffi::CString, io, os::fd::RawFd, path::PathBuf};
+
+use tz::TimeZone;
+
+pub trait Module: Send {
+    fn update(&mut self) -> io::Result<String>;
+
+    /// Descriptor that becomes readable when module should be updated outside of it's interval
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Called when wall-clock time was set or timezone changed
+    fn time_changed(&mut self) {}
+}
+
+/// Replaces {key} placeholders in template
+pub fn format(template: &str, values: &[(&str, String)]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) => {
+                let key = &after[..end];
+                match values.iter().find(|(name, _)| *name == key) {
+                    Some((_, value)) => result.push_str(value),
+                    None => {
+                        result.push('{');
+                        result.push_str(key);
+                        result.push('}');
+                    }
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                result.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn read_trimmed(path: impl Into<PathBuf>) -> io::Result<String> {
+    Ok(std::fs::read_to_string(path.into())?.trim().to_string())
+}
+
+fn invalid_data(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("can't parse {what}"))
+}
+
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes}{}", UNITS[0])
+    } else {
+        format!("{value:.1}{}", UNITS[unit])
+    }
+}
+
+/// Zone of /etc/localtime or TZ variable. It's loaded by the bar itself, because
+/// localtime_r doesn't notice changed /etc/localtime
+pub fn local_timezone() -> TimeZone {
+    TimeZone::local().unwrap_or_else(|err| {
+        log::warn!("Can't load local timezone: {err}, using UTC");
+        TimeZone::utc()
+    })
+}
+
+/// Offset from UTC in seconds and abbreviation of the zone at given time
+pub fn offset_at(timezone: &TimeZone, time: i64) -> io::Result<(i32, &str)> {
+    let local_time_type = timezone
+        .find_local_time_type(time)
+        .map_err(|err| io::Error::other(err.to_string()))?;
+    Ok((
+        local_time_type.ut_offset(),
+        local_time_type.time_zone_designation(),
+    ))
+}
+
+pub struct Clock {
+    // strftime(3) format
+    format: String,
+    timezone: TimeZone,
+    // Zone from config isn't replaced when local one changes
+    fixed: bool,
+}
+
+impl Clock {
+    pub fn new(format: &str) -> Self {
+        Self {
+            format: format.to_string(),
+            timezone: local_timezone(),
+            fixed: false,
+        }
+    }
+
+    /// Shows time in IANA zone like "Europe/Moscow" or POSIX TZ string instead of local time
+    pub fn with_timezone(mut self, timezone: &str) -> Self {
+        match TimeZone::from_posix_tz(timezone) {
+            Ok(zone) => {
+                self.timezone = zone;
+                self.fixed = true;
+            }
+            Err(err) => {
+                log::warn!("Unknown timezone {timezone}: {err}, falling back to local time")
+            }
+        }
+        self
+    }
+}
+
+impl Module for Clock {
+    fn update(&mut self) -> io::Result<String> {
+        let format = CString::new(self.format.as_str()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "clock format has NUL byte")
+        })?;
+        let now = unsafe { libc::time(std::ptr::null_mut()) };
+        let (offset, zone) = offset_at(&self.timezone, now as i64)?;
+        // Must outlive strftime call because tm points to it
+        let abbreviation = CString::new(zone).unwrap_or_default();
+        let local = now + offset as libc::time_t;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        unsafe { libc::gmtime_r(&local, &mut tm) };
+        tm.tm_gmtoff = offset as libc::c_long;
+        tm.tm_zone = abbreviation.as_ptr();
+        let mut buffer = [0u8; 256];
+        let length = unsafe {
+            libc::strftime(
+                buffer.as_mut_ptr() as *mut libc::c_char,
+                buffer.len(),
+                format.as_ptr(),
+                &tm,
+            )
+        };
+        Ok(String::from_utf8_lossy(&buffer[..length]).into_owned())
+    }
+
+    fn time_changed(&mut self) {
+        if !self.fixed {
+            self.timezone = local_timezone();
+        }
+    }
+}
+
+pub struct Battery {
+    path: PathBuf,
+    // Placeholders: {capacity}, {status}
+    format: String,
+}
+
+impl Battery {
+    /// battery is the name in /sys/class/power_supply, e.g. "BAT0"
+    pub fn new(battery: &str, format: &str) -> Self {
+        Self {
+            path: PathBuf::from("/sys/class/power_supply").join(battery),
+            format: format.to_string(),
+        }
+    }
+}
+
+impl Module for Battery {
+    fn update(&mut self) -> io::Result<String> {
+        let capacity = read_trimmed(self.path.join("capacity"))?;
+        let status = read_trimmed(self.path.join("status")).unwrap_or_default();
+        Ok(format(
+            &self.format,
+            &[("capacity", capacity), ("status", status)],
+        ))
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+struct CpuTimes {
+    total: u64,
+    idle: u64,
+}
+
+fn parse_proc_stat(stat: &str) -> Option<CpuTimes> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let values: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .map(|value| value.parse().ok())
+        .collect::<Option<_>>()?;
+    // user nice system idle iowait irq softirq steal, guest time is already included in user
+    let total = values.iter().take(8).sum();
+    let idle = values.get(3)? + values.get(4).unwrap_or(&0);
+    Some(CpuTimes { total, idle })
+}
+
+pub struct Cpu {
+    // Placeholders: {usage}
+    format: String,
+    previous: CpuTimes,
+}
+
+impl Cpu {
+    pub fn new(format: &str) -> Self {
+        Self {
+            format: format.to_string(),
+            previous: CpuTimes::default(),
+        }
+    }
+}
+
+impl Module for Cpu {
+    fn update(&mut self) -> io::Result<String> {
+        let stat = std::fs::read_to_string("/proc/stat")?;
+        let times = parse_proc_stat(&stat).ok_or_else(|| invalid_data("/proc/stat"))?;
+        let total = times.total.saturating_sub(self.previous.total);
+        let idle = times.idle.saturating_sub(self.previous.idle);
+        self.previous = times;
+        let usage = ((total - idle.min(total)) * 100)
+            .checked_div(total)
+            .unwrap_or(0);
+        Ok(format(&self.format, &[("usage", usage.to_string())]))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct MemInfo {
+    total: u64,
+    available: u64,
+}
+
+fn parse_meminfo(meminfo: &str) -> Option<MemInfo> {
+    let field = |name: &str| -> Option<u64> {
+        let line = meminfo.lines().find(|line| line.starts_with(name))?;
+        let kilobytes: u64 = line[name.len()..]
+            .trim_start_matches(':')
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()?;
+        Some(kilobytes * 1024)
+    };
+    Some(MemInfo {
+        total: field("MemTotal")?,
+        available: field("MemAvailable")?,
+    })
+}
+
+pub struct Memory {
+    // Placeholders: {used}, {total}, {available}, {percent}
+    format: String,
+}
+
+impl Memory {
+    pub fn new(format: &str) -> Self {
+        Self {
+            format: format.to_string(),
+        }
+    }
+}
+
+impl Module for Memory {
+    fn update(&mut self) -> io::Result<String> {
+        let meminfo = std::fs::read_to_string("/proc/meminfo")?;
+        let info = parse_meminfo(&meminfo).ok_or_else(|| invalid_data("/proc/meminfo"))?;
+        let used = info.total.saturating_sub(info.available);
+        let percent = used * 100 / info.total.max(1);
+        Ok(format(
+            &self.format,
+            &[
+                ("used", human_bytes(used)),
+                ("total", human_bytes(info.total)),
+                ("available", human_bytes(info.available)),
+                ("percent", percent.to_string()),
+            ],
+        ))
+    }
+}
+
+pub struct Load {
+    // Placeholders: {1m}, {5m}, {15m}
+    format: String,
+}
+
+impl Load {
+    pub fn new(format: &str) -> Self {
+        Self {
+            format: format.to_string(),
+        }
+    }
+}
+
+impl Module for Load {
+    fn update(&mut self) -> io::Result<String> {
+        let loadavg = read_trimmed("/proc/loadavg")?;
+        let mut averages = loadavg.split_whitespace().map(String::from);
+        let mut next = || averages.next().ok_or_else(|| invalid_data("/proc/loadavg"));
+        let values = [("1m", next()?), ("5m", next()?), ("15m", next()?)];
+        Ok(format(&self.format, &values))
+    }
+}
+
+pub struct Disk {
+    path: String,
+    // Placeholders: {free}, {used}, {total}, {percent}
+    format: String,
+}
+
+impl Disk {
+    /// path is any path on the filesystem, e.g. "/" or "/home"
+    pub fn new(path: &str, format: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            format: format.to_string(),
+        }
+    }
+}
+
+impl Module for Disk {
+    fn update(&mut self) -> io::Result<String> {
+        let path = CString::new(self.path.as_str())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "disk path has NUL byte"))?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let block_size = stat.f_frsize as u64;
+        let total = stat.f_blocks as u64 * block_size;
+        // Space available to unprivileged user, like df shows
+        let free = stat.f_bavail as u64 * block_size;
+        let used = total.saturating_sub(stat.f_bfree as u64 * block_size);
+        let percent = used * 100 / (used + free).max(1);
+        Ok(format(
+            &self.format,
+            &[
+                ("free", human_bytes(free)),
+                ("used", human_bytes(used)),
+                ("total", human_bytes(total)),
+                ("percent", percent.to_string()),
+            ],
+        ))
+    }
+}
+
+pub struct Temperature {
+    // hwmon name, e.g. "coretemp" or "k10temp"
+    sensor: String,
+    // Input within hwmon, e.g. "temp1"
+    input: String,
+    // Placeholders: {temp}
+    format: String,
+}
+
+impl Temperature {
+    pub fn new(sensor: &str, input: &str, format: &str) -> Self {
+        Self {
+            sensor: sensor.to_string(),
+            input: input.to_string(),
+            format: format.to_string(),
+        }
+    }
+
+    // hwmon numbering isn't stable between boots, so sensor is looked up by name
+    fn find_hwmon(&self) -> io::Result<PathBuf> {
+        for entry in std::fs::read_dir("/sys/class/hwmon")? {
+            let path = entry?.path();
+            if read_trimmed(path.join("name")).ok().as_deref() == Some(self.sensor.as_str()) {
+                return Ok(path);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no hwmon named {}", self.sensor),
+        ))
+    }
+}
+
+impl Module for Temperature {
+    fn update(&mut self) -> io::Result<String> {
+        let input = self.find_hwmon()?.join(format!("{}_input", self.input));
+        let millidegrees: i64 = read_trimmed(input)?
+            .parse()
+            .map_err(|_| invalid_data("temperature"))?;
+        Ok(format(
+            &self.format,
+            &[("temp", (millidegrees / 1000).to_string())],
+        ))
+    }
+}
+
+#[test]
+fn test_format() {
+    let values = [("a", String::from("1")), ("b", String::from("2"))];
+    assert_eq!(format("{a}% {b}", &values), "1% 2");
+    assert_eq!(format("{c} {a", &values), "{c} {a");
+}
+
+#[test]
+fn test_parse_proc_files() {
+    let stat = "cpu  10 0 10 70 10 0 0 0 0 0\ncpu0 10 0 10 70 10 0 0 0 0 0\n";
+    assert_eq!(
+        parse_proc_stat(stat),
+        Some(CpuTimes {
+            total: 100,
+            idle: 80
+        })
+    );
+    let meminfo = "MemTotal:       16000 kB\nMemFree:        1000 kB\nMemAvailable:   4000 kB\n";
+    assert_eq!(
+        parse_meminfo(meminfo),
+        Some(MemInfo {
+            total: 16000 * 1024,
+            available: 4000 * 1024
+        })
+    );
+    assert_eq!(human_bytes(1536), "1.5K");
+}
+
+#[test]
+fn test_clock_timezone() {
+    let mut clock = Clock::new("%Z %z").with_timezone("EST5");
+    assert_eq!(clock.update().unwrap(), "EST -0500");
+    // Zone from config stays after local zone changes
+    clock.time_changed();
+    assert_eq!(clock.update().unwrap(), "EST -0500");
+    let mut clock = Clock::new("%Z").with_timezone("UTC0");
+    assert_eq!(clock.update().unwrap(), "UTC");
+    // Block shows error marker instead of bar panicking
+    assert!(Clock::new("%H\0%M").update().is_err());
+}
+
+#[test]
+fn test_disk() {
+    let mut disk = Disk::new("/", "{percent}");
+    assert!(disk.update().unwrap().parse::<u64>().is_ok());
+    assert!(Disk::new("/\0home", "{free}").update().is_err());
+}
@@ -0,0 +1,473 @@
+// Network block that follows rtnetlink link and address events instead of polling
+
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    time::Instant,
+};
+
+use crate::modules::{self, Module};
+
+const NLMSG_HEADER_LEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_DUMP: u16 = 0x300;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_GETLINK: u16 = 18;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_GETADDR: u16 = 22;
+
+const RTMGRP_LINK: u32 = 0x1;
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+
+const IFLA_IFNAME: u16 = 3;
+const IFLA_OPERSTATE: u16 = 16;
+const IF_OPER_UP: u8 = 6;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+const IFINFOMSG_LEN: usize = 16;
+const IFADDRMSG_LEN: usize = 8;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(data.get(at..at + 2)?.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(data.get(at..at + 4)?.try_into().unwrap()))
+}
+
+/// Iterates over (type, payload) of netlink messages in buffer
+fn messages(mut buffer: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let current = buffer;
+        let len = read_u32(current, 0)? as usize;
+        let message_type = read_u16(current, 4)?;
+        if len < NLMSG_HEADER_LEN || len > current.len() {
+            return None;
+        }
+        buffer = current.get(align(len)..).unwrap_or_default();
+        Some((message_type, &current[NLMSG_HEADER_LEN..len]))
+    })
+}
+
+/// Iterates over (type, value) of route attributes
+fn attributes(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let current = data;
+        let len = read_u16(current, 0)? as usize;
+        let attribute_type = read_u16(current, 2)?;
+        if len < 4 || len > current.len() {
+            return None;
+        }
+        data = current.get(align(len)..).unwrap_or_default();
+        Some((attribute_type, &current[4..len]))
+    })
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub struct LinkState {
+    index: Option<u32>,
+    up: bool,
+    ipv4: Vec<Ipv4Addr>,
+    ipv6: Vec<Ipv6Addr>,
+}
+
+impl LinkState {
+    /// Applies netlink messages concerning interface. Returns true when dump is finished
+    pub fn apply(&mut self, interface: &str, buffer: &[u8]) -> bool {
+        let mut done = false;
+        for (message_type, payload) in messages(buffer) {
+            match message_type {
+                RTM_NEWLINK | RTM_DELLINK => self.apply_link(interface, message_type, payload),
+                RTM_NEWADDR | RTM_DELADDR => self.apply_address(message_type, payload),
+                NLMSG_DONE | NLMSG_ERROR => done = true,
+                _ => (),
+            }
+        }
+        done
+    }
+
+    fn apply_link(&mut self, interface: &str, message_type: u16, payload: &[u8]) {
+        let Some(index) = read_u32(payload, 4) else {
+            return;
+        };
+        let Some(attributes_data) = payload.get(IFINFOMSG_LEN..) else {
+            return;
+        };
+        let mut name_matches = false;
+        let mut up = false;
+        for (attribute_type, value) in attributes(attributes_data) {
+            match attribute_type {
+                IFLA_IFNAME => {
+                    let name = value.split(|&c| c == 0).next().unwrap_or_default();
+                    name_matches = name == interface.as_bytes();
+                }
+                IFLA_OPERSTATE => up = value.first() == Some(&IF_OPER_UP),
+                _ => (),
+            }
+        }
+        if !name_matches {
+            return;
+        }
+        if message_type == RTM_DELLINK {
+            *self = Self::default();
+        } else {
+            self.index = Some(index);
+            self.up = up;
+        }
+    }
+
+    fn apply_address(&mut self, message_type: u16, payload: &[u8]) {
+        let (Some(&family), Some(index)) = (payload.first(), read_u32(payload, 4)) else {
+            return;
+        };
+        if self.index != Some(index) {
+            return;
+        }
+        let Some(attributes_data) = payload.get(IFADDRMSG_LEN..) else {
+            return;
+        };
+        let mut address = None;
+        let mut local = None;
+        for (attribute_type, value) in attributes(attributes_data) {
+            match attribute_type {
+                IFA_ADDRESS => address = Some(value),
+                IFA_LOCAL => local = Some(value),
+                _ => (),
+            }
+        }
+        // For point-to-point links IFA_ADDRESS is address of the other end
+        let Some(value) = local.or(address) else {
+            return;
+        };
+        let add = message_type == RTM_NEWADDR;
+        match (family as i32, value.len()) {
+            (libc::AF_INET, 4) => {
+                let ip = Ipv4Addr::from(<[u8; 4]>::try_from(value).unwrap());
+                update_list(&mut self.ipv4, ip, add);
+            }
+            (libc::AF_INET6, 16) => {
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(value).unwrap());
+                update_list(&mut self.ipv6, ip, add);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn update_list<T: PartialEq>(list: &mut Vec<T>, item: T, add: bool) {
+    list.retain(|existing| *existing != item);
+    if add {
+        list.push(item);
+    }
+}
+
+/// Returns received and transmitted bytes of interface
+fn parse_net_dev(net_dev: &str, interface: &str) -> Option<(u64, u64)> {
+    for line in net_dev.lines().skip(2) {
+        let (name, counters) = line.split_once(':')?;
+        if name.trim() != interface {
+            continue;
+        }
+        let counters: Vec<u64> = counters
+            .split_whitespace()
+            .map(|counter| counter.parse().ok())
+            .collect::<Option<_>>()?;
+        return Some((*counters.first()?, *counters.get(8)?));
+    }
+    None
+}
+
+pub struct Network {
+    interface: String,
+    // Placeholders: {ifname}, {state}, {ipv4}, {ipv6}, {down}, {up}
+    format: String,
+    socket: Option<OwnedFd>,
+    state: LinkState,
+    previous_counters: Option<(Instant, u64, u64)>,
+}
+
+impl Network {
+    pub fn new(interface: &str, format: &str) -> Self {
+        let mut network = Self {
+            interface: interface.to_string(),
+            format: format.to_string(),
+            socket: None,
+            state: LinkState::default(),
+            previous_counters: None,
+        };
+        if let Err(err) = network.subscribe() {
+            log::warn!("Can't subscribe to rtnetlink: {err}");
+        }
+        network
+    }
+
+    fn subscribe(&mut self) -> io::Result<()> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR;
+        let bind_result = unsafe {
+            libc::bind(
+                fd,
+                &address as *const _ as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bind_result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.dump(fd)?;
+        self.socket = Some(socket);
+        Ok(())
+    }
+
+    /// Replaces state with the current one. Dump starts over when events
+    /// overflow the socket meanwhile, because some of them are lost
+    fn dump(&mut self, fd: RawFd) -> io::Result<()> {
+        'dump: loop {
+            self.state = LinkState::default();
+            // Kernel allows only one dump at a time
+            for (sequence, request) in [RTM_GETLINK, RTM_GETADDR].into_iter().enumerate() {
+                send_dump_request(fd, request, sequence as u32 + 1)?;
+                let mut buffer = [0u8; 8192];
+                loop {
+                    let received = match receive(fd, &mut buffer, 0) {
+                        Ok(received) => received,
+                        Err(err) if err.raw_os_error() == Some(libc::ENOBUFS) => continue 'dump,
+                        Err(err) => return Err(err),
+                    };
+                    if self.state.apply(&self.interface, &buffer[..received]) {
+                        break;
+                    }
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    fn drain_events(&mut self) -> io::Result<()> {
+        let Some(fd) = self.socket.as_ref().map(|socket| socket.as_raw_fd()) else {
+            return Ok(());
+        };
+        let mut buffer = [0u8; 8192];
+        loop {
+            match receive(fd, &mut buffer, libc::MSG_DONTWAIT) {
+                Ok(received) => {
+                    self.state.apply(&self.interface, &buffer[..received]);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.raw_os_error() == Some(libc::ENOBUFS) => {
+                    log::debug!("Lost rtnetlink events of {}, dumping again", self.interface);
+                    self.dump(fd)?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Bytes per second since previous update
+    fn throughput(&mut self) -> (u64, u64) {
+        let Some((rx, tx)) = std::fs::read_to_string("/proc/net/dev")
+            .ok()
+            .and_then(|net_dev| parse_net_dev(&net_dev, &self.interface))
+        else {
+            return (0, 0);
+        };
+        let now = Instant::now();
+        let rates = match self.previous_counters {
+            Some((time, previous_rx, previous_tx)) => {
+                let seconds = now.duration_since(time).as_secs_f64().max(0.001);
+                (
+                    (rx.saturating_sub(previous_rx) as f64 / seconds) as u64,
+                    (tx.saturating_sub(previous_tx) as f64 / seconds) as u64,
+                )
+            }
+            None => (0, 0),
+        };
+        self.previous_counters = Some((now, rx, tx));
+        rates
+    }
+}
+
+fn send_dump_request(fd: RawFd, request: u16, sequence: u32) -> io::Result<()> {
+    // nlmsghdr followed by rtgenmsg (family only), padded to 4 bytes
+    let mut message = [0u8; NLMSG_HEADER_LEN + 4];
+    let length = message.len() as u32;
+    message[0..4].copy_from_slice(&length.to_ne_bytes());
+    message[4..6].copy_from_slice(&request.to_ne_bytes());
+    message[6..8].copy_from_slice(&(NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    message[8..12].copy_from_slice(&sequence.to_ne_bytes());
+    message[NLMSG_HEADER_LEN] = libc::AF_UNSPEC as u8;
+    let sent = unsafe {
+        libc::send(
+            fd,
+            message.as_ptr() as *const libc::c_void,
+            message.len(),
+            0,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn receive(fd: RawFd, buffer: &mut [u8], flags: libc::c_int) -> io::Result<usize> {
+    let received = unsafe {
+        libc::recv(
+            fd,
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+            flags,
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(received as usize)
+}
+
+impl Module for Network {
+    fn update(&mut self) -> io::Result<String> {
+        self.drain_events()?;
+        let (down, up) = self.throughput();
+        let join = |addresses: Vec<String>| addresses.join(" ");
+        Ok(modules::format(
+            &self.format,
+            &[
+                ("ifname", self.interface.clone()),
+                ("state", String::from(if self.state.up { "up" } else { "down" })),
+                ("ipv4", join(self.state.ipv4.iter().map(|ip| ip.to_string()).collect())),
+                ("ipv6", join(self.state.ipv6.iter().map(|ip| ip.to_string()).collect())),
+                ("down", format!("{}/s", modules::human_bytes(down))),
+                ("up", format!("{}/s", modules::human_bytes(up))),
+            ],
+        ))
+    }
+
+    fn fd(&self) -> Option<RawFd> {
+        self.socket.as_ref().map(|socket| socket.as_raw_fd())
+    }
+}
+
+#[cfg(test)]
+fn message(message_type: u16, header: &[u8], attributes: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut payload = header.to_vec();
+    for (attribute_type, value) in attributes {
+        payload.extend_from_slice(&((4 + value.len()) as u16).to_ne_bytes());
+        payload.extend_from_slice(&attribute_type.to_ne_bytes());
+        payload.extend_from_slice(value);
+        payload.resize(align(payload.len()), 0);
+    }
+    let mut message = Vec::new();
+    message.extend_from_slice(&((NLMSG_HEADER_LEN + payload.len()) as u32).to_ne_bytes());
+    message.extend_from_slice(&message_type.to_ne_bytes());
+    message.extend_from_slice(&[0; 10]);
+    message.extend_from_slice(&payload);
+    message
+}
+
+#[test]
+fn test_link_state_from_netlink_messages() {
+    let ifinfomsg = |index: u32| {
+        let mut header = vec![0u8; IFINFOMSG_LEN];
+        header[4..8].copy_from_slice(&index.to_ne_bytes());
+        header
+    };
+    let ifaddrmsg = |family: i32, index: u32| {
+        let mut header = vec![family as u8, 24, 0, 0];
+        header.extend_from_slice(&index.to_ne_bytes());
+        header
+    };
+
+    let mut recording = message(
+        RTM_NEWLINK,
+        &ifinfomsg(1),
+        &[(IFLA_IFNAME, b"lo\0"), (IFLA_OPERSTATE, &[0])],
+    );
+    recording.extend(message(
+        RTM_NEWLINK,
+        &ifinfomsg(2),
+        &[(IFLA_IFNAME, b"wlan0\0"), (IFLA_OPERSTATE, &[IF_OPER_UP])],
+    ));
+    recording.extend(message(
+        RTM_NEWADDR,
+        &ifaddrmsg(libc::AF_INET, 2),
+        &[(IFA_LOCAL, &[192, 168, 1, 5])],
+    ));
+    recording.extend(message(
+        RTM_NEWADDR,
+        &ifaddrmsg(libc::AF_INET, 1),
+        &[(IFA_LOCAL, &[127, 0, 0, 1])],
+    ));
+    recording.extend(message(NLMSG_DONE, &[0; 4], &[]));
+
+    let mut state = LinkState::default();
+    assert!(state.apply("wlan0", &recording));
+    assert_eq!(
+        state,
+        LinkState {
+            index: Some(2),
+            up: true,
+            ipv4: vec![Ipv4Addr::new(192, 168, 1, 5)],
+            ipv6: Vec::new(),
+        }
+    );
+
+    let mut ipv6 = [0u8; 16];
+    ipv6[0] = 0xfe;
+    ipv6[1] = 0x80;
+    ipv6[15] = 1;
+    let mut events = message(
+        RTM_DELADDR,
+        &ifaddrmsg(libc::AF_INET, 2),
+        &[(IFA_LOCAL, &[192, 168, 1, 5])],
+    );
+    events.extend(message(
+        RTM_NEWADDR,
+        &ifaddrmsg(libc::AF_INET6, 2),
+        &[(IFA_ADDRESS, &ipv6)],
+    ));
+    events.extend(message(
+        RTM_NEWLINK,
+        &ifinfomsg(2),
+        &[(IFLA_IFNAME, b"wlan0\0"), (IFLA_OPERSTATE, &[2])],
+    ));
+    assert!(!state.apply("wlan0", &events));
+    assert!(!state.up);
+    assert!(state.ipv4.is_empty());
+    assert_eq!(state.ipv6, vec![Ipv6Addr::from(ipv6)]);
+}
+
+#[test]
+fn test_parse_net_dev() {
+    let net_dev = "Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:    1000      10    0    0    0     0          0         0     1000      10    0    0    0     0       0          0
+ wlan0: 5000000    4000    0    0    0     0          0         0   250000    2000    0    0    0     0       0          0
+";
+    assert_eq!(parse_net_dev(net_dev, "wlan0"), Some((5000000, 250000)));
+    assert_eq!(parse_net_dev(net_dev, "eth0"), None);
+}
@@ -0,0 +1,659 @@
+// Outputs are set up in steps. Output is bound when it's announced, it's
+// mode and name are collected until done event, and the bar is put on it once
+// globals the bar needs are bound. Globals may be announced in any order.
+// Buffers of the bar are sized by configure of it's layer surface.
+// Bars put on an output are picked by it's name from OutputConfigs
+
+use std::{
+    ffi::CStr,
+    fs::File,
+    io,
+    os::{fd::FromRawFd, unix::prelude::AsFd},
+};
+
+use wayland_client::{
+    protocol::{wl_buffer::WlBuffer, wl_output, wl_registry, wl_shm, wl_shm_pool},
+    Connection, Dispatch, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols_wlr::layer_shell::v1::client::{zwlr_layer_shell_v1, zwlr_layer_surface_v1};
+
+use crate::error::{self, Error};
+use crate::river_status_protocol::zriver_output_status_v1;
+use crate::{Bar, OutputContext};
+
+// Height of the bar relative to height of the output
+const HEIGHT_PROCENT: f32 = 0.015;
+
+/// Edge of the output the bar is on
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Position {
+    #[default]
+    Top,
+    Bottom,
+}
+
+/// What one bar on an output looks like
+#[derive(Clone)]
+pub struct BarConfig {
+    // In pixels, 1.5% of output height if None
+    pub height: Option<i32>,
+    pub position: Position,
+    pub tags: bool,
+    // Title or taskbar
+    pub title: bool,
+    pub widgets: bool,
+    pub blocks: bool,
+    // Names of blocks that aren't shown on the bar
+    pub hidden_blocks: Vec<String>,
+    pub tray: bool,
+}
+
+impl Default for BarConfig {
+    fn default() -> Self {
+        Self {
+            height: None,
+            position: Position::Top,
+            tags: true,
+            title: true,
+            widgets: true,
+            blocks: true,
+            hidden_blocks: Vec::new(),
+            tray: true,
+        }
+    }
+}
+
+impl BarConfig {
+    pub fn shows_block(&self, name: &str) -> bool {
+        self.blocks && !self.hidden_blocks.iter().any(|hidden| hidden == name)
+    }
+}
+
+/// Bars on outputs it matches. The first matching one of Config::outputs
+/// is used, outputs without a match get one default bar
+#[derive(Clone)]
+pub struct OutputConfig {
+    // Whole output name like "DP-1" or "*", part of it's description like
+    // model of the monitor when by_description is set
+    pub matches: String,
+    pub by_description: bool,
+    // Each gets it's own layer surface, no bars keep the output free
+    pub bars: Vec<BarConfig>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self::new("*")
+    }
+}
+
+impl OutputConfig {
+    /// One bar with everything on outputs that match
+    pub fn new(matches: &str) -> Self {
+        Self::with_bars(matches, vec![BarConfig::default()])
+    }
+
+    pub fn with_bars(matches: &str, bars: Vec<BarConfig>) -> Self {
+        Self {
+            matches: matches.to_string(),
+            by_description: false,
+            bars,
+        }
+    }
+
+    /// Keeps bar off outputs that match
+    pub fn disabled(matches: &str) -> Self {
+        Self::with_bars(matches, Vec::new())
+    }
+
+    /// Matches outputs whose description contains matches instead of their name
+    pub fn by_description(mut self) -> Self {
+        self.by_description = true;
+        self
+    }
+
+    pub fn matches(&self, name: &str, description: &str) -> bool {
+        if self.by_description {
+            !self.matches.is_empty() && description.contains(&self.matches)
+        } else {
+            self.matches == "*" || self.matches == name
+        }
+    }
+
+    /// First of configs that matches the output, or the default
+    pub fn find(configs: &[OutputConfig], name: &str, description: &str) -> OutputConfig {
+        configs
+            .iter()
+            .find(|config| config.matches(name, description))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+pub(crate) enum Setup {
+    // Mode and name are still being sent
+    Describing,
+    // Waits for globals needed to create the bar
+    Described,
+    // Bars shown on the output, none when config keeps it free
+    Bars(Vec<zwlr_layer_surface_v1::ZwlrLayerSurfaceV1>),
+}
+
+pub(crate) struct Output {
+    pub wl_output: wl_output::WlOutput,
+    // Name of the global in registry
+    pub global: u32,
+    // e.g. "DP-1"
+    pub name: String,
+    // e.g. "Samsung Electric Company LU28R55 HX5R300107 (DP-1)"
+    pub description: String,
+    // Size of current mode
+    pub mode: Option<(i32, i32)>,
+    pub transform: wl_output::Transform,
+    pub scale: i32,
+    pub river_status: Option<zriver_output_status_v1::ZriverOutputStatusV1>,
+    pub setup: Setup,
+}
+
+impl Output {
+    /// Destroys objects of the output, it's bars have to be closed before
+    pub fn release(self) {
+        if let Some(river_status) = self.river_status {
+            river_status.destroy();
+        }
+        if self.wl_output.version() >= 3 {
+            self.wl_output.release();
+        }
+    }
+
+    fn has_bar(&self, layer_surface: &zwlr_layer_surface_v1::ZwlrLayerSurfaceV1) -> bool {
+        matches!(&self.setup, Setup::Bars(surfaces) if surfaces.contains(layer_surface))
+    }
+
+    // Layer surfaces of bars on the output
+    fn bars(&self) -> &[zwlr_layer_surface_v1::ZwlrLayerSurfaceV1] {
+        match &self.setup {
+            Setup::Bars(surfaces) => surfaces,
+            _ => &[],
+        }
+    }
+
+    // Size of the output for surfaces on it, e.g. 1080x1920 for 1920x1080 mode turned by 90 degrees
+    fn logical_size(&self) -> Option<(i32, i32)> {
+        use wl_output::Transform;
+        let (width, height) = self.mode?;
+        let (width, height) = match self.transform {
+            Transform::_90 | Transform::_270 | Transform::Flipped90 | Transform::Flipped270 => {
+                (height, width)
+            }
+            _ => (width, height),
+        };
+        Some((width / self.scale, height / self.scale))
+    }
+
+    fn bar_height(&self) -> Option<i32> {
+        let (_, height) = self.logical_size()?;
+        Some((height as f32 * HEIGHT_PROCENT) as i32)
+    }
+}
+
+/// Anonymous memory of given size that can be shared with the compositor.
+/// Each instance of the bar gets it's own, so they don't overwrite each other
+pub(crate) fn shared_memory(name: &CStr, size: u64) -> io::Result<File> {
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size)?;
+    Ok(file)
+}
+
+/// Shared memory of one bar: two buffers drawn in turns and one pixel high
+/// strip presented while the bar is auto hidden
+pub(crate) struct Buffers {
+    // Region of the pool the buffers were created in
+    offset: i32,
+    capacity: i32,
+    // Required to keep mmap from droping and let us send this to other thread cause cairo::Context didn't implement Send+Sync
+    pub canvases: [memmap2::MmapMut; 2],
+    pub buffers: [WlBuffer; 2],
+    pub strip: WlBuffer,
+}
+
+impl Buffers {
+    fn size(width: i32, height: i32) -> i32 {
+        width * height * 4 * 2 + width * 4
+    }
+
+    fn new(
+        pool: &wl_shm_pool::WlShmPool,
+        file: &File,
+        (offset, capacity): (i32, i32),
+        width: i32,
+        height: i32,
+        qh: &QueueHandle<Bar>,
+    ) -> io::Result<Self> {
+        let frame_size = width * height * 4;
+        let mmap = |offset: i32, len: i32| unsafe {
+            memmap2::MmapOptions::new()
+                .offset(offset as u64)
+                .len(len as usize)
+                .map_mut(file)
+        };
+        let mut canvases = [
+            mmap(offset, frame_size)?,
+            mmap(offset + frame_size, frame_size)?,
+        ];
+        // Shared memory file may contain leftovers from previous run or other sizes
+        for canvas in canvases.iter_mut() {
+            canvas.fill(0);
+        }
+        mmap(offset + frame_size * 2, width * 4)?.fill(0);
+        let buffer = |offset: i32, height: i32| {
+            pool.create_buffer(
+                offset,
+                width,
+                height,
+                width * 4,
+                wl_shm::Format::Argb8888,
+                qh,
+                (),
+            )
+        };
+        Ok(Self {
+            offset,
+            capacity,
+            canvases,
+            buffers: [buffer(offset, height), buffer(offset + frame_size, height)],
+            strip: buffer(offset + frame_size * 2, 1),
+        })
+    }
+
+    /// Copies the last committed canvas into canvas with index current, so
+    /// only what changed has to be drawn into it. Both threads draw just their
+    /// part of the bar and commit it whole, so each draw starts with this
+    pub fn copy_previous(&mut self, current: usize) {
+        let [first, second] = &mut self.canvases;
+        let (previous, current) = if current == 0 {
+            (&*second, first)
+        } else {
+            (&*first, second)
+        };
+        current.copy_from_slice(previous);
+    }
+
+    pub fn destroy(&self) {
+        for buffer in self.buffers.iter() {
+            buffer.destroy();
+        }
+        self.strip.destroy();
+    }
+}
+
+impl OutputContext {
+    // Asks compositor for another height, e.g. after output was rotated
+    fn request_height(&mut self, height: i32) {
+        if self.height == height {
+            return;
+        }
+        self.height = height;
+        if self.visible {
+            self.layer_surface.set_size(0, height as u32);
+            if !self.auto_hide {
+                self.layer_surface.set_exclusive_zone(height);
+            }
+            self.surface.commit();
+        }
+    }
+}
+
+impl Bar {
+    /// Binds announced output, bar is put on it after it's described
+    pub fn add_output(
+        &mut self,
+        registry: &wl_registry::WlRegistry,
+        global: u32,
+        version: u32,
+        qh: &QueueHandle<Self>,
+    ) {
+        // Version 4 sends names of outputs
+        let wl_output: wl_output::WlOutput = registry.bind(global, version.min(4), qh, ());
+        self.outputs.push(Output {
+            wl_output,
+            global,
+            name: String::new(),
+            description: String::new(),
+            mode: None,
+            transform: wl_output::Transform::Normal,
+            scale: 1,
+            river_status: None,
+            setup: Setup::Describing,
+        });
+    }
+
+    /// Gives outputs what they waited for, called whenever a global is bound
+    /// or an output is described
+    pub fn setup_outputs(&mut self, qh: &QueueHandle<Self>) {
+        if let Some(manager) = &self.river_status_manager {
+            for output in self.outputs.iter_mut() {
+                if output.river_status.is_none() {
+                    output.river_status =
+                        Some(manager.get_river_output_status(&output.wl_output, qh, ()));
+                }
+            }
+        }
+        if self.check_globals().is_err() {
+            return;
+        }
+        for index in 0..self.outputs.len() {
+            if matches!(self.outputs[index].setup, Setup::Described) {
+                self.create_bar(index, qh);
+            }
+        }
+    }
+
+    /// Output global was removed. Bars on it are closed as if compositor closed them
+    pub fn remove_output(&mut self, global: u32) {
+        let Some(index) = self
+            .outputs
+            .iter()
+            .position(|output| output.global == global)
+        else {
+            return;
+        };
+        let layer_surfaces = self.outputs[index].bars().to_vec();
+        if layer_surfaces.is_empty() {
+            self.outputs.remove(index).release();
+        }
+        // Output is released with it's last bar
+        for layer_surface in layer_surfaces {
+            self.close_bar(&layer_surface);
+        }
+    }
+
+    /// Destroys bar whose layer surface compositor won't show anymore. The output
+    /// under it is released once all of it's bars are closed
+    pub fn close_bar(&mut self, layer_surface: &zwlr_layer_surface_v1::ZwlrLayerSurfaceV1) {
+        let Some(output_index) = self
+            .layer_surfaces
+            .iter()
+            .position(|surface| surface == layer_surface)
+        else {
+            return;
+        };
+        self.layer_surfaces.remove(output_index);
+        let mut outputs_contexts = self.outputs_contexts.lock().unwrap();
+        let output_context = outputs_contexts.remove(output_index);
+        output_context.layer_surface.destroy();
+        output_context.surface.destroy();
+        if let Some(buffers) = &output_context.buffers {
+            buffers.destroy();
+        }
+        // Bar keeps running without outputs, e.g. while the only monitor is
+        // unplugged or turned off, and is put on them again when they come back
+        drop(outputs_contexts);
+        let Some(index) = self
+            .outputs
+            .iter()
+            .position(|output| output.has_bar(layer_surface))
+        else {
+            return;
+        };
+        if let Setup::Bars(surfaces) = &mut self.outputs[index].setup {
+            surfaces.retain(|surface| surface != layer_surface);
+            if surfaces.is_empty() {
+                self.outputs.remove(index).release();
+            }
+        }
+    }
+
+    // Globals that are needed to create a bar on an output
+    fn check_globals(&self) -> error::Result<()> {
+        if self.compositor.is_none() {
+            return Err(Error::MissingGlobal("wl_compositor"));
+        }
+        if self.shm.is_none() {
+            return Err(Error::MissingGlobal("wl_shm"));
+        }
+        if self.layer_shell.is_none() {
+            return Err(Error::MissingGlobal("zwlr_layer_shell_v1"));
+        }
+        Ok(())
+    }
+
+    // Puts a layer surface on the output for each bar of it's config
+    fn create_bar(&mut self, output_index: usize, qh: &QueueHandle<Self>) {
+        let output = &mut self.outputs[output_index];
+        let config = OutputConfig::find(&self.output_configs, &output.name, &output.description);
+        let (Some((width, _)), Some(output_bar_height)) =
+            (output.logical_size(), output.bar_height())
+        else {
+            return;
+        };
+        let auto_hide = self.visibility.auto_hide;
+        let mut layer_surfaces = Vec::new();
+        for bar_config in config.bars {
+            let height = bar_config.height.unwrap_or(output_bar_height);
+            let surface = self.compositor.as_ref().unwrap().create_surface(qh, ());
+            let layer_surface = self.layer_shell.as_ref().unwrap().get_layer_surface(
+                &surface,
+                Some(&output.wl_output),
+                zwlr_layer_shell_v1::Layer::Top,
+                "statusbar".to_string(),
+                qh,
+                (),
+            );
+            // Layer configure. Compositor picks the width, buffers are created after configure
+            use zwlr_layer_surface_v1::{Anchor, KeyboardInteractivity};
+            let edge = match bar_config.position {
+                Position::Top => Anchor::Top,
+                Position::Bottom => Anchor::Bottom,
+            };
+            layer_surface.set_anchor(edge | Anchor::Left | Anchor::Right);
+            layer_surface.set_exclusive_zone(height);
+            layer_surface.set_keyboard_interactivity(KeyboardInteractivity::None);
+            if auto_hide {
+                layer_surface.set_exclusive_zone(0);
+                layer_surface.set_size(0, 1);
+            } else {
+                layer_surface.set_size(0, height as u32);
+            }
+            surface.commit();
+
+            layer_surfaces.push(layer_surface.clone());
+            self.layer_surfaces.push(layer_surface.clone());
+
+            self.outputs_contexts.lock().unwrap().push(OutputContext {
+                name: output.name.clone(),
+                ready_to_draw: false,
+                visible: !auto_hide,
+                revealed_by_mode: false,
+                auto_hide,
+                width,
+                height,
+                blocks_offset: width,
+                tray_width: 0,
+                block_spans: Vec::new(),
+                tag_spans: Vec::new(),
+                widget_spans: Vec::new(),
+                tray_spans: Vec::new(),
+                taskbar_entries: Vec::new(),
+                previous_tags_and_title_length: -1,
+                config: bar_config,
+                current_buffer_index: 0,
+                surface,
+                layer_surface,
+                buffers: None,
+            });
+        }
+        output.setup = Setup::Bars(layer_surfaces);
+    }
+
+    /// Takes size from configure of the bar's layer surface. Buffers are created
+    /// again when it differs from their size. Returns false if there are no buffers to draw into
+    pub fn configure_bar(
+        &mut self,
+        output_index: usize,
+        width: u32,
+        height: u32,
+        qh: &QueueHandle<Self>,
+    ) -> bool {
+        let mut outputs_contexts = self.outputs_contexts.lock().unwrap();
+        let output_context = &mut outputs_contexts[output_index];
+        // Zero means the bar may pick, collapsed bar keeps height of the bar for when it's revealed
+        let width = if width > 0 {
+            width as i32
+        } else {
+            output_context.width
+        };
+        let height = if height > 0 && output_context.visible {
+            height as i32
+        } else {
+            output_context.height
+        };
+        if output_context.buffers.is_some()
+            && (width, height) == (output_context.width, output_context.height)
+        {
+            return true;
+        }
+
+        // Buffers of an output are created again in their old region when they fit,
+        // otherwise after buffers of all outputs. Memory of removed outputs isn't reused
+        let size = Buffers::size(width, height);
+        let region = match output_context.buffers.take() {
+            Some(buffers) => {
+                buffers.destroy();
+                (buffers.capacity >= size).then_some((buffers.offset, buffers.capacity))
+            }
+            None => None,
+        };
+        let region = match region {
+            Some(region) => region,
+            None => {
+                let offset = self.pool.as_ref().map_or(0, |(_, size)| *size);
+                let file_size = self.file.metadata().map_or(0, |metadata| metadata.len());
+                if (offset + size) as u64 > file_size {
+                    log::error!("Shared memory file is too small for {width}x{height} bar");
+                    return false;
+                }
+                match &mut self.pool {
+                    Some((pool, pool_size)) => {
+                        *pool_size += size;
+                        pool.resize(*pool_size);
+                    }
+                    None => {
+                        let pool =
+                            self.shm
+                                .as_ref()
+                                .unwrap()
+                                .create_pool(self.file.as_fd(), size, qh, ());
+                        self.pool = Some((pool, size));
+                    }
+                }
+                (offset, size)
+            }
+        };
+        let pool = &self.pool.as_ref().unwrap().0;
+        match Buffers::new(pool, &self.file, region, width, height, qh) {
+            Ok(buffers) => output_context.buffers = Some(buffers),
+            Err(err) => {
+                log::error!("Can't map bar buffers: {err}");
+                return false;
+            }
+        }
+        output_context.width = width;
+        output_context.height = height;
+        output_context.blocks_offset = width;
+        output_context.current_buffer_index = 0;
+        // Fresh buffers are drawn whole by both threads
+        output_context.ready_to_draw = false;
+        output_context.previous_tags_and_title_length = -1;
+        true
+    }
+}
+
+impl Dispatch<wl_output::WlOutput, ()> for Bar {
+    fn event(
+        state: &mut Self,
+        proxy: &wl_output::WlOutput,
+        event: wl_output::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        let Some(output) = state
+            .outputs
+            .iter_mut()
+            .find(|output| output.wl_output == *proxy)
+        else {
+            return;
+        };
+        match event {
+            wl_output::Event::Geometry {
+                transform: WEnum::Value(transform),
+                ..
+            } => output.transform = transform,
+            wl_output::Event::Mode {
+                flags: WEnum::Value(flags),
+                width,
+                height,
+                ..
+            } if flags.contains(wl_output::Mode::Current) => {
+                output.mode = Some((width, height));
+                // Outputs before version 2 don't send done
+                if proxy.version() < 2 && matches!(output.setup, Setup::Describing) {
+                    output.setup = Setup::Described;
+                    state.setup_outputs(qh);
+                }
+            }
+            wl_output::Event::Scale { factor } => output.scale = factor.max(1),
+            wl_output::Event::Name { name } => {
+                let mut outputs_contexts = state.outputs_contexts.lock().unwrap();
+                for output_context in outputs_contexts
+                    .iter_mut()
+                    .filter(|context| output.bars().contains(&context.layer_surface))
+                {
+                    output_context.name = name.clone();
+                }
+                output.name = name;
+            }
+            wl_output::Event::Description { description } => output.description = description,
+            wl_output::Event::Done => match &output.setup {
+                Setup::Describing if output.mode.is_some() => {
+                    output.setup = Setup::Described;
+                    state.setup_outputs(qh);
+                }
+                // Output changed mode, scale or was rotated. Compositor configures the bars
+                // to new width, the height is asked for
+                Setup::Bars(layer_surfaces) => {
+                    let mut outputs_contexts = state.outputs_contexts.lock().unwrap();
+                    for output_context in outputs_contexts
+                        .iter_mut()
+                        .filter(|context| layer_surfaces.contains(&context.layer_surface))
+                    {
+                        if let Some(height) = output_context.config.height.or(output.bar_height()) {
+                            output_context.request_height(height);
+                        }
+                    }
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+}
+
+#[test]
+fn test_output_config_matches() {
+    let config = OutputConfig::new("DP-1");
+    assert!(config.matches("DP-1", ""));
+    assert!(!config.matches("eDP-1", ""));
+    assert!(!config.matches("DP-2", "Dell DP-1 monitor"));
+
+    let config = OutputConfig::new("LG TV").by_description();
+    assert!(config.matches("HDMI-A-1", "LG Electronics LG TV 0x01010101"));
+    assert!(!config.matches("LG TV", "Dell Inc. DELL U2720Q"));
+
+    assert!(OutputConfig::default().matches("eDP-1", ""));
+}
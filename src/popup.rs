@@ -0,0 +1,393 @@
+// Popups under the bar: menus, calendar and tooltips. Only one is open at a time
+// because xdg_popup grabs the pointer anyway. Grabbing popups also get keyboard
+// focus for navigation, bar asks for it only while one of them is open
+
+use std::{fs::File, os::fd::AsFd};
+
+use pangocairo::cairo as cr;
+use wayland_client::{
+    protocol::{wl_buffer, wl_shm, wl_shm_pool, wl_surface},
+    Connection, Dispatch, Proxy, QueueHandle,
+};
+use wayland_protocols::xdg::shell::client::{xdg_popup, xdg_positioner, xdg_surface, xdg_wm_base};
+use wayland_protocols_wlr::layer_shell::v1::client::zwlr_layer_surface_v1::KeyboardInteractivity;
+
+use crate::blocks::BlockPopup;
+use crate::calendar::Calendar;
+use crate::error;
+use crate::menu::{Menu, MenuItem, MenuSource};
+use crate::output::{self, Position};
+use crate::render;
+use crate::tooltip;
+use crate::Bar;
+
+// linux/input-event-codes.h
+const BTN_LEFT: u32 = 0x110;
+const KEY_ESC: u32 = 1;
+const KEY_ENTER: u32 = 28;
+const KEY_SPACE: u32 = 57;
+const KEY_HOME: u32 = 102;
+const KEY_UP: u32 = 103;
+const KEY_LEFT: u32 = 105;
+const KEY_RIGHT: u32 = 106;
+const KEY_DOWN: u32 = 108;
+
+pub enum Content {
+    Menu(Menu),
+    Calendar(Calendar),
+    Tooltip(String),
+}
+
+pub struct Popup {
+    pub surface: wl_surface::WlSurface,
+    xdg_surface: xdg_surface::XdgSurface,
+    xdg_popup: xdg_popup::XdgPopup,
+    pool: wl_shm_pool::WlShmPool,
+    buffer: wl_buffer::WlBuffer,
+    canvas: memmap2::MmapMut,
+    pub width: i32,
+    pub height: i32,
+    // Where popup was opened, submenus reuse it
+    pub output_index: usize,
+    pub anchor: (i32, i32),
+    // Bar has keyboard interactivity while popup is open
+    grabbed: bool,
+    configured: bool,
+    pub content: Content,
+}
+
+/// Context to measure text before popup size is known
+pub fn measure_context() -> cr::Context {
+    let surface = cr::ImageSurface::create(cr::Format::ARgb32, 1, 1).unwrap();
+    cr::Context::new(surface).unwrap()
+}
+
+// Popup has it's own memory, bar's pool is laid out per output
+fn popup_memory(size: i32) -> error::Result<(File, memmap2::MmapMut)> {
+    let file = output::shared_memory(c"tagsandblocks-popup", size as u64)?;
+    let canvas = unsafe { memmap2::MmapMut::map_mut(&file)? };
+    Ok((file, canvas))
+}
+
+impl Bar {
+    /// Opens popup under span of the bar on given output.
+    /// grab is serial of pointer button press that caused it, tooltips don't grab
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_popup(
+        &mut self,
+        output_index: usize,
+        anchor_x: i32,
+        anchor_width: i32,
+        (width, height): (i32, i32),
+        grab: Option<u32>,
+        content: Content,
+        qh: &QueueHandle<Self>,
+    ) {
+        self.close_popup();
+        let (Some(compositor), Some(shm), Some(wm_base)) =
+            (&self.compositor, &self.shm, &self.xdg_wm_base)
+        else {
+            return;
+        };
+        let outputs_contexts = self.outputs_contexts.lock().unwrap();
+        let Some(output_context) = outputs_contexts.get(output_index) else {
+            return;
+        };
+
+        let size = width * height * 4;
+        let (file, canvas) = match popup_memory(size) {
+            Ok(memory) => memory,
+            Err(err) => {
+                log::error!("Can't create popup memory: {err}");
+                return;
+            }
+        };
+        let pool = shm.create_pool(file.as_fd(), size, qh, ());
+        let buffer = pool.create_buffer(0, width, height, width * 4, wl_shm::Format::Argb8888, qh, ());
+
+        let positioner = wm_base.create_positioner(qh, ());
+        positioner.set_size(width, height);
+        positioner.set_anchor_rect(anchor_x, 0, anchor_width.max(1), output_context.height);
+        // Popups of a bar at the bottom open upwards
+        if output_context.config.position == Position::Bottom {
+            positioner.set_anchor(xdg_positioner::Anchor::TopLeft);
+            positioner.set_gravity(xdg_positioner::Gravity::TopRight);
+        } else {
+            positioner.set_anchor(xdg_positioner::Anchor::BottomLeft);
+            positioner.set_gravity(xdg_positioner::Gravity::BottomRight);
+        }
+        positioner.set_constraint_adjustment(
+            (xdg_positioner::ConstraintAdjustment::SlideX
+                | xdg_positioner::ConstraintAdjustment::FlipY)
+                .bits(),
+        );
+
+        let surface = compositor.create_surface(qh, ());
+        let xdg_surface = wm_base.get_xdg_surface(&surface, qh, ());
+        let xdg_popup = xdg_surface.get_popup(None, &positioner, qh, ());
+        // Popups inherit keyboard interactivity of the layer surface
+        let grabbed = grab.is_some() && self.seat.is_some();
+        if grabbed {
+            let layer_surface = &output_context.layer_surface;
+            if layer_surface.version() >= 4 {
+                layer_surface.set_keyboard_interactivity(KeyboardInteractivity::OnDemand);
+            } else {
+                layer_surface.set_keyboard_interactivity(KeyboardInteractivity::Exclusive);
+            }
+            output_context.surface.commit();
+        }
+        output_context.layer_surface.get_popup(&xdg_popup);
+        positioner.destroy();
+        // Popup is dismissed by click outside of it
+        if let (Some(seat), Some(serial)) = (&self.seat, grab) {
+            xdg_popup.grab(seat, serial);
+        }
+        surface.commit();
+        drop(outputs_contexts);
+
+        self.popup = Some(Popup {
+            surface,
+            xdg_surface,
+            xdg_popup,
+            pool,
+            buffer,
+            canvas,
+            width,
+            height,
+            output_index,
+            anchor: (anchor_x, anchor_width),
+            grabbed,
+            configured: false,
+            content,
+        });
+    }
+
+    pub fn close_popup(&mut self) {
+        if let Some(popup) = self.popup.take() {
+            popup.xdg_popup.destroy();
+            popup.xdg_surface.destroy();
+            popup.surface.destroy();
+            popup.buffer.destroy();
+            popup.pool.destroy();
+            if popup.grabbed {
+                let outputs_contexts = self.outputs_contexts.lock().unwrap();
+                if let Some(output_context) = outputs_contexts.get(popup.output_index) {
+                    output_context
+                        .layer_surface
+                        .set_keyboard_interactivity(KeyboardInteractivity::None);
+                    output_context.surface.commit();
+                }
+            }
+        }
+    }
+
+    pub fn draw_popup(&mut self) {
+        let Some(popup) = &mut self.popup else {
+            return;
+        };
+        if !popup.configured {
+            return;
+        }
+        let image_surface = unsafe {
+            cr::ImageSurface::create_for_data_unsafe(
+                popup.canvas.as_mut_ptr(),
+                cr::Format::ARgb32,
+                popup.width,
+                popup.height,
+                popup.width * 4,
+            )
+            .unwrap()
+        };
+        let cr = cr::Context::new(image_surface).unwrap();
+        let pg_layout = render::create_layout(&cr);
+        match &popup.content {
+            Content::Menu(menu) => menu.draw(&cr, &pg_layout, &self.theme, popup.width),
+            Content::Calendar(calendar) => {
+                calendar.draw(&cr, &pg_layout, &self.theme, (popup.width, popup.height))
+            }
+            Content::Tooltip(text) => tooltip::draw(
+                &cr,
+                &pg_layout,
+                &self.theme,
+                text,
+                (popup.width, popup.height),
+            ),
+        }
+        popup.surface.attach(Some(&popup.buffer), 0, 0);
+        popup.surface.damage(0, 0, popup.width, popup.height);
+        popup.surface.commit();
+    }
+
+    /// Opens popup configured for the block under pointer on left click.
+    /// Returns false if there is no such block
+    pub fn block_button(&mut self, serial: u32, button: u32, qh: &QueueHandle<Self>) -> bool {
+        if button != BTN_LEFT {
+            return false;
+        }
+        let Some((output_index, _)) = self.pointer_output() else {
+            return false;
+        };
+        let x = self.pointer_x as i32;
+        let outputs_contexts = self.outputs_contexts.lock().unwrap();
+        let Some(span) = outputs_contexts[output_index]
+            .block_spans
+            .iter()
+            .find(|span| (span.start..span.end).contains(&x))
+            .cloned()
+        else {
+            return false;
+        };
+        drop(outputs_contexts);
+        let Some((_, block_popup)) = self
+            .block_popups
+            .iter()
+            .find(|(name, _)| *name == span.name)
+        else {
+            return false;
+        };
+        let (size, content) = match block_popup {
+            BlockPopup::Calendar => {
+                let calendar = Calendar::local();
+                (calendar.size(), Content::Calendar(calendar))
+            }
+            BlockPopup::Menu(commands) => {
+                let items = commands
+                    .iter()
+                    .enumerate()
+                    .map(|(index, (label, _))| MenuItem::new(index as i32, label))
+                    .collect();
+                let mut menu = Menu::new(MenuSource::Block(span.name.clone()), items);
+                (menu.size(), Content::Menu(menu))
+            }
+        };
+        self.open_popup(
+            output_index,
+            span.start,
+            span.end - span.start,
+            size,
+            Some(serial),
+            content,
+            qh,
+        );
+        true
+    }
+
+    /// Keyboard navigation inside of the open popup
+    pub fn popup_key(&mut self, key: u32, serial: u32, qh: &QueueHandle<Self>) {
+        let Some(popup) = &mut self.popup else {
+            return;
+        };
+        if key == KEY_ESC {
+            self.close_popup();
+            return;
+        }
+        match &mut popup.content {
+            Content::Menu(menu) => match key {
+                KEY_UP => menu.select(-1),
+                KEY_DOWN => menu.select(1),
+                KEY_ENTER | KEY_SPACE | KEY_RIGHT => {
+                    if let Some(index) = menu.hovered {
+                        self.activate_menu_item(index, serial, qh);
+                    }
+                    return;
+                }
+                _ => return,
+            },
+            Content::Calendar(calendar) => match key {
+                KEY_LEFT => calendar.shift(-1),
+                KEY_RIGHT => calendar.shift(1),
+                KEY_UP => calendar.shift(-12),
+                KEY_DOWN => calendar.shift(12),
+                KEY_HOME => calendar.reset(),
+                _ => return,
+            },
+            Content::Tooltip(_) => return,
+        }
+        self.draw_popup();
+    }
+
+    /// Scrolling over calendar browses months. Returns false if pointer isn't over it
+    pub fn popup_scroll(&mut self, value: f64) -> bool {
+        if !self.is_pointer_on_popup() {
+            return false;
+        }
+        if let Some(Popup {
+            content: Content::Calendar(calendar),
+            ..
+        }) = &mut self.popup
+        {
+            if value != 0. {
+                calendar.shift(value.signum() as i32);
+                self.draw_popup();
+            }
+        }
+        true
+    }
+
+    /// Pointer is over the popup surface
+    pub fn is_pointer_on_popup(&self) -> bool {
+        match (&self.popup, &self.pointer_surface) {
+            (Some(popup), Some(surface)) => popup.surface == *surface,
+            _ => false,
+        }
+    }
+}
+
+impl Dispatch<xdg_wm_base::XdgWmBase, ()> for Bar {
+    fn event(
+        _: &mut Self,
+        wm_base: &xdg_wm_base::XdgWmBase,
+        event: xdg_wm_base::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_wm_base::Event::Ping { serial } = event {
+            wm_base.pong(serial);
+        }
+    }
+}
+
+impl Dispatch<xdg_surface::XdgSurface, ()> for Bar {
+    fn event(
+        state: &mut Self,
+        xdg_surface: &xdg_surface::XdgSurface,
+        event: xdg_surface::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_surface::Event::Configure { serial } = event {
+            xdg_surface.ack_configure(serial);
+            if let Some(popup) = &mut state.popup {
+                if popup.xdg_surface == *xdg_surface {
+                    popup.configured = true;
+                    state.draw_popup();
+                }
+            }
+        }
+    }
+}
+
+impl Dispatch<xdg_popup::XdgPopup, ()> for Bar {
+    fn event(
+        state: &mut Self,
+        xdg_popup: &xdg_popup::XdgPopup,
+        event: xdg_popup::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let xdg_popup::Event::PopupDone = event {
+            let is_current = match &state.popup {
+                Some(popup) => popup.xdg_popup == *xdg_popup,
+                None => false,
+            };
+            if is_current {
+                state.close_popup();
+            }
+        }
+    }
+}
+
+wayland_client::delegate_noop!(Bar: ignore xdg_positioner::XdgPositioner);
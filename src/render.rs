@@ -0,0 +1,283 @@
+// Drawing of the bar that doesn't need a compositor. Both threads draw into
+// mmaped buffers with it and snapshots are drawn into plain image surfaces
+
+use std::fs::File;
+
+use pangocairo::cairo as cr;
+use pangocairo::pango;
+
+use crate::blocks::{self, Block};
+use crate::decoration::Decorations;
+use crate::output::BarConfig;
+use crate::tags::Tags;
+use crate::theme::{self, Theme};
+use crate::widget::{DrawContext, Title, Widget};
+use crate::{Config, RiverStatus};
+
+#[cfg(not(test))]
+const FONT: &str = "IosevkaNerdFontMono";
+// Golden images have to be the same wherever tests run, DejaVu is installed almost everywhere
+#[cfg(test)]
+const FONT: &str = "DejaVu Sans Mono";
+
+/// Layout with the bar font
+pub fn create_layout(cr: &cr::Context) -> pango::Layout {
+    let pg_layout = pangocairo::create_layout(cr);
+    let mut font_desc = pango::FontDescription::new();
+    font_desc.set_family(FONT);
+    font_desc.set_weight(pango::Weight::Bold);
+    font_desc.set_style(pango::Style::Normal);
+    pg_layout.set_font_description(Some(&font_desc));
+    pg_layout
+}
+
+/// Context drawing into ARGB buffer of shared memory.
+/// Buffer has to be width * height * 4 bytes long and outlive the context
+pub(crate) unsafe fn buffer_context(data: *mut u8, width: i32, height: i32) -> cr::Context {
+    let image_surface = cr::ImageSurface::create_for_data_unsafe(
+        data,
+        cr::Format::ARgb32,
+        width,
+        height,
+        width * 4,
+    )
+    .unwrap();
+    cr::Context::new(image_surface).unwrap()
+}
+
+/// Measures widget with space it may take and draws it at x. Returns width it took
+pub fn draw_widget(context: &mut DrawContext, widget: &mut dyn Widget, x: i32, space: i32) -> i32 {
+    context.space = space;
+    let width = widget.measure(context);
+    widget.draw(context, x, width);
+    width
+}
+
+/// Draws widgets one after another from x to right. Returns their spans
+pub fn draw_widgets(
+    context: &mut DrawContext,
+    widgets: &mut [Box<dyn Widget>],
+    x: i32,
+    right: i32,
+) -> Vec<(i32, i32)> {
+    let mut offset = x;
+    let mut spans = Vec::with_capacity(widgets.len());
+    for widget in widgets.iter_mut() {
+        let width = draw_widget(context, widget.as_mut(), offset, right - offset);
+        spans.push((offset, offset + width));
+        offset += width;
+    }
+    spans
+}
+
+/// Width that widgets need together, measured with space of the context
+pub fn widgets_width(context: &DrawContext, widgets: &mut [Box<dyn Widget>]) -> i32 {
+    widgets
+        .iter_mut()
+        .map(|widget| widget.measure(context))
+        .sum()
+}
+
+/// Draws whole bar into a new image surface. Taskbar and tray need
+/// a compositor and a session bus, so title is drawn and tray is left out
+pub fn render(
+    (width, height): (i32, i32),
+    theme: &Theme,
+    decorations: &Decorations,
+    river: &RiverStatus,
+    tags: &mut Tags,
+    widgets: &mut [Box<dyn Widget>],
+    blocks: &mut [Block],
+) -> cr::ImageSurface {
+    let surface = cr::ImageSurface::create(cr::Format::ARgb32, width, height).unwrap();
+    let cr = cr::Context::new(&surface).unwrap();
+    let pg_layout = create_layout(&cr);
+    theme::clear_rectangle(&cr, &theme.bg, 0., 0., width as f64, height as f64);
+    let mut context = DrawContext {
+        cr: &cr,
+        pg_layout: &pg_layout,
+        theme,
+        decorations,
+        river,
+        height,
+        space: width,
+    };
+
+    let block_spans = blocks::draw_blocks(&context, blocks, width, &BarConfig::default());
+    let right = block_spans.last().map_or(width, |span| span.start);
+    let mut offset = draw_widget(&mut context, tags, 0, right);
+    context.space = right - offset;
+    let title_space = right - offset - widgets_width(&context, widgets);
+    offset += draw_widget(&mut context, &mut Title, offset, title_space);
+    draw_widgets(&mut context, widgets, offset, right);
+
+    drop(pg_layout);
+    drop(cr);
+    surface
+}
+
+/// Draws bar of the config once into a PNG file, river state is the one
+/// bar starts with. Used to check themes and blocks without a compositor
+pub fn render_png(config: Config, path: &str, size: (i32, i32)) -> Result<(), String> {
+    let mut blocks = config.blocks;
+    for block in blocks.iter_mut() {
+        block.refresh();
+    }
+    let mut widgets = config.widgets;
+    let river = RiverStatus {
+        focused_tags: 1,
+        view_tags: vec![1],
+        title: String::from("Have a nice day!"),
+        ..RiverStatus::default()
+    };
+    let surface = render(
+        size,
+        &config.theme,
+        &config.decorations,
+        &river,
+        &mut Tags::new(config.tag_labels),
+        &mut widgets,
+        &mut blocks,
+    );
+    let mut file = File::create(path).map_err(|err| format!("Can't create {path}: {err}"))?;
+    surface
+        .write_to_png(&mut file)
+        .map_err(|err| format!("Can't write {path}: {err}"))
+}
+
+// Golden images live in tests/golden. UPDATE_GOLDEN=1 writes them after intended
+// changes of drawing, missing one is a failure so new tests can't pass unchecked
+#[cfg(test)]
+fn assert_golden(name: &str, mut surface: cr::ImageSurface) {
+    let path = format!("{}/tests/golden/{name}.png", env!("CARGO_MANIFEST_DIR"));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(std::path::Path::new(&path).parent().unwrap()).unwrap();
+        surface
+            .write_to_png(&mut File::create(&path).unwrap())
+            .unwrap();
+        return;
+    }
+    let Ok(mut file) = File::open(&path) else {
+        panic!("golden image {path} is missing, run tests with UPDATE_GOLDEN=1 to write it");
+    };
+    let mut golden = cr::ImageSurface::create_from_png(&mut file).unwrap();
+    assert_eq!(
+        (surface.width(), surface.height()),
+        (golden.width(), golden.height()),
+        "size of {name} differs from golden image"
+    );
+    // Allow for small differences of antialiasing between cairo versions
+    let different = surface
+        .data()
+        .unwrap()
+        .iter()
+        .zip(golden.data().unwrap().iter())
+        .filter(|(actual, expected)| actual.abs_diff(**expected) > 8)
+        .count();
+    if different > 0 {
+        let actual = std::env::temp_dir().join(format!("{name}.png"));
+        surface
+            .write_to_png(&mut File::create(&actual).unwrap())
+            .unwrap();
+        panic!("{name} differs from golden image in {different} bytes, see {actual:?}");
+    }
+}
+
+#[cfg(test)]
+fn text_block(name: &str, text: &str) -> Block {
+    use std::process::Command;
+    let mut block = Block::new(name, blocks::Source::Command(Command::new("true")));
+    block.set_text(text);
+    block
+}
+
+#[cfg(test)]
+fn render_test_bar(theme: &Theme, title: &str, width: i32) -> cr::ImageSurface {
+    let river = RiverStatus {
+        focused_tags: 0b10,
+        view_tags: vec![0b1, 0b10, 0b1000],
+        urgent_tags: 0b1000,
+        title: title.to_string(),
+        views: Vec::new(),
+    };
+    let mut blocks = vec![
+        text_block("date", "Mon Oct 19 12:00"),
+        text_block("battery", "85%"),
+    ];
+    render(
+        (width, 20),
+        theme,
+        &Decorations::default(),
+        &river,
+        &mut Tags::new(Default::default()),
+        &mut [],
+        &mut blocks,
+    )
+}
+
+#[test]
+fn test_render_truncated_title() {
+    let title = "A very long title of a browser tab that doesn't fit between tags and blocks";
+    let surface = render_test_bar(&Theme::default(), title, 400);
+    assert_golden("truncated_title", surface);
+
+    let surface = cr::ImageSurface::create(cr::Format::ARgb32, 1, 1).unwrap();
+    let cr = cr::Context::new(&surface).unwrap();
+    let pg_layout = create_layout(&cr);
+    let river = RiverStatus {
+        title: title.to_string(),
+        ..RiverStatus::default()
+    };
+    let context = DrawContext {
+        cr: &cr,
+        pg_layout: &pg_layout,
+        theme: &Theme::default(),
+        decorations: &Decorations::default(),
+        river: &river,
+        height: 20,
+        space: 100,
+    };
+    assert!(Title.measure(&context) <= 100);
+    // Layout is left as it was for the next widget
+    assert_eq!(pg_layout.width(), -1);
+}
+
+// Focused, occupied and urgent tags, title and blocks in every theme
+#[test]
+fn test_render_themes() {
+    for name in Theme::BUILTIN {
+        let surface = render_test_bar(&Theme::builtin(name).unwrap(), "Terminal", 600);
+        assert_golden(&format!("theme_{name}"), surface);
+    }
+}
+
+#[test]
+fn test_render_hidden_block() {
+    let mut blocks = vec![text_block("date", "12:00"), text_block("battery", "85%")];
+    let river = RiverStatus::default();
+    let render_blocks = |blocks: &mut [Block]| {
+        let surface = cr::ImageSurface::create(cr::Format::ARgb32, 200, 20).unwrap();
+        let cr = cr::Context::new(&surface).unwrap();
+        let pg_layout = create_layout(&cr);
+        let context = DrawContext {
+            cr: &cr,
+            pg_layout: &pg_layout,
+            theme: &Theme::default(),
+            decorations: &Decorations::default(),
+            river: &river,
+            height: 20,
+            space: 200,
+        };
+        blocks::draw_blocks(&context, blocks, 200, &BarConfig::default())
+    };
+    let spans = render_blocks(&mut blocks);
+    assert_eq!(spans.len(), 2);
+    assert_eq!(spans[0].end, 200);
+    // Separator is between them
+    assert!(spans[1].end < spans[0].start);
+
+    blocks[0].set_hidden(true);
+    let spans = render_blocks(&mut blocks);
+    assert_eq!(spans.len(), 1);
+    assert_eq!((spans[0].name.as_str(), spans[0].end), ("battery", 200));
+}
@@ -1,6 +1,8 @@
 #![allow(non_camel_case_types)]
 #![allow(non_upper_case_globals)]
 
+// Generated code refers to it as super::wayland_client
+#[allow(clippy::single_component_path_imports)]
 use wayland_client;
 // import objects from the core protocol if needed
 use wayland_client::protocol::*;
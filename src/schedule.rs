@@ -0,0 +1,142 @@
+// Blocks with an interval are updated at their own deadlines. One timerfd is armed
+// for the nearest of them. It counts on CLOCK_BOOTTIME, so time spent in suspend
+// passes for the blocks too and overdue ones are updated once after resume.
+// Deadlines move by whole intervals and don't drift with the time updates take
+
+use std::{
+    io,
+    os::fd::RawFd,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::blocks::Block;
+use crate::timer::TimerFd;
+
+pub(crate) struct Scheduler {
+    timer: TimerFd,
+    // Next update of each block on CLOCK_BOOTTIME, None for blocks without interval
+    deadlines: Vec<Option<Duration>>,
+}
+
+impl Scheduler {
+    pub fn new(blocks: &[Block]) -> io::Result<Self> {
+        let mut scheduler = Self {
+            timer: TimerFd::new(libc::CLOCK_BOOTTIME)?,
+            deadlines: Vec::new(),
+        };
+        scheduler.restart(blocks);
+        Ok(scheduler)
+    }
+
+    /// Counts intervals of all blocks from now, e.g. after they were all updated
+    /// because wall-clock time jumped
+    pub fn restart(&mut self, blocks: &[Block]) {
+        let now = now();
+        self.deadlines = blocks
+            .iter()
+            .map(|block| (!block.interval.is_zero()).then(|| first_deadline(block, now)))
+            .collect();
+        self.arm();
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.timer.fd()
+    }
+
+    /// Indices of blocks that should be updated now. Their next deadlines
+    /// are picked and the timer is armed for the nearest one
+    pub fn due(&mut self, blocks: &[Block]) -> Vec<usize> {
+        let _ = self.timer.read();
+        let now = now();
+        let mut due = Vec::new();
+        for (index, deadline) in self.deadlines.iter_mut().enumerate() {
+            let Some(time) = *deadline else {
+                continue;
+            };
+            if time > now {
+                continue;
+            }
+            let block = &blocks[index];
+            *deadline = Some(if block.aligned {
+                first_deadline(block, now)
+            } else {
+                next_deadline(time, block.interval, now)
+            });
+            due.push(index);
+        }
+        self.arm();
+        due
+    }
+
+    // Timer fires at the nearest deadline, it's disarmed when no block has an interval
+    fn arm(&self) {
+        let deadline = self.deadlines.iter().flatten().min().copied();
+        let deadline = deadline.unwrap_or(Duration::ZERO);
+        self.timer.set(deadline, libc::TFD_TIMER_ABSTIME);
+    }
+}
+
+// Time on CLOCK_BOOTTIME, the clock of the timer
+fn now() -> Duration {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut time) };
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+// One interval from now, aligned blocks wait for the next multiple of their interval in wall-clock time
+fn first_deadline(block: &Block, now: Duration) -> Duration {
+    if !block.aligned {
+        return now + block.interval;
+    }
+    let wall_clock = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now + until_boundary(block.interval, wall_clock)
+}
+
+// Time left to the next multiple of interval, boundaries are counted in UTC
+fn until_boundary(interval: Duration, wall_clock: Duration) -> Duration {
+    let interval = interval.as_nanos();
+    let passed = wall_clock.as_nanos() % interval;
+    Duration::from_nanos((interval - passed) as u64)
+}
+
+// First deadline after now on the grid of the passed one. Intervals missed
+// while the thread was busy or the system was suspended are skipped
+fn next_deadline(deadline: Duration, interval: Duration, now: Duration) -> Duration {
+    if deadline > now {
+        return deadline;
+    }
+    let missed = (now - deadline).as_nanos() / interval.as_nanos();
+    deadline + Duration::from_nanos((interval.as_nanos() * (missed + 1)) as u64)
+}
+
+#[test]
+fn test_next_deadline() {
+    let second = Duration::from_secs(1);
+    let deadline = Duration::from_millis(10_000);
+    // Late update keeps the phase
+    assert_eq!(
+        next_deadline(deadline, second, Duration::from_millis(10_200)),
+        Duration::from_millis(11_000)
+    );
+    // Hour of suspend gives one update, not 3600
+    assert_eq!(
+        next_deadline(deadline, second, Duration::from_millis(3_610_500)),
+        Duration::from_millis(3_611_000)
+    );
+}
+
+#[test]
+fn test_until_boundary() {
+    let minute = Duration::from_secs(60);
+    assert_eq!(
+        until_boundary(minute, Duration::from_millis(120_250)),
+        Duration::from_millis(59_750)
+    );
+    // Exactly on the minute waits for the next one
+    assert_eq!(until_boundary(minute, Duration::from_secs(180)), minute);
+}
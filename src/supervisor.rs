@@ -0,0 +1,204 @@
+// Keeps the bar alive when compositor restarts or the socket drops.
+// Block state lives in blocks thread and survives, everything bound to the
+// connection is created again from scratch
+
+use std::{fmt::Display, io, os::fd::AsRawFd, time::Duration};
+
+use wayland_client::{backend::WaylandError, DispatchError, EventQueue};
+
+use crate::lifecycle::Shutdown;
+use crate::tray::Tray;
+use crate::Bar;
+
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            delay: min,
+        }
+    }
+
+    /// Delay before the next attempt, it doubles every call up to max
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = self.min;
+    }
+}
+
+/// Calls connect until it succeeds. Returns None if shutdown was triggered while waiting
+pub fn retry<T, E: Display>(
+    backoff: &mut Backoff,
+    shutdown: &Shutdown,
+    mut connect: impl FnMut() -> Result<T, E>,
+) -> Option<T> {
+    loop {
+        match connect() {
+            Ok(connection) => {
+                backoff.reset();
+                return Some(connection);
+            }
+            Err(err) => {
+                let delay = backoff.next_delay();
+                log::warn!(
+                    "Can't connect to compositor: {err}, retrying in {:.1}s",
+                    delay.as_secs_f32()
+                );
+                if shutdown.wait(delay) {
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+// Full socket buffer or spurious wakeup isn't a reason to reconnect
+fn ignore_would_block<T>(result: Result<T, WaylandError>) -> Result<(), DispatchError> {
+    match result {
+        Err(WaylandError::Io(err)) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(err) => Err(DispatchError::Backend(err)),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// Dispatches events until bar stops running or shutdown is triggered.
+/// Returns error when connection is lost
+pub fn run(
+    event_queue: &mut EventQueue<Bar>,
+    bar: &mut Bar,
+    shutdown: &Shutdown,
+) -> Result<(), DispatchError> {
+    while bar.running {
+        // Messages that came during tray calls are already read from socket, so poll won't see them
+        if bar.tray.as_ref().is_some_and(Tray::has_queued) {
+            bar.process_tray(&event_queue.handle());
+        }
+        event_queue.dispatch_pending(bar)?;
+        ignore_would_block(event_queue.flush())?;
+        let Some(guard) = event_queue.prepare_read() else {
+            continue;
+        };
+        let mut pfd = vec![
+            libc::pollfd {
+                fd: guard.connection_fd().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: shutdown.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let timer_index = bar.tooltip_timer.as_ref().map(|timer| {
+            pfd.push(libc::pollfd {
+                fd: timer.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            });
+            pfd.len() - 1
+        });
+        // Session bus isn't tied to compositor and survives reconnects
+        let tray_index = bar.tray.as_ref().map(|tray| {
+            pfd.push(libc::pollfd {
+                fd: tray.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            });
+            pfd.len() - 1
+        });
+        // Custom widgets that wait for something, e.g. a socket
+        let widgets_start = pfd.len();
+        let mut fd_widgets = Vec::new();
+        for (index, widget) in bar.widgets.iter().enumerate() {
+            if let Some(fd) = widget.fd() {
+                pfd.push(libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+                fd_widgets.push(index);
+            }
+        }
+        let poll_result = unsafe { libc::poll(pfd.as_mut_ptr(), pfd.len() as libc::nfds_t, -1) };
+        if poll_result < 0 {
+            continue;
+        }
+        // Hangup is reported by read as an error
+        if pfd[0].revents & (libc::POLLIN | libc::POLLHUP | libc::POLLERR) != 0 {
+            ignore_would_block(guard.read())?;
+        } else {
+            drop(guard);
+        }
+        if pfd[1].revents & libc::POLLIN != 0 {
+            bar.running = false;
+        }
+        if timer_index.is_some_and(|index| pfd[index].revents & libc::POLLIN != 0) {
+            bar.show_tooltip(&event_queue.handle());
+        }
+        if tray_index.is_some_and(|index| pfd[index].revents != 0) {
+            bar.process_tray(&event_queue.handle());
+        }
+        for (i, index) in fd_widgets.into_iter().enumerate() {
+            if pfd[widgets_start + i].revents != 0 {
+                bar.update_widget(index);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_backoff() {
+    let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(350));
+    let delays: Vec<u128> = (0..4).map(|_| backoff.next_delay().as_millis()).collect();
+    assert_eq!(delays, [100, 200, 350, 350]);
+    backoff.reset();
+    assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+}
+
+// Reconnecting bar itself is tested against the mock compositor
+#[test]
+fn test_retry_until_socket_appears() {
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let directory = std::env::temp_dir().join(format!("tagsandblocks-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let socket_path = directory.join("wayland-stub");
+    let _ = std::fs::remove_file(&socket_path);
+
+    let shutdown = Shutdown::new().unwrap();
+    let mut backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(4));
+    let mut attempts = 0;
+    let mut listener = None;
+    let stream = retry(&mut backoff, &shutdown, || {
+        attempts += 1;
+        // Socket is there on third attempt
+        if attempts == 3 {
+            listener = Some(UnixListener::bind(&socket_path).unwrap());
+        }
+        UnixStream::connect(&socket_path)
+    });
+    assert!(stream.is_some());
+    assert_eq!(attempts, 3);
+    // Successful connection resets delay
+    assert_eq!(backoff.next_delay(), Duration::from_millis(1));
+
+    shutdown.trigger();
+    let stream = retry(&mut backoff, &shutdown, || {
+        UnixStream::connect(directory.join("missing"))
+    });
+    assert!(stream.is_none());
+    std::fs::remove_dir_all(&directory).unwrap();
+}
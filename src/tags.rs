@@ -0,0 +1,166 @@
+use crate::widget::{DrawContext, Widget};
+
+pub struct TagLabels {
+    // Pango markup for tag with index i (first tag is 0)
+    pub labels: Vec<String>,
+    // Append icons of apps that were seen on the tag. Requires zwlr_foreign_toplevel_manager_v1
+    pub show_app_icons: bool,
+    // app_id to icon markup. Apps that aren't listed fall back to default_icon
+    pub app_icons: Vec<(String, String)>,
+    pub default_icon: String,
+}
+
+impl Default for TagLabels {
+    fn default() -> Self {
+        Self {
+            labels: (1..=9).map(|i| i.to_string()).collect(),
+            show_app_icons: false,
+            app_icons: Vec::new(),
+            default_icon: String::from("\u{f2d0}"),
+        }
+    }
+}
+
+impl TagLabels {
+    pub fn label(&self, tag_index: usize) -> String {
+        match self.labels.get(tag_index) {
+            Some(label) => label.clone(),
+            None => (tag_index + 1).to_string(),
+        }
+    }
+
+    pub fn icon(&self, app_id: &str) -> &str {
+        self.app_icons
+            .iter()
+            .find(|(id, _)| id == app_id)
+            .map(|(_, icon)| icon.as_str())
+            .unwrap_or(&self.default_icon)
+    }
+
+    /// Builds markup for tag bitflag. `app_ids` are ids of views on this tag
+    pub fn markup<'a>(&self, tag: u32, app_ids: impl Iterator<Item = &'a str>) -> String {
+        let mut markup = self.label(crate::bitflag_to_pos(tag).saturating_sub(1) as usize);
+        if self.show_app_icons {
+            let mut icons: Vec<&str> = Vec::new();
+            for app_id in app_ids {
+                let icon = self.icon(app_id);
+                if !icons.contains(&icon) {
+                    icons.push(icon);
+                }
+            }
+            for icon in icons {
+                markup.push(' ');
+                markup.push_str(icon);
+            }
+        }
+        markup
+    }
+}
+
+/// Occupied and focused tags followed by separator
+pub struct Tags {
+    pub labels: TagLabels,
+    // Tags drawn last time as spans on the bar and tag bits
+    spans: Vec<(i32, i32, u32)>,
+}
+
+impl Tags {
+    pub fn new(labels: TagLabels) -> Self {
+        Self {
+            labels,
+            spans: Vec::new(),
+        }
+    }
+
+    pub fn spans(&self) -> &[(i32, i32, u32)] {
+        &self.spans
+    }
+
+    fn markup(&self, context: &DrawContext, tag: u32) -> String {
+        self.labels.markup(tag, context.river.app_ids_on_tag(tag))
+    }
+}
+
+// Occupied tags and focused one even if it's empty
+fn shown_tags(view_tags: &[u32], focused_tags: u32) -> Vec<u32> {
+    let mut tags = view_tags.to_vec();
+    if !tags.contains(&focused_tags) {
+        tags.push(focused_tags);
+        tags.sort();
+    }
+    tags
+}
+
+impl Widget for Tags {
+    fn measure(&mut self, context: &DrawContext) -> i32 {
+        let decoration = &context.decorations.tags;
+        let mut width = 0;
+        for tag in shown_tags(&context.river.view_tags, context.river.focused_tags) {
+            context.pg_layout.set_markup(&self.markup(context, tag));
+            width += decoration.outer_width(context.pg_layout.pixel_size().0);
+        }
+        width + decoration.separator_width(context.pg_layout)
+    }
+
+    fn draw(&mut self, context: &DrawContext, x: i32, _width: i32) {
+        let (cr, pg_layout, theme) = (context.cr, context.pg_layout, context.theme);
+        let decoration = &context.decorations.tags;
+        let height = context.height;
+        let river = context.river;
+        let mut offset = x;
+        self.spans.clear();
+        for tag in shown_tags(&river.view_tags, river.focused_tags) {
+            let (tag_bg, tag_fg) = if tag == river.focused_tags {
+                (theme.focused, theme.focused_fg)
+            } else if tag & river.urgent_tags != 0 {
+                (theme.urgent, theme.urgent_fg)
+            } else {
+                (theme.occupied, theme.occupied_fg)
+            };
+            pg_layout.set_markup(&self.markup(context, tag));
+            let (text_width, text_height) = pg_layout.pixel_size();
+            let tag_width = decoration.outer_width(text_width);
+
+            decoration.draw_background(
+                cr,
+                offset as f64,
+                tag_width as f64,
+                height as f64,
+                &tag_bg,
+            );
+            tag_fg.set_source(cr);
+            cr.move_to(
+                (offset + decoration.padding) as f64,
+                (height - text_height) as f64 / 2.,
+            );
+            pangocairo::show_layout(cr, pg_layout);
+            self.spans.push((offset, offset + tag_width, tag));
+            offset += tag_width;
+        }
+        decoration.draw_separator(cr, pg_layout, offset as f64, height as f64, &theme.fg);
+    }
+}
+
+#[test]
+fn test_shown_tags() {
+    assert_eq!(shown_tags(&[1, 4], 2), [1, 2, 4]);
+    assert_eq!(shown_tags(&[1, 4], 4), [1, 4]);
+}
+
+#[test]
+fn test_tag_markup() {
+    let mut labels = TagLabels {
+        labels: vec![String::from("web"), String::from("code")],
+        app_icons: vec![(String::from("firefox"), String::from("F"))],
+        default_icon: String::from("?"),
+        ..TagLabels::default()
+    };
+    assert_eq!(labels.markup(0b10, ["firefox"].into_iter()), "code");
+    assert_eq!(labels.markup(0b100, std::iter::empty()), "3");
+
+    labels.show_app_icons = true;
+    assert_eq!(
+        labels.markup(0b1, ["firefox", "foot", "firefox", "kitty"].into_iter()),
+        "web F ?"
+    );
+}
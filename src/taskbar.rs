@@ -0,0 +1,208 @@
+// Window list of views on focused tags, drawn in place of focused view title
+
+use pangocairo::cairo as cr;
+use pangocairo::pango;
+use wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_handle_v1::ZwlrForeignToplevelHandleV1;
+
+use crate::Bar;
+
+// linux/input-event-codes.h
+const BTN_LEFT: u32 = 0x110;
+const BTN_MIDDLE: u32 = 0x112;
+// Mouse wheel sends 10 or 15 per notch, touchpad sends many small values
+const SCROLL_STEP: f64 = 10.;
+
+pub struct Taskbar {
+    // Otherwise title of focused view is drawn like before
+    pub enabled: bool,
+    // In pixels, longer titles are ellipsized
+    pub max_entry_width: i32,
+    // Show app_id instead of title, e.g. "firefox"
+    pub show_app_id: bool,
+}
+
+impl Default for Taskbar {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_entry_width: 200,
+            show_app_id: false,
+        }
+    }
+}
+
+// Horizontal span of drawn entry, used to find what was clicked
+#[derive(Clone)]
+pub struct TaskbarEntry {
+    start: i32,
+    end: i32,
+    handle: ZwlrForeignToplevelHandleV1,
+}
+
+/// Index of entry that is step entries away from active one, wrapping around
+pub fn cycle_index(length: usize, active: Option<usize>, step: i32) -> Option<usize> {
+    if length == 0 {
+        return None;
+    }
+    let length = length as i32;
+    let index = match active {
+        Some(active) => (active as i32 + step).rem_euclid(length),
+        // Nothing is active yet, so start from either end
+        None if step > 0 => 0,
+        None => length - 1,
+    };
+    Some(index as usize)
+}
+
+// Longest text of an entry for count entries to fit into space, padding is
+// what decoration adds around the text
+fn text_limit(max_entry_width: i32, space: i32, count: usize, padding: i32) -> i32 {
+    max_entry_width.min(space / count.max(1) as i32 - padding)
+}
+
+impl Bar {
+    /// Draws entries starting at offset into space pixels and returns their
+    /// total width and spans. Entries shrink to share the space
+    pub fn draw_taskbar(
+        &self,
+        cr: &cr::Context,
+        pg_layout: &pango::Layout,
+        offset: i32,
+        space: i32,
+        height: i32,
+    ) -> (i32, Vec<TaskbarEntry>) {
+        let theme = &self.theme;
+        let decoration = &self.decorations.title;
+        let mut entries = Vec::new();
+        let toplevels: Vec<_> = self
+            .toplevels
+            .iter()
+            .filter(|toplevel| toplevel.tags & self.focused_tag != 0)
+            .collect();
+        let text_limit = text_limit(
+            self.taskbar.max_entry_width,
+            space,
+            toplevels.len(),
+            decoration.outer_width(0),
+        );
+        // Not even ellipsis fits
+        if text_limit <= 0 {
+            return (0, entries);
+        }
+
+        pg_layout.set_attributes(None);
+        pg_layout.set_width(text_limit * pango::SCALE);
+        pg_layout.set_ellipsize(pango::EllipsizeMode::End);
+        let mut x = offset;
+        for toplevel in toplevels {
+            let label = if self.taskbar.show_app_id || toplevel.title.is_empty() {
+                &toplevel.app_id
+            } else {
+                &toplevel.title
+            };
+            pg_layout.set_text(label);
+            let (text_width, text_height) = pg_layout.pixel_size();
+            let entry_width = decoration.outer_width(text_width);
+            let (entry_bg, entry_fg) = if toplevel.activated {
+                (theme.focused, theme.focused_fg)
+            } else {
+                (theme.widget_bg, theme.title_fg)
+            };
+            decoration.draw_background(cr, x as f64, entry_width as f64, height as f64, &entry_bg);
+            entry_fg.set_source(cr);
+            cr.move_to(
+                (x + decoration.padding) as f64,
+                (height - text_height) as f64 / 2.,
+            );
+            pangocairo::show_layout(cr, pg_layout);
+            entries.push(TaskbarEntry {
+                start: x,
+                end: x + entry_width,
+                handle: toplevel.handle.clone(),
+            });
+            x += entry_width;
+        }
+        // Layout is shared with the rest of the bar
+        pg_layout.set_width(-1);
+        pg_layout.set_ellipsize(pango::EllipsizeMode::None);
+        (x - offset, entries)
+    }
+
+    // Entries drawn last time on the output under pointer
+    fn pointer_taskbar_entries(&self) -> Vec<TaskbarEntry> {
+        let Some((output_index, _)) = self.pointer_output() else {
+            return Vec::new();
+        };
+        self.outputs_contexts
+            .lock()
+            .unwrap()
+            .get(output_index)
+            .map_or_else(Vec::new, |context| context.taskbar_entries.clone())
+    }
+
+    fn taskbar_entry_at(&self, x: f64) -> Option<TaskbarEntry> {
+        let x = x as i32;
+        self.pointer_taskbar_entries()
+            .into_iter()
+            .find(|entry| entry.start <= x && x < entry.end)
+    }
+
+    pub fn taskbar_button(&self, x: f64, button: u32) {
+        let Some(entry) = self.taskbar_entry_at(x) else {
+            return;
+        };
+        match button {
+            BTN_LEFT => {
+                if let Some(seat) = &self.seat {
+                    entry.handle.activate(seat);
+                }
+            }
+            BTN_MIDDLE => entry.handle.close(),
+            _ => (),
+        }
+    }
+
+    pub fn taskbar_scroll(&mut self, x: f64, value: f64) {
+        if self.taskbar_entry_at(x).is_none() {
+            return;
+        }
+        self.taskbar_scroll += value;
+        if self.taskbar_scroll.abs() < SCROLL_STEP {
+            return;
+        }
+        let step = if self.taskbar_scroll > 0. { 1 } else { -1 };
+        self.taskbar_scroll = 0.;
+
+        let entries = self.pointer_taskbar_entries();
+        let active = entries.iter().position(|entry| {
+            self.toplevels
+                .iter()
+                .any(|toplevel| toplevel.handle == entry.handle && toplevel.activated)
+        });
+        let Some(next) = cycle_index(entries.len(), active, step) else {
+            return;
+        };
+        if let Some(seat) = &self.seat {
+            entries[next].handle.activate(seat);
+        }
+    }
+}
+
+#[test]
+fn test_cycle_index() {
+    assert_eq!(cycle_index(0, None, 1), None);
+    assert_eq!(cycle_index(3, Some(2), 1), Some(0));
+    assert_eq!(cycle_index(3, Some(0), -1), Some(2));
+    assert_eq!(cycle_index(3, None, 1), Some(0));
+    assert_eq!(cycle_index(3, None, -1), Some(2));
+}
+
+#[test]
+fn test_text_limit() {
+    // Few entries keep their width
+    assert_eq!(text_limit(200, 1000, 2, 16), 200);
+    // Six entries share the space left for them
+    assert_eq!(text_limit(200, 900, 6, 16), 134);
+    assert!(6 * (text_limit(200, 900, 6, 16) + 16) <= 900);
+    assert!(text_limit(200, 100, 10, 16) <= 0);
+}
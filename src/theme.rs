@@ -0,0 +1,154 @@
+use pangocairo::cairo as cr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
+    pub a: f64,
+}
+
+impl Color {
+    pub const fn rgba(r: f64, g: f64, b: f64, a: f64) -> Self {
+        Self { r, g, b, a }
+    }
+
+    /// Builds color from 0xRRGGBBAA
+    pub const fn hex(rgba: u32) -> Self {
+        Self {
+            r: ((rgba >> 24) & 0xff) as f64 / 255.,
+            g: ((rgba >> 16) & 0xff) as f64 / 255.,
+            b: ((rgba >> 8) & 0xff) as f64 / 255.,
+            a: (rgba & 0xff) as f64 / 255.,
+        }
+    }
+
+    /// Parses "#rgb", "#rrggbb" and "#rrggbbaa" strings
+    pub fn parse(color: &str) -> Option<Self> {
+        let digits = color.strip_prefix('#').unwrap_or(color);
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let value = u32::from_str_radix(digits, 16).ok()?;
+        match digits.len() {
+            3 => {
+                let (r, g, b) = ((value >> 8) & 0xf, (value >> 4) & 0xf, value & 0xf);
+                Some(Self::hex((r * 0x11) << 24 | (g * 0x11) << 16 | (b * 0x11) << 8 | 0xff))
+            }
+            6 => Some(Self::hex(value << 8 | 0xff)),
+            8 => Some(Self::hex(value)),
+            _ => None,
+        }
+    }
+
+    pub fn with_alpha(self, a: f64) -> Self {
+        Self { a, ..self }
+    }
+
+    pub fn set_source(&self, cr: &cr::Context) {
+        cr.set_source_rgba(self.r, self.g, self.b, self.a);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Theme {
+    pub bg: Color,
+    pub fg: Color,
+    pub focused: Color,
+    pub focused_fg: Color,
+    pub occupied: Color,
+    pub occupied_fg: Color,
+    pub urgent: Color,
+    pub urgent_fg: Color,
+    pub title_fg: Color,
+    pub widget_bg: Color,
+    pub accent: Color,
+}
+
+impl Default for Theme {
+    // Colors that were hardcoded before themes were introduced
+    fn default() -> Self {
+        Self {
+            bg: Color::hex(0x000000ff),
+            fg: Color::hex(0xffffffff),
+            focused: Color::hex(0x0000ffff),
+            focused_fg: Color::hex(0x000000ff),
+            occupied: Color::hex(0xffffffff),
+            occupied_fg: Color::hex(0x000000ff),
+            urgent: Color::hex(0xff0000ff),
+            urgent_fg: Color::hex(0x000000ff),
+            title_fg: Color::hex(0x00ff00ff),
+            widget_bg: Color::hex(0x000000ff),
+            accent: Color::hex(0xff0000ff),
+        }
+    }
+}
+
+impl Theme {
+    pub const BUILTIN: [&'static str; 4] = ["default", "gruvbox", "nord", "transparent"];
+
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(Self::default()),
+            "gruvbox" => Some(Self {
+                bg: Color::hex(0x282828ff),
+                fg: Color::hex(0xebdbb2ff),
+                focused: Color::hex(0xd79921ff),
+                focused_fg: Color::hex(0x282828ff),
+                occupied: Color::hex(0x504945ff),
+                occupied_fg: Color::hex(0xebdbb2ff),
+                urgent: Color::hex(0xcc241dff),
+                urgent_fg: Color::hex(0xfbf1c7ff),
+                title_fg: Color::hex(0xb8bb26ff),
+                widget_bg: Color::hex(0x3c3836ff),
+                accent: Color::hex(0xfe8019ff),
+            }),
+            "nord" => Some(Self {
+                bg: Color::hex(0x2e3440ff),
+                fg: Color::hex(0xd8dee9ff),
+                focused: Color::hex(0x88c0d0ff),
+                focused_fg: Color::hex(0x2e3440ff),
+                occupied: Color::hex(0x4c566aff),
+                occupied_fg: Color::hex(0xeceff4ff),
+                urgent: Color::hex(0xbf616aff),
+                urgent_fg: Color::hex(0xeceff4ff),
+                title_fg: Color::hex(0xa3be8cff),
+                widget_bg: Color::hex(0x3b4252ff),
+                accent: Color::hex(0xebcb8bff),
+            }),
+            "transparent" => Some(Self {
+                bg: Color::hex(0x00000080),
+                fg: Color::hex(0xffffffff),
+                focused: Color::hex(0xffffff40),
+                focused_fg: Color::hex(0xffffffff),
+                occupied: Color::hex(0x00000000),
+                occupied_fg: Color::hex(0xbbbbbbff),
+                urgent: Color::hex(0xff555580),
+                urgent_fg: Color::hex(0xffffffff),
+                title_fg: Color::hex(0xffffffff),
+                widget_bg: Color::hex(0x00000000),
+                accent: Color::hex(0xffffffff),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Fills rectangle replacing whatever was in the buffer, so translucent backgrounds don't stack up
+pub fn clear_rectangle(cr: &cr::Context, color: &Color, x: f64, y: f64, width: f64, height: f64) {
+    cr.save().unwrap();
+    cr.set_operator(cr::Operator::Source);
+    color.set_source(cr);
+    cr.rectangle(x, y, width, height);
+    cr.fill().unwrap();
+    cr.restore().unwrap();
+}
+
+#[test]
+fn test_color_parse() {
+    assert_eq!(Color::parse("#ff0000"), Some(Color::rgba(1., 0., 0., 1.)));
+    assert_eq!(Color::parse("00ff0000"), Some(Color::rgba(0., 1., 0., 0.)));
+    assert_eq!(Color::parse("#fff"), Some(Color::rgba(1., 1., 1., 1.)));
+    assert_eq!(Color::parse("#12345"), None);
+    assert_eq!(Color::parse("#gg0000"), None);
+}
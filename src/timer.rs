@@ -0,0 +1,71 @@
+// Timerfd shared by the blocks scheduler, clock watch and tooltips. Times
+// passed to set are absolute on the timer's clock with TFD_TIMER_ABSTIME,
+// relative to now without it. Zero disarms the timer
+
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    time::Duration,
+};
+
+pub(crate) struct TimerFd(OwnedFd);
+
+impl TimerFd {
+    pub fn new(clock: libc::clockid_t) -> io::Result<Self> {
+        let fd = unsafe { libc::timerfd_create(clock, libc::TFD_CLOEXEC | libc::TFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+
+    /// One shot at time, flags are TFD_TIMER_* ones
+    pub fn set(&self, time: Duration, flags: libc::c_int) {
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: time.as_secs() as libc::time_t,
+                tv_nsec: time.subsec_nanos() as libc::c_long,
+            },
+        };
+        unsafe { libc::timerfd_settime(self.fd(), flags, &spec, std::ptr::null_mut()) };
+    }
+
+    /// Consumes expirations so poll doesn't wake up again. Fails with
+    /// WouldBlock if the timer didn't expire and ECANCELED if the clock was set
+    pub fn read(&self) -> io::Result<u64> {
+        let mut expirations: u64 = 0;
+        let result = unsafe {
+            libc::read(
+                self.fd(),
+                &mut expirations as *mut u64 as *mut libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(expirations)
+    }
+}
+
+#[test]
+fn test_timer_fd() {
+    let timer = TimerFd::new(libc::CLOCK_MONOTONIC).unwrap();
+    assert_eq!(timer.read().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    timer.set(Duration::from_millis(5), 0);
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(timer.read().unwrap(), 1);
+    // Disarmed timer doesn't expire
+    timer.set(Duration::from_millis(5), 0);
+    timer.set(Duration::ZERO, 0);
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(timer.read().is_err());
+}
@@ -0,0 +1,363 @@
+// Tooltips shown under tags and blocks after pointer rests on them.
+// Blocks thread publishes spans of its blocks with tooltip text or command in output context,
+// tags are known to main thread
+
+use std::{
+    io::{self, Read},
+    os::fd::RawFd,
+    process::{Command, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use pangocairo::cairo as cr;
+use pangocairo::pango;
+use wayland_client::QueueHandle;
+
+use crate::popup::{self, Content};
+use crate::render;
+use crate::theme::{self, Theme};
+use crate::timer::TimerFd;
+use crate::Bar;
+
+const PADDING: i32 = 6;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Tooltips {
+    pub enabled: bool,
+    // How long pointer has to stay over widget
+    pub delay: Duration,
+}
+
+impl Default for Tooltips {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            delay: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Hover {
+    Tag(u32),
+    // Blocks are identified by their left edge
+    Block(i32),
+}
+
+/// One shot timer that becomes readable when tooltip should be shown,
+/// and again when tooltip command run for it has finished
+pub(crate) struct Timer {
+    // Shared with threads of tooltip commands
+    fd: Arc<TimerFd>,
+    job: Option<Job>,
+}
+
+// Tooltip command running in it's own thread for hovered block
+struct Job {
+    hover: (usize, Hover),
+    output: Arc<Mutex<Option<String>>>,
+}
+
+impl Timer {
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            fd: Arc::new(TimerFd::new(libc::CLOCK_MONOTONIC)?),
+            job: None,
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd.fd()
+    }
+
+    pub fn arm(&self, delay: Duration) {
+        // Zero would disarm the timer
+        self.fd.set(delay.max(Duration::from_nanos(1)), 0);
+    }
+
+    /// Stops the delay. Output of a running command isn't waited for anymore
+    pub fn disarm(&mut self) {
+        self.fd.set(Duration::ZERO, 0);
+        self.job = None;
+    }
+
+    /// Consumes expiration so poll doesn't wake up again
+    pub fn clear(&self) {
+        let _ = self.fd.read();
+    }
+
+    /// Output of the tooltip command of hovered block. The first call starts
+    /// the command and returns None, timer fires again once output is there
+    fn command_output(
+        &mut self,
+        hover: (usize, Hover),
+        command: Arc<Mutex<Command>>,
+    ) -> Option<String> {
+        if let Some(job) = self.job.as_ref().filter(|job| job.hover == hover) {
+            let output = job.output.lock().unwrap().take();
+            if output.is_some() {
+                self.job = None;
+            }
+            return output;
+        }
+        let output = Arc::new(Mutex::new(None));
+        let (fd, result) = (Arc::clone(&self.fd), Arc::clone(&output));
+        // Main thread keeps answering the compositor while command runs
+        std::thread::spawn(move || {
+            let text = run_command(&command, COMMAND_TIMEOUT);
+            *result.lock().unwrap() = Some(text);
+            fd.set(Duration::from_nanos(1), 0);
+        });
+        self.job = Some(Job { hover, output });
+        None
+    }
+}
+
+/// Size of popup needed to show the text
+pub fn size(text: &str) -> (i32, i32) {
+    let cr = popup::measure_context();
+    let pg_layout = render::create_layout(&cr);
+    pg_layout.set_text(text);
+    let (width, height) = pg_layout.pixel_size();
+    (width + PADDING * 2, height + PADDING * 2)
+}
+
+pub fn draw(
+    cr: &cr::Context,
+    pg_layout: &pango::Layout,
+    theme: &Theme,
+    text: &str,
+    (width, height): (i32, i32),
+) {
+    theme::clear_rectangle(cr, &theme.bg, 0., 0., width as f64, height as f64);
+    pg_layout.set_text(text);
+    theme.fg.set_source(cr);
+    cr.move_to(PADDING as f64, PADDING as f64);
+    pangocairo::show_layout(cr, pg_layout);
+}
+
+// Command tooltips are run only when shown, e.g. cal doesn't need to run every second.
+// Commands that don't finish in time are killed. Output should fit into pipe buffer
+fn run_command(command: &Mutex<Command>, timeout: Duration) -> String {
+    let child = command.lock().unwrap().stdout(Stdio::piped()).spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            log::warn!("Tooltip command failed: {err}");
+            return String::new();
+        }
+    };
+    let deadline = Instant::now() + timeout;
+    while let Ok(None) = child.try_wait() {
+        if Instant::now() >= deadline {
+            log::warn!("Tooltip command didn't finish in {timeout:?}");
+            let _ = child.kill();
+            let _ = child.wait();
+            return String::new();
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let mut output = String::new();
+    if let Some(mut stdout) = child.stdout.take() {
+        let _ = stdout.read_to_string(&mut output);
+    }
+    output.trim_end().to_string()
+}
+
+impl Bar {
+    /// Titles of views on the tag, one per line
+    pub fn tag_tooltip(&self, tag: u32) -> String {
+        let titles: Vec<&str> = self
+            .toplevels
+            .iter()
+            .filter(|toplevel| toplevel.tags & tag != 0)
+            .map(|toplevel| toplevel.title.as_str())
+            .collect();
+        titles.join("\n")
+    }
+
+    /// Widget under pointer on the bar and it's span
+    fn hover_at(&self, x: f64) -> Option<(usize, Hover, i32, i32)> {
+        let (output_index, _) = self.pointer_output()?;
+        let x = x as i32;
+        let outputs_contexts = self.outputs_contexts.lock().unwrap();
+        let output_context = outputs_contexts.get(output_index)?;
+        if let Some(&(start, end, tag)) = output_context
+            .tag_spans
+            .iter()
+            .find(|(start, end, _)| (*start..*end).contains(&x))
+        {
+            return Some((output_index, Hover::Tag(tag), start, end));
+        }
+        output_context
+            .block_spans
+            .iter()
+            .find(|span| span.has_tooltip() && (span.start..span.end).contains(&x))
+            .map(|span| (output_index, Hover::Block(span.start), span.start, span.end))
+    }
+
+    /// Restarts tooltip delay when pointer moved to other widget
+    pub fn update_hover(&mut self) {
+        if !self.tooltips.enabled {
+            return;
+        }
+        let hovered = self
+            .hover_at(self.pointer_x)
+            .map(|(output_index, hover, _, _)| (output_index, hover));
+        if hovered == self.hovered {
+            return;
+        }
+        self.hide_tooltip();
+        self.hovered = hovered;
+        if let (Some(_), Some(timer)) = (hovered, &self.tooltip_timer) {
+            timer.arm(self.tooltips.delay);
+        }
+    }
+
+    pub fn hide_tooltip(&mut self) {
+        if let Some(timer) = &mut self.tooltip_timer {
+            timer.disarm();
+        }
+        let is_tooltip = matches!(
+            self.popup.as_ref().map(|popup| &popup.content),
+            Some(Content::Tooltip(_))
+        );
+        if is_tooltip {
+            self.close_popup();
+        }
+    }
+
+    /// Called when delay has passed
+    pub fn show_tooltip(&mut self, qh: &QueueHandle<Self>) {
+        if let Some(timer) = &self.tooltip_timer {
+            timer.clear();
+        }
+        // Menus are more important
+        if self.popup.is_some() {
+            return;
+        }
+        let Some((output_index, hover, start, end)) = self.hover_at(self.pointer_x) else {
+            return;
+        };
+        if self.hovered != Some((output_index, hover)) {
+            return;
+        }
+        let text = match hover {
+            Hover::Tag(tag) => self.tag_tooltip(tag),
+            Hover::Block(_) => {
+                let span = self.outputs_contexts.lock().unwrap()[output_index]
+                    .block_spans
+                    .iter()
+                    .find(|span| span.start == start)
+                    .cloned();
+                match span {
+                    Some(span) if span.tooltip.is_empty() => {
+                        let (Some(command), Some(timer)) =
+                            (span.tooltip_command, &mut self.tooltip_timer)
+                        else {
+                            return;
+                        };
+                        // Shown when the command is done
+                        match timer.command_output((output_index, hover), command) {
+                            Some(text) => text,
+                            None => return,
+                        }
+                    }
+                    Some(span) => span.tooltip,
+                    None => String::new(),
+                }
+            }
+        };
+        if text.is_empty() {
+            return;
+        }
+        let size = size(&text);
+        self.open_popup(
+            output_index,
+            start,
+            end - start,
+            size,
+            None,
+            Content::Tooltip(text),
+            qh,
+        );
+    }
+}
+
+#[test]
+fn test_timer_fires_once() {
+    let mut timer = Timer::new().unwrap();
+    let mut pfd = libc::pollfd {
+        fd: timer.fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    timer.arm(Duration::from_millis(5));
+    assert_eq!(unsafe { libc::poll(&mut pfd, 1, 1000) }, 1);
+    timer.clear();
+    assert_eq!(unsafe { libc::poll(&mut pfd, 1, 20) }, 0);
+
+    timer.arm(Duration::from_millis(5));
+    timer.disarm();
+    assert_eq!(unsafe { libc::poll(&mut pfd, 1, 20) }, 0);
+}
+
+#[test]
+fn test_command_runs_only_when_shown() {
+    use crate::blocks::{Block, Source, TooltipSource};
+    let marker = std::env::temp_dir().join(format!("tagsandblocks-tooltip-{}", std::process::id()));
+    let _ = std::fs::remove_file(&marker);
+    let mut command = Command::new("sh");
+    command.args([
+        "-c",
+        "touch \"$0\" && echo tooltip",
+        marker.to_str().unwrap(),
+    ]);
+    let tooltip = TooltipSource::command(command);
+    let mut block = Block::new("date", Source::Command(Command::new("true"))).with_tooltip(tooltip);
+    block.refresh();
+    block.refresh();
+    assert!(!marker.exists());
+
+    let TooltipSource::Command(command) = &block.tooltip else {
+        unreachable!();
+    };
+    assert_eq!(run_command(command, COMMAND_TIMEOUT), "tooltip");
+    assert!(marker.exists());
+    std::fs::remove_file(&marker).unwrap();
+}
+
+#[test]
+fn test_hung_command_is_killed() {
+    let mut command = Command::new("sleep");
+    command.arg("10");
+    let start = Instant::now();
+    assert_eq!(
+        run_command(&Mutex::new(command), Duration::from_millis(50)),
+        ""
+    );
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_command_output_arrives_later() {
+    let mut timer = Timer::new().unwrap();
+    let mut pfd = libc::pollfd {
+        fd: timer.fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let mut command = Command::new("echo");
+    command.arg("tooltip");
+    let command = Arc::new(Mutex::new(command));
+    let hover = (0, Hover::Block(100));
+    assert_eq!(timer.command_output(hover, Arc::clone(&command)), None);
+    // Timer fires once the command is done
+    assert_eq!(unsafe { libc::poll(&mut pfd, 1, 5000) }, 1);
+    timer.clear();
+    assert_eq!(
+        timer.command_output(hover, command).as_deref(),
+        Some("tooltip")
+    );
+}
@@ -0,0 +1,97 @@
+use wayland_client::{event_created_child, Connection, Dispatch, QueueHandle};
+use wayland_protocols_wlr::foreign_toplevel::v1::client::{
+    zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+    zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+};
+
+use crate::Bar;
+
+pub struct Toplevel {
+    pub handle: ZwlrForeignToplevelHandleV1,
+    pub title: String,
+    pub app_id: String,
+    pub activated: bool,
+    // Protocol doesn't tell on which tags view is, so tags are remembered
+    // from focused tags at the moment view was activated
+    pub tags: u32,
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for Bar {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrForeignToplevelManagerV1,
+        event: zwlr_foreign_toplevel_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } => {
+                state.toplevels.push(Toplevel {
+                    handle: toplevel,
+                    title: String::new(),
+                    app_id: String::new(),
+                    activated: false,
+                    tags: 0,
+                });
+            }
+            zwlr_foreign_toplevel_manager_v1::Event::Finished => {
+                state.foreign_toplevel_manager = None;
+            }
+            _ => (),
+        }
+    }
+
+    event_created_child!(Bar, ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for Bar {
+    fn event(
+        state: &mut Self,
+        handle: &ZwlrForeignToplevelHandleV1,
+        event: zwlr_foreign_toplevel_handle_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use zwlr_foreign_toplevel_handle_v1::Event;
+        let Some(index) = state
+            .toplevels
+            .iter()
+            .position(|toplevel| &toplevel.handle == handle)
+        else {
+            return;
+        };
+        let toplevel = &mut state.toplevels[index];
+        match event {
+            Event::Title { title } => toplevel.title = title,
+            Event::AppId { app_id } => toplevel.app_id = app_id,
+            Event::State { state: states } => {
+                let activated = zwlr_foreign_toplevel_handle_v1::State::Activated as u32;
+                toplevel.activated = states
+                    .chunks_exact(4)
+                    .map(|bytes_4| u32::from_ne_bytes(bytes_4.try_into().unwrap()))
+                    .any(|state| state == activated);
+            }
+            Event::Done => {
+                // New views usually open on focused tags, so it's the best guess until activation
+                if toplevel.activated || toplevel.tags == 0 {
+                    toplevel.tags = state.focused_tag;
+                }
+                if state.tags_widget.labels.show_app_icons || state.taskbar.enabled {
+                    state.draw_tags_and_title();
+                }
+            }
+            Event::Closed => {
+                handle.destroy();
+                state.toplevels.remove(index);
+                if state.tags_widget.labels.show_app_icons || state.taskbar.enabled {
+                    state.draw_tags_and_title();
+                }
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,1143 @@
+// System tray: StatusNotifierWatcher and StatusNotifierHost over D-Bus.
+// If some other watcher already runs (e.g. another bar), the bar only registers as a host.
+// See https://freedesktop.org/wiki/Specifications/StatusNotifierItem/
+
+use std::{collections::HashMap, io, os::fd::RawFd, path::PathBuf};
+
+use pangocairo::cairo as cr;
+use wayland_client::QueueHandle;
+
+use crate::dbus::{self, Message, Value};
+use crate::menu::{Menu, MenuItem, MenuSource};
+use crate::popup::Content;
+use crate::theme;
+use crate::Bar;
+
+const WATCHER_NAME: &str = "org.kde.StatusNotifierWatcher";
+const WATCHER_PATH: &str = "/StatusNotifierWatcher";
+const ITEM_INTERFACE: &str = "org.kde.StatusNotifierItem";
+const ITEM_PATH: &str = "/StatusNotifierItem";
+const MENU_INTERFACE: &str = "com.canonical.dbusmenu";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+// linux/input-event-codes.h
+const BTN_LEFT: u32 = 0x110;
+const BTN_RIGHT: u32 = 0x111;
+const BTN_MIDDLE: u32 = 0x112;
+
+pub struct TrayItem {
+    // Name that item registered with, may be well-known or unique one
+    pub service: String,
+    // Unique name of the owner, signals come from it
+    owner: String,
+    pub path: String,
+    pub title: String,
+    pub icon: Option<cr::ImageSurface>,
+    pub menu: Option<String>,
+    // Item only supports context menu, so activation should show it too
+    pub item_is_menu: bool,
+    // Passive items ask not to be shown
+    pub passive: bool,
+}
+
+impl TrayItem {
+    // Identifier used in watcher signals and RegisteredStatusNotifierItems
+    fn id(&self) -> String {
+        format!("{}{}", self.service, self.path)
+    }
+}
+
+/// Where the menu was asked for, popup is opened there once the layout arrives
+pub struct MenuAnchor {
+    pub output_index: usize,
+    pub x: i32,
+    pub width: i32,
+    // Serial of the button press that grabs the popup
+    pub serial: u32,
+}
+
+// Calls whose replies are awaited. Items may be slow or stuck, so the bar
+// never blocks on them and handles replies as they come
+enum Pending {
+    // RegisteredStatusNotifierItems of another watcher
+    RegisteredItems,
+    // Unique name of item service, item is added after it
+    Owner {
+        service: String,
+        path: String,
+    },
+    // GetAll of item properties, item may be gone by the time they come
+    Properties {
+        id: String,
+    },
+    // GetLayout of item menu
+    Layout {
+        service: String,
+        path: String,
+        item_path: String,
+        anchor: MenuAnchor,
+    },
+}
+
+pub struct Tray {
+    connection: dbus::Connection,
+    // We own org.kde.StatusNotifierWatcher and answer to items ourselves
+    is_watcher: bool,
+    pub items: Vec<TrayItem>,
+    // Name of icon theme for icon lookups, hicolor is always used as a fallback
+    pub icon_theme: String,
+    // By serial of the call
+    pending: HashMap<u32, Pending>,
+    // Size of icons on the bar, set by process
+    icon_size: i32,
+    // Menu that arrived and waits to be opened
+    ready_menu: Option<(Menu, MenuAnchor)>,
+}
+
+/// Splits "service/path" or plain service into both parts
+fn split_item_id(id: &str) -> (String, String) {
+    match id.find('/') {
+        Some(slash) => (id[..slash].to_string(), id[slash..].to_string()),
+        None => (id.to_string(), ITEM_PATH.to_string()),
+    }
+}
+
+/// Removes mnemonic underscores: "_Quit" becomes "Quit", "__" becomes "_"
+fn strip_mnemonic(label: &str) -> String {
+    let mut result = String::with_capacity(label.len());
+    let mut chars = label.chars();
+    while let Some(char) = chars.next() {
+        if char == '_' {
+            if let Some(next) = chars.next() {
+                result.push(next);
+            }
+        } else {
+            result.push(char);
+        }
+    }
+    result
+}
+
+/// Parses (ia{sv}av) node of GetLayout reply
+pub fn parse_menu_item(node: &Value) -> Option<MenuItem> {
+    let [id, properties, children] = node.as_fields()? else {
+        return None;
+    };
+    let property = |name: &str| properties.get(name);
+    let children = children
+        .as_array()?
+        .iter()
+        .filter_map(parse_menu_item)
+        .filter(|child| child.id >= 0)
+        .collect();
+    let visible = property("visible").and_then(Value::as_bool).unwrap_or(true);
+    let toggle = match property("toggle-type").and_then(Value::as_str) {
+        Some("checkmark" | "radio") => property("toggle-state")
+            .and_then(Value::as_i32)
+            .map(|state| state == 1),
+        _ => None,
+    };
+    Some(MenuItem {
+        // Hidden items are dropped by parent, see filter above
+        id: if visible { id.as_i32()? } else { -1 },
+        label: strip_mnemonic(property("label").and_then(Value::as_str).unwrap_or("")),
+        enabled: property("enabled").and_then(Value::as_bool).unwrap_or(true),
+        separator: property("type").and_then(Value::as_str) == Some("separator"),
+        toggle,
+        children,
+    })
+}
+
+/// Converts IconPixmap (ARGB in network byte order) into cairo surface
+fn pixmap_to_surface(width: i32, height: i32, argb: &[u8]) -> Option<cr::ImageSurface> {
+    if width <= 0 || height <= 0 || argb.len() != (width * height * 4) as usize {
+        return None;
+    }
+    let mut data = Vec::with_capacity(argb.len());
+    for pixel in argb.chunks_exact(4) {
+        let alpha = pixel[0] as u32;
+        let premultiply = |channel: u8| channel as u32 * alpha / 255;
+        let value = (alpha << 24)
+            | (premultiply(pixel[1]) << 16)
+            | (premultiply(pixel[2]) << 8)
+            | premultiply(pixel[3]);
+        data.extend_from_slice(&value.to_ne_bytes());
+    }
+    cr::ImageSurface::create_for_data(data, cr::Format::ARgb32, width, height, width * 4).ok()
+}
+
+/// Picks the smallest pixmap that is at least size, or the biggest one
+fn pick_pixmap(pixmaps: &[Value], size: i32) -> Option<cr::ImageSurface> {
+    let pixmaps: Vec<(i32, i32, Vec<u8>)> = pixmaps
+        .iter()
+        .filter_map(|pixmap| {
+            let [width, height, data] = pixmap.as_fields()? else {
+                return None;
+            };
+            Some((width.as_i32()?, height.as_i32()?, data.as_bytes()?))
+        })
+        .collect();
+    let best = pixmaps
+        .iter()
+        .filter(|(width, _, _)| *width >= size)
+        .min_by_key(|(width, _, _)| *width)
+        .or_else(|| pixmaps.iter().max_by_key(|(width, _, _)| *width))?;
+    pixmap_to_surface(best.0, best.1, &best.2)
+}
+
+/// Directories with icon themes in XDG order
+fn icon_directories() -> Vec<PathBuf> {
+    let mut directories = Vec::new();
+    if let Some(home) = std::env::var_os("HOME") {
+        directories.push(PathBuf::from(home).join(".icons"));
+    }
+    match std::env::var_os("XDG_DATA_HOME") {
+        Some(data_home) => directories.push(PathBuf::from(data_home).join("icons")),
+        None => {
+            if let Some(home) = std::env::var_os("HOME") {
+                directories.push(PathBuf::from(home).join(".local/share/icons"));
+            }
+        }
+    }
+    let data_dirs = std::env::var("XDG_DATA_DIRS")
+        .unwrap_or_else(|_| String::from("/usr/local/share:/usr/share"));
+    for data_dir in data_dirs.split(':').filter(|dir| !dir.is_empty()) {
+        directories.push(PathBuf::from(data_dir).join("icons"));
+    }
+    directories
+}
+
+/// Finds png icon by name. Scalable svg icons can't be loaded with cairo, so they are skipped
+pub fn lookup_icon(
+    name: &str,
+    theme_path: Option<&str>,
+    theme: &str,
+    size: i32,
+) -> Option<PathBuf> {
+    if name.starts_with('/') {
+        return Some(PathBuf::from(name));
+    }
+    let file_name = format!("{name}.png");
+    let mut sizes = vec![size, 16, 22, 24, 32, 48, 64, 128, 256];
+    sizes.dedup();
+    let categories = ["apps", "status", "devices", "panel", "actions", "places"];
+
+    let mut roots: Vec<PathBuf> = Vec::new();
+    if let Some(theme_path) = theme_path.filter(|path| !path.is_empty()) {
+        let theme_path = PathBuf::from(theme_path);
+        let direct = theme_path.join(&file_name);
+        if direct.is_file() {
+            return Some(direct);
+        }
+        roots.push(theme_path.join(theme));
+        roots.push(theme_path.join("hicolor"));
+    }
+    for directory in icon_directories() {
+        roots.push(directory.join(theme));
+        roots.push(directory.join("hicolor"));
+    }
+    for root in roots {
+        for size in &sizes {
+            for category in categories {
+                let path = root
+                    .join(format!("{size}x{size}"))
+                    .join(category)
+                    .join(&file_name);
+                if path.is_file() {
+                    return Some(path);
+                }
+            }
+        }
+    }
+    let pixmap = PathBuf::from("/usr/share/pixmaps").join(&file_name);
+    pixmap.is_file().then_some(pixmap)
+}
+
+impl Tray {
+    pub fn connect() -> io::Result<Self> {
+        Self::with_connection(dbus::Connection::session()?)
+    }
+
+    #[cfg(test)]
+    pub fn connect_to(address: &str) -> io::Result<Self> {
+        Self::with_connection(dbus::Connection::connect(address)?)
+    }
+
+    fn with_connection(mut connection: dbus::Connection) -> io::Result<Self> {
+        let is_watcher = connection.request_name(WATCHER_NAME)?;
+        let host_name = format!("org.kde.StatusNotifierHost-{}", std::process::id());
+        connection.request_name(&host_name)?;
+        connection.add_match(&format!("type='signal',interface='{ITEM_INTERFACE}'"))?;
+        connection
+            .add_match("type='signal',sender='org.freedesktop.DBus',member='NameOwnerChanged'")?;
+        let mut tray = Self {
+            connection,
+            is_watcher,
+            items: Vec::new(),
+            icon_theme: String::from("hicolor"),
+            pending: HashMap::new(),
+            icon_size: 0,
+            ready_menu: None,
+        };
+        if !is_watcher {
+            tray.connection.add_match(&format!(
+                "type='signal',interface='{WATCHER_NAME}',path='{WATCHER_PATH}'"
+            ))?;
+            tray.connection.send(&Message::method_call(
+                WATCHER_NAME,
+                WATCHER_PATH,
+                WATCHER_NAME,
+                "RegisterStatusNotifierHost",
+                vec![Value::Str(host_name)],
+            ))?;
+            tray.send_call(
+                Message::method_call(
+                    WATCHER_NAME,
+                    WATCHER_PATH,
+                    PROPERTIES_INTERFACE,
+                    "Get",
+                    vec![
+                        Value::Str(WATCHER_NAME.to_string()),
+                        Value::Str(String::from("RegisteredStatusNotifierItems")),
+                    ],
+                ),
+                Pending::RegisteredItems,
+            );
+        }
+        Ok(tray)
+    }
+
+    // Sends call and remembers what it's reply is for
+    fn send_call(&mut self, message: Message, pending: Pending) {
+        match self.connection.send(&message) {
+            Ok(serial) => {
+                self.pending.insert(serial, pending);
+            }
+            Err(err) => log::warn!(
+                "Can't call {} of {}: {err}",
+                message.member.as_deref().unwrap_or_default(),
+                message.destination.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.connection.fd()
+    }
+
+    pub fn has_queued(&self) -> bool {
+        self.connection.has_queued()
+    }
+
+    pub fn visible_items(&self) -> impl Iterator<Item = (usize, &TrayItem)> {
+        self.items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| !item.passive)
+    }
+
+    /// Handles incoming messages. Returns true if items have changed
+    pub fn process(&mut self, icon_size: i32) -> io::Result<bool> {
+        self.icon_size = icon_size;
+        let mut changed = false;
+        loop {
+            for message in self.connection.read_messages()? {
+                changed |= self.handle(message);
+            }
+            // Sends made while handling could have queued more messages
+            if !self.connection.has_queued() {
+                return Ok(changed);
+            }
+        }
+    }
+
+    fn handle(&mut self, message: Message) -> bool {
+        if let Some(pending) = message
+            .reply_serial
+            .and_then(|serial| self.pending.remove(&serial))
+        {
+            return self.handle_reply(pending, message);
+        }
+        let argument = |index: usize| -> String {
+            message
+                .body
+                .get(index)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        if message.is_signal("org.freedesktop.DBus", "NameOwnerChanged") {
+            // Owner has left the bus
+            if argument(2).is_empty() {
+                let name = argument(0);
+                return self.remove_items(|item| item.service == name || item.owner == name);
+            }
+            return false;
+        }
+        if message.is_signal(WATCHER_NAME, "StatusNotifierItemRegistered") {
+            let (service, path) = split_item_id(&argument(0));
+            return self.add_item(service, path);
+        }
+        if message.is_signal(WATCHER_NAME, "StatusNotifierItemUnregistered") {
+            let id = argument(0);
+            return self.remove_items(|item| item.id() == id);
+        }
+        if message.kind == dbus::SIGNAL && message.interface.as_deref() == Some(ITEM_INTERFACE) {
+            let sender = message.sender.clone().unwrap_or_default();
+            let path = message.path.clone().unwrap_or_default();
+            let Some(index) = self
+                .items
+                .iter()
+                .position(|item| item.owner == sender && item.path == path)
+            else {
+                return false;
+            };
+            // Item is redrawn when it's properties come
+            self.refresh_item(index);
+            return false;
+        }
+        if message.kind == dbus::METHOD_CALL && self.is_watcher {
+            return self.handle_watcher_call(&message);
+        }
+        if message.kind == dbus::METHOD_CALL {
+            let _ = self.connection.send(&Message::error(
+                &message,
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                "Unknown method",
+            ));
+        }
+        false
+    }
+
+    fn handle_reply(&mut self, pending: Pending, reply: Message) -> bool {
+        let error = (reply.kind == dbus::ERROR).then(|| {
+            let text = reply.body.first().and_then(Value::as_str).unwrap_or("");
+            format!(
+                "{}: {text}",
+                reply.error_name.as_deref().unwrap_or_default()
+            )
+        });
+        match pending {
+            Pending::RegisteredItems => {
+                if let Some(err) = error {
+                    log::warn!("Can't get items of tray watcher: {err}");
+                    return false;
+                }
+                let ids: Vec<String> = reply
+                    .body
+                    .first()
+                    .and_then(Value::as_array)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|id| id.as_str().map(String::from))
+                    .collect();
+                let mut changed = false;
+                for id in ids {
+                    let (service, path) = split_item_id(&id);
+                    changed |= self.add_item(service, path);
+                }
+                changed
+            }
+            Pending::Owner { service, path } => {
+                let owner = match error {
+                    Some(_) => service.clone(),
+                    None => reply
+                        .body
+                        .first()
+                        .and_then(Value::as_str)
+                        .unwrap_or(&service)
+                        .to_string(),
+                };
+                self.insert_item(service, owner, path)
+            }
+            Pending::Properties { id } => {
+                if let Some(err) = error {
+                    log::warn!("Can't get properties of tray item {id}: {err}");
+                    return false;
+                }
+                let Some(index) = self.items.iter().position(|item| item.id() == id) else {
+                    return false;
+                };
+                let properties = reply
+                    .body
+                    .into_iter()
+                    .next()
+                    .unwrap_or(Value::Array(String::from("{sv}"), Vec::new()));
+                self.apply_properties(index, &properties);
+                true
+            }
+            Pending::Layout {
+                service,
+                path,
+                item_path,
+                anchor,
+            } => {
+                let root = match error {
+                    Some(err) => {
+                        log::warn!("Can't get menu of tray item {service}: {err}");
+                        None
+                    }
+                    None => reply.body.get(1).and_then(parse_menu_item),
+                };
+                match root {
+                    Some(root) => {
+                        let source = MenuSource::Tray { service, path };
+                        self.ready_menu = Some((Menu::new(source, root.children), anchor));
+                    }
+                    // Item draws menu by itself
+                    None => {
+                        let _ = self.connection.send(&Message::method_call(
+                            &service,
+                            &item_path,
+                            ITEM_INTERFACE,
+                            "ContextMenu",
+                            vec![Value::I32(0), Value::I32(0)],
+                        ));
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    fn handle_watcher_call(&mut self, call: &Message) -> bool {
+        let argument = call
+            .body
+            .first()
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let sender = call.sender.clone().unwrap_or_default();
+        let mut changed = false;
+        let reply = if call.is_call(WATCHER_NAME, "RegisterStatusNotifierItem") {
+            // Some implementations pass object path and expect sender to be used as service
+            let (service, path) = if argument.starts_with('/') {
+                (sender, argument.to_string())
+            } else {
+                (argument.to_string(), ITEM_PATH.to_string())
+            };
+            // Reply first, item is likely waiting for it and won't answer our calls until then
+            let _ = self
+                .connection
+                .send(&Message::method_return(call, Vec::new()));
+            changed = self.add_item(service, path);
+            None
+        } else if call.is_call(WATCHER_NAME, "RegisterStatusNotifierHost") {
+            let _ = self.connection.send(&Message::signal(
+                WATCHER_PATH,
+                WATCHER_NAME,
+                "StatusNotifierHostRegistered",
+                Vec::new(),
+            ));
+            Some(Message::method_return(call, Vec::new()))
+        } else if call.is_call(PROPERTIES_INTERFACE, "Get") {
+            let name = call.body.get(1).and_then(Value::as_str).unwrap_or_default();
+            match self.watcher_property(name) {
+                Some(value) => Some(Message::method_return(
+                    call,
+                    vec![Value::Variant(Box::new(value))],
+                )),
+                None => Some(Message::error(
+                    call,
+                    "org.freedesktop.DBus.Error.UnknownProperty",
+                    "Unknown property",
+                )),
+            }
+        } else if call.is_call(PROPERTIES_INTERFACE, "GetAll") {
+            let properties = [
+                "RegisteredStatusNotifierItems",
+                "IsStatusNotifierHostRegistered",
+                "ProtocolVersion",
+            ]
+            .into_iter()
+            .filter_map(|name| Some((name, self.watcher_property(name)?)))
+            .collect();
+            Some(Message::method_return(call, vec![Value::dict(properties)]))
+        } else if call.is_call("org.freedesktop.DBus.Introspectable", "Introspect") {
+            Some(Message::method_return(
+                call,
+                vec![Value::Str(WATCHER_INTROSPECTION.to_string())],
+            ))
+        } else {
+            Some(Message::error(
+                call,
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                "Unknown method",
+            ))
+        };
+        if let Some(reply) = reply {
+            let _ = self.connection.send(&reply);
+        }
+        changed
+    }
+
+    fn watcher_property(&self, name: &str) -> Option<Value> {
+        match name {
+            "RegisteredStatusNotifierItems" => {
+                let ids: Vec<String> = self.items.iter().map(TrayItem::id).collect();
+                Some(Value::strings(&ids))
+            }
+            "IsStatusNotifierHostRegistered" => Some(Value::Bool(true)),
+            "ProtocolVersion" => Some(Value::I32(0)),
+            _ => None,
+        }
+    }
+
+    fn has_item(&self, service: &str, path: &str) -> bool {
+        self.items
+            .iter()
+            .any(|item| item.service == service && item.path == path)
+    }
+
+    /// Adds item right away if it's service is a unique name,
+    /// otherwise after it's owner is known. Returns true if it was added
+    fn add_item(&mut self, service: String, path: String) -> bool {
+        if self.has_item(&service, &path) {
+            return false;
+        }
+        if service.starts_with(':') {
+            let owner = service.clone();
+            return self.insert_item(service, owner, path);
+        }
+        // Signals of the item come from the owner
+        let message = Message::method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "GetNameOwner",
+            vec![Value::Str(service.clone())],
+        );
+        self.send_call(message, Pending::Owner { service, path });
+        false
+    }
+
+    fn insert_item(&mut self, service: String, owner: String, path: String) -> bool {
+        // Could be registered again while it's owner was asked for
+        if self.has_item(&service, &path) {
+            return false;
+        }
+        self.items.push(TrayItem {
+            service,
+            owner,
+            path,
+            title: String::new(),
+            icon: None,
+            menu: None,
+            item_is_menu: false,
+            passive: false,
+        });
+        let index = self.items.len() - 1;
+        if self.is_watcher {
+            let id = self.items[index].id();
+            let _ = self.connection.send(&Message::signal(
+                WATCHER_PATH,
+                WATCHER_NAME,
+                "StatusNotifierItemRegistered",
+                vec![Value::Str(id)],
+            ));
+        }
+        self.refresh_item(index);
+        true
+    }
+
+    fn remove_items(&mut self, mut predicate: impl FnMut(&TrayItem) -> bool) -> bool {
+        let mut removed = Vec::new();
+        self.items.retain(|item| {
+            let remove = predicate(item);
+            if remove {
+                removed.push(item.id());
+            }
+            !remove
+        });
+        if self.is_watcher {
+            for id in &removed {
+                let _ = self.connection.send(&Message::signal(
+                    WATCHER_PATH,
+                    WATCHER_NAME,
+                    "StatusNotifierItemUnregistered",
+                    vec![Value::Str(id.clone())],
+                ));
+            }
+        }
+        !removed.is_empty()
+    }
+
+    /// Asks for all properties of the item again, they are applied when they come
+    pub fn refresh_item(&mut self, index: usize) {
+        let item = &self.items[index];
+        let message = Message::method_call(
+            &item.service,
+            &item.path,
+            PROPERTIES_INTERFACE,
+            "GetAll",
+            vec![Value::Str(ITEM_INTERFACE.to_string())],
+        );
+        let id = item.id();
+        self.send_call(message, Pending::Properties { id });
+    }
+
+    fn apply_properties(&mut self, index: usize, properties: &Value) {
+        let property = |name: &str| properties.get(name);
+        let status = property("Status")
+            .and_then(Value::as_str)
+            .unwrap_or("Active");
+        let attention = status == "NeedsAttention";
+        let (icon_name, pixmap) = if attention {
+            ("AttentionIconName", "AttentionIconPixmap")
+        } else {
+            ("IconName", "IconPixmap")
+        };
+        let icon_name = property(icon_name)
+            .and_then(Value::as_str)
+            .filter(|name| !name.is_empty())
+            .or_else(|| property("IconName").and_then(Value::as_str));
+        let theme_path = property("IconThemePath").and_then(Value::as_str);
+        // Icons are looked up in bigger size and scaled down on draw
+        let lookup_size = self.icon_size.max(22);
+        let icon = icon_name
+            .and_then(|name| lookup_icon(name, theme_path, &self.icon_theme, lookup_size))
+            .and_then(|path| {
+                let mut file = std::fs::File::open(path).ok()?;
+                cr::ImageSurface::create_from_png(&mut file).ok()
+            })
+            .or_else(|| {
+                let pixmaps = property(pixmap)
+                    .or_else(|| property("IconPixmap"))
+                    .and_then(Value::as_array)?;
+                pick_pixmap(pixmaps, lookup_size)
+            });
+
+        let item = &mut self.items[index];
+        item.passive = status == "Passive";
+        item.title = property("Title")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        item.menu = property("Menu")
+            .and_then(Value::as_str)
+            .filter(|path| *path != "/")
+            .map(String::from);
+        item.item_is_menu = property("ItemIsMenu")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        item.icon = icon;
+    }
+
+    fn call_item(&mut self, index: usize, member: &str, body: Vec<Value>) {
+        let item = &self.items[index];
+        let message = Message::method_call(&item.service, &item.path, ITEM_INTERFACE, member, body);
+        // Reply doesn't matter, so don't wait for it
+        let _ = self.connection.send(&message);
+    }
+
+    /// Asks for menu of the item with dbusmenu GetLayout, it's opened at anchor
+    /// once it comes. Returns false if the item has no menu
+    pub fn request_menu(&mut self, index: usize, anchor: MenuAnchor) -> bool {
+        let item = &self.items[index];
+        let Some(path) = item.menu.clone() else {
+            return false;
+        };
+        let (service, item_path) = (item.service.clone(), item.path.clone());
+        // Some menus are filled only before they are shown, calls are handled in order
+        let _ = self.connection.send(&Message::method_call(
+            &service,
+            &path,
+            MENU_INTERFACE,
+            "AboutToShow",
+            vec![Value::I32(0)],
+        ));
+        let message = Message::method_call(
+            &service,
+            &path,
+            MENU_INTERFACE,
+            "GetLayout",
+            vec![
+                Value::I32(0),
+                Value::I32(-1),
+                Value::Array(String::from("s"), Vec::new()),
+            ],
+        );
+        let pending = Pending::Layout {
+            service,
+            path,
+            item_path,
+            anchor,
+        };
+        self.send_call(message, pending);
+        true
+    }
+
+    pub fn take_menu(&mut self) -> Option<(Menu, MenuAnchor)> {
+        self.ready_menu.take()
+    }
+
+    pub fn menu_clicked(&mut self, service: &str, path: &str, id: i32) {
+        let message = Message::method_call(
+            service,
+            path,
+            MENU_INTERFACE,
+            "Event",
+            vec![
+                Value::I32(id),
+                Value::Str(String::from("clicked")),
+                Value::Variant(Box::new(Value::I32(0))),
+                Value::U32(0),
+            ],
+        );
+        let _ = self.connection.send(&message);
+    }
+}
+
+const WATCHER_INTROSPECTION: &str = r#"<node>
+  <interface name="org.kde.StatusNotifierWatcher">
+    <method name="RegisterStatusNotifierItem"><arg name="service" type="s" direction="in"/></method>
+    <method name="RegisterStatusNotifierHost"><arg name="service" type="s" direction="in"/></method>
+    <property name="RegisteredStatusNotifierItems" type="as" access="read"/>
+    <property name="IsStatusNotifierHostRegistered" type="b" access="read"/>
+    <property name="ProtocolVersion" type="i" access="read"/>
+    <signal name="StatusNotifierItemRegistered"><arg type="s"/></signal>
+    <signal name="StatusNotifierItemUnregistered"><arg type="s"/></signal>
+    <signal name="StatusNotifierHostRegistered"/>
+  </interface>
+</node>"#;
+
+pub fn icon_size(bar_height: i32) -> i32 {
+    bar_height * 3 / 4
+}
+
+impl Bar {
+    /// Draws tray icons at the right edge of the bar over previous_width of old icons.
+    /// Returns their total width and spans measured from the right edge
+    pub fn draw_tray(
+        &self,
+        cr: &cr::Context,
+        width: i32,
+        height: i32,
+        previous_width: i32,
+    ) -> (i32, Vec<(i32, i32, usize)>) {
+        let size = icon_size(height);
+        let spacing = self.decorations.blocks.padding;
+        let icons: Vec<(usize, &cr::ImageSurface)> = match &self.tray {
+            Some(tray) => tray
+                .visible_items()
+                .filter_map(|(index, item)| Some((index, item.icon.as_ref()?)))
+                .collect(),
+            None => Vec::new(),
+        };
+        let tray_width = if icons.is_empty() {
+            0
+        } else {
+            icons.len() as i32 * (spacing + size) + spacing
+        };
+        let cleared_width = tray_width.max(previous_width);
+        theme::clear_rectangle(
+            cr,
+            &self.theme.bg,
+            (width - cleared_width) as f64,
+            0.,
+            cleared_width as f64,
+            height as f64,
+        );
+
+        let mut spans = Vec::new();
+        let mut right = 0;
+        for (index, icon) in icons {
+            let x = width - right - spacing - size;
+            let y = (height - size) / 2;
+            let scale = size as f64 / icon.width().max(icon.height()) as f64;
+            cr.save().unwrap();
+            cr.translate(x as f64, y as f64);
+            cr.scale(scale, scale);
+            cr.set_source_surface(icon, 0., 0.).unwrap();
+            cr.paint().unwrap();
+            cr.restore().unwrap();
+            spans.push((right, right + spacing + size, index));
+            right += spacing + size;
+        }
+        (tray_width, spans)
+    }
+
+    pub fn process_tray(&mut self, qh: &QueueHandle<Self>) {
+        let Some(tray) = &mut self.tray else {
+            return;
+        };
+        let icon_size = self
+            .outputs_contexts
+            .lock()
+            .unwrap()
+            .first()
+            .map_or(0, |context| icon_size(context.height));
+        match tray.process(icon_size) {
+            Ok(true) => self.draw_tags_and_title(),
+            Ok(false) => (),
+            Err(err) => {
+                log::error!("Lost connection to session bus, tray is disabled: {err}");
+                self.tray = None;
+                self.draw_tags_and_title();
+            }
+        }
+        let Some((mut menu, anchor)) = self.tray.as_mut().and_then(Tray::take_menu) else {
+            return;
+        };
+        let size = menu.size();
+        self.open_popup(
+            anchor.output_index,
+            anchor.x,
+            anchor.width,
+            size,
+            Some(anchor.serial),
+            Content::Menu(menu),
+            qh,
+        );
+    }
+
+    // Tray icon on the output at given distance from it's right edge
+    fn tray_span_at(&self, output_index: usize, from_right: i32) -> Option<(i32, i32, usize)> {
+        self.outputs_contexts
+            .lock()
+            .unwrap()
+            .get(output_index)?
+            .tray_spans
+            .iter()
+            .find(|(start, end, _)| *start < from_right && from_right <= *end)
+            .copied()
+    }
+
+    /// Handles press on the bar. Returns false if it wasn't over a tray item
+    pub fn tray_button(&mut self, serial: u32, button: u32) -> bool {
+        let Some((output_index, output_width)) = self.pointer_output() else {
+            return false;
+        };
+        let from_right = output_width - self.pointer_x as i32;
+        let Some((start, end, index)) = self.tray_span_at(output_index, from_right) else {
+            return false;
+        };
+        let Some(tray) = &mut self.tray else {
+            return false;
+        };
+        let Some(item) = tray.items.get(index) else {
+            return false;
+        };
+        let show_menu = match button {
+            BTN_LEFT => item.item_is_menu,
+            BTN_RIGHT => true,
+            _ => false,
+        };
+        if show_menu {
+            let anchor = MenuAnchor {
+                output_index,
+                x: output_width - end,
+                width: end - start,
+                serial,
+            };
+            // Item draws menu by itself
+            if !tray.request_menu(index, anchor) {
+                tray.call_item(index, "ContextMenu", vec![Value::I32(0), Value::I32(0)]);
+            }
+            return true;
+        }
+        match button {
+            BTN_LEFT => tray.call_item(index, "Activate", vec![Value::I32(0), Value::I32(0)]),
+            BTN_MIDDLE => tray.call_item(
+                index,
+                "SecondaryActivate",
+                vec![Value::I32(0), Value::I32(0)],
+            ),
+            _ => (),
+        }
+        true
+    }
+
+    pub fn tray_scroll(&mut self, value: f64) -> bool {
+        let Some((output_index, output_width)) = self.pointer_output() else {
+            return false;
+        };
+        let from_right = output_width - self.pointer_x as i32;
+        let Some((_, _, index)) = self.tray_span_at(output_index, from_right) else {
+            return false;
+        };
+        if let Some(tray) = &mut self.tray {
+            tray.call_item(
+                index,
+                "Scroll",
+                vec![
+                    Value::I32(value as i32),
+                    Value::Str(String::from("vertical")),
+                ],
+            );
+        }
+        true
+    }
+}
+
+#[test]
+fn test_parse_menu_item() {
+    let properties = |label: &str, extra: Vec<(&str, Value)>| {
+        let mut entries = vec![("label", Value::Str(label.to_string()))];
+        entries.extend(extra);
+        Value::dict(entries)
+    };
+    let node = |id: i32, properties: Value, children: Vec<Value>| {
+        Value::Struct(vec![
+            Value::I32(id),
+            properties,
+            Value::Array(
+                String::from("v"),
+                children
+                    .into_iter()
+                    .map(|child| Value::Variant(Box::new(child)))
+                    .collect(),
+            ),
+        ])
+    };
+    let layout = node(
+        0,
+        Value::dict(Vec::new()),
+        vec![
+            node(1, properties("_Open", Vec::new()), Vec::new()),
+            node(
+                2,
+                properties("", vec![("type", Value::Str(String::from("separator")))]),
+                Vec::new(),
+            ),
+            node(
+                3,
+                properties("Hidden", vec![("visible", Value::Bool(false))]),
+                Vec::new(),
+            ),
+            node(
+                4,
+                properties(
+                    "Mute",
+                    vec![
+                        ("toggle-type", Value::Str(String::from("checkmark"))),
+                        ("toggle-state", Value::I32(1)),
+                        ("enabled", Value::Bool(false)),
+                    ],
+                ),
+                Vec::new(),
+            ),
+        ],
+    );
+    let root = parse_menu_item(&layout).unwrap();
+    let labels: Vec<&str> = root
+        .children
+        .iter()
+        .map(|item| item.label.as_str())
+        .collect();
+    assert_eq!(labels, ["Open", "", "Mute"]);
+    assert!(root.children[1].separator);
+    assert_eq!(root.children[2].toggle, Some(true));
+    assert!(!root.children[2].enabled);
+    assert_eq!(strip_mnemonic("Save __as_"), "Save _as");
+    assert_eq!(
+        split_item_id(":1.5/org/ayatana/NotificationItem/nm"),
+        (
+            String::from(":1.5"),
+            String::from("/org/ayatana/NotificationItem/nm")
+        )
+    );
+}
+
+#[test]
+fn test_watcher_with_private_bus() {
+    use std::io::BufRead;
+    use std::process::{Child, Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    // Daemon is stopped even when an assertion fails
+    struct Daemon(Child);
+    impl Drop for Daemon {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    // Private bus, so test doesn't touch user session
+    let mut daemon = Daemon(
+        Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("dbus-daemon is needed to test the tray"),
+    );
+    let mut address = String::new();
+    std::io::BufReader::new(daemon.0.stdout.take().unwrap())
+        .read_line(&mut address)
+        .unwrap();
+    let address = address.trim().to_string();
+
+    let mut tray = Tray::connect_to(&address).unwrap();
+    assert!(tray.is_watcher);
+
+    // Item that answers GetAll and leaves when told to
+    let item_address = address.clone();
+    let (leave, left) = std::sync::mpsc::channel();
+    let item = std::thread::spawn(move || {
+        let mut connection = dbus::Connection::connect(&item_address).unwrap();
+        connection
+            .call(Message::method_call(
+                WATCHER_NAME,
+                WATCHER_PATH,
+                WATCHER_NAME,
+                "RegisterStatusNotifierItem",
+                vec![Value::Str(ITEM_PATH.to_string())],
+            ))
+            .unwrap();
+        loop {
+            for message in connection.read_messages().unwrap() {
+                if message.is_call(PROPERTIES_INTERFACE, "GetAll") {
+                    let pixmap = Value::Struct(vec![
+                        Value::I32(1),
+                        Value::I32(1),
+                        Value::Array(
+                            String::from("y"),
+                            [255, 255, 0, 0].map(Value::Byte).to_vec(),
+                        ),
+                    ]);
+                    let properties = Value::dict(vec![
+                        ("Status", Value::Str(String::from("Active"))),
+                        ("Title", Value::Str(String::from("Test"))),
+                        ("Menu", Value::ObjectPath(String::from("/Menu"))),
+                        (
+                            "IconPixmap",
+                            Value::Array(String::from("(iiay)"), vec![pixmap]),
+                        ),
+                    ]);
+                    connection
+                        .send(&Message::method_return(&message, vec![properties]))
+                        .unwrap();
+                    left.recv().unwrap();
+                    return;
+                }
+            }
+        }
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while tray.items.is_empty() || tray.items[0].icon.is_none() {
+        assert!(Instant::now() < deadline, "item wasn't registered");
+        tray.process(16).unwrap();
+    }
+    assert!(tray.pending.is_empty());
+    assert_eq!(tray.items[0].title, "Test");
+    assert_eq!(tray.items[0].path, ITEM_PATH);
+    assert_eq!(tray.items[0].menu.as_deref(), Some("/Menu"));
+    assert_eq!(
+        tray.watcher_property("RegisteredStatusNotifierItems")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // Item's connection is closed, so it's removed
+    leave.send(()).unwrap();
+    item.join().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !tray.items.is_empty() {
+        assert!(Instant::now() < deadline, "item wasn't removed");
+        tray.process(16).unwrap();
+    }
+}
@@ -1,12 +1,12 @@
 use crate::Bar;
 use wayland_client::{
     protocol::{wl_keyboard, wl_seat},
-    Connection, Dispatch, QueueHandle, WEnum,
+    Connection, Dispatch, Proxy, QueueHandle, WEnum,
 };
 
 impl Dispatch<wl_seat::WlSeat, ()> for Bar {
     fn event(
-        _: &mut Self,
+        state: &mut Self,
         seat: &wl_seat::WlSeat,
         event: wl_seat::Event,
         _: &(),
@@ -17,8 +17,36 @@ impl Dispatch<wl_seat::WlSeat, ()> for Bar {
             capabilities: WEnum::Value(capabilities),
         } = event
         {
-            if capabilities.contains(wl_seat::Capability::Keyboard) {
-                seat.get_keyboard(qh, ());
+            // Capabilities are sent again when devices are plugged in or out,
+            // devices that are already there keep their objects
+            if !capabilities.contains(wl_seat::Capability::Keyboard) {
+                state.release_keyboard();
+            } else if state.wl_keyboard.is_none() {
+                state.wl_keyboard = Some(seat.get_keyboard(qh, ()));
+            }
+            if !capabilities.contains(wl_seat::Capability::Pointer) {
+                state.release_pointer();
+            } else if state.wl_pointer.is_none() {
+                state.wl_pointer = Some(seat.get_pointer(qh, ()));
+            }
+        }
+    }
+}
+
+impl Bar {
+    // Release request was added in version 3
+    pub(crate) fn release_keyboard(&mut self) {
+        if let Some(keyboard) = self.wl_keyboard.take() {
+            if keyboard.version() >= 3 {
+                keyboard.release();
+            }
+        }
+    }
+
+    pub(crate) fn release_pointer(&mut self) {
+        if let Some(pointer) = self.wl_pointer.take() {
+            if pointer.version() >= 3 {
+                pointer.release();
             }
         }
     }
@@ -31,13 +59,59 @@ impl Dispatch<wl_keyboard::WlKeyboard, ()> for Bar {
         event: wl_keyboard::Event,
         _: &(),
         _: &Connection,
-        _: &QueueHandle<Self>,
+        qh: &QueueHandle<Self>,
     ) {
-        if let wl_keyboard::Event::Key { key, .. } = event {
-            if key == 1 {
-                // ESC key
-                state.running = false;
+        match event {
+            wl_keyboard::Event::Key {
+                serial,
+                key,
+                state: WEnum::Value(wl_keyboard::KeyState::Pressed),
+                ..
+            } => {
+                // Bar gets keyboard focus only while a popup is open
+                state.popup_key(key, serial, qh);
+            }
+            wl_keyboard::Event::Keymap {
+                format: WEnum::Value(wl_keyboard::KeymapFormat::XkbV1),
+                fd,
+                size,
+            } => {
+                let Some(keyboard) = &state.keyboard else {
+                    return;
+                };
+                let file = std::fs::File::from(fd);
+                // Keymap fd must be mapped private since wl_keyboard version 7
+                let keymap = unsafe {
+                    memmap2::MmapOptions::new()
+                        .len(size as usize)
+                        .map_copy_read_only(&file)
+                };
+                match keymap {
+                    Ok(keymap) => {
+                        let keymap = String::from_utf8_lossy(&keymap);
+                        keyboard.set_keymap(keymap.trim_end_matches('\0'));
+                    }
+                    Err(err) => log::warn!("Can't map keymap: {err}"),
+                }
+            }
+            wl_keyboard::Event::Enter { .. } => {
+                if let Some(keyboard) = &state.keyboard {
+                    keyboard.set_focused(true);
+                }
+            }
+            wl_keyboard::Event::Leave { .. } => {
+                if let Some(keyboard) = &state.keyboard {
+                    keyboard.set_focused(false);
+                }
+            }
+            wl_keyboard::Event::Modifiers {
+                mods_locked, group, ..
+            } => {
+                if let Some(keyboard) = &state.keyboard {
+                    keyboard.set_modifiers(mods_locked, group);
+                }
             }
+            _ => (),
         }
     }
 }
@@ -0,0 +1,239 @@
+use wayland_client::{protocol::wl_pointer, Connection, Dispatch, QueueHandle, WEnum};
+
+use crate::popup;
+use crate::widget::PointerEvent;
+use crate::{Bar, OutputContext};
+
+#[derive(Default)]
+pub struct Visibility {
+    // Collapse bar into 1px strip at the edge and reveal it when pointer touches the strip
+    pub auto_hide: bool,
+    // River modes (see riverctl declare-mode) that reveal the bar while active
+    pub reveal_modes: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Show,
+    Hide,
+    Toggle,
+}
+
+impl Action {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "show" => Some(Self::Show),
+            "hide" => Some(Self::Hide),
+            "toggle" => Some(Self::Toggle),
+            _ => None,
+        }
+    }
+}
+
+impl OutputContext {
+    pub fn set_visible(&mut self, visible: bool) {
+        if self.visible == visible {
+            return;
+        }
+        self.visible = visible;
+        self.ready_to_draw = false;
+        if visible {
+            self.layer_surface.set_size(0, self.height as u32);
+            // Auto hidden bar is drawn over windows instead of pushing them away
+            self.layer_surface
+                .set_exclusive_zone(if self.auto_hide { 0 } else { self.height });
+            // For unmapped surface this is initial commit, the bar is drawn after configure
+            self.surface.commit();
+        } else if self.auto_hide {
+            self.layer_surface.set_size(0, 1);
+            self.layer_surface.set_exclusive_zone(0);
+            self.surface.commit();
+        } else {
+            self.layer_surface.set_exclusive_zone(0);
+            self.surface.attach(None, 0, 0);
+            self.surface.commit();
+        }
+    }
+
+    /// Presents transparent strip that catches pointer while bar is auto hidden
+    pub fn present_strip(&self) {
+        let Some(buffers) = &self.buffers else {
+            return;
+        };
+        self.surface.attach(Some(&buffers.strip), 0, 0);
+        self.surface.damage(0, 0, self.width, 1);
+        self.surface.commit();
+    }
+}
+
+/// Applies action to bars of output with given name, to bar with given index, or to all bars
+pub fn apply(
+    outputs_contexts: &mut [OutputContext],
+    output: Option<&str>,
+    action: Action,
+) -> Result<(), String> {
+    let selected: Vec<usize> = match output {
+        None => (0..outputs_contexts.len()).collect(),
+        Some(output) => {
+            // All bars of the output with that name
+            let named: Vec<usize> = (0..outputs_contexts.len())
+                .filter(|&index| outputs_contexts[index].name == output)
+                .collect();
+            if named.is_empty() {
+                let index = output
+                    .parse()
+                    .ok()
+                    .filter(|&index| index < outputs_contexts.len())
+                    .ok_or_else(|| format!("error: no output {output}"))?;
+                vec![index]
+            } else {
+                named
+            }
+        }
+    };
+    // Toggle of several outputs shouldn't leave them in different states
+    let visible = match action {
+        Action::Show => true,
+        Action::Hide => false,
+        Action::Toggle => !selected.iter().any(|&i| outputs_contexts[i].visible),
+    };
+    for i in selected {
+        outputs_contexts[i].set_visible(visible);
+        // Explicit request outlasts the mode
+        outputs_contexts[i].revealed_by_mode = false;
+    }
+    Ok(())
+}
+
+impl Bar {
+    pub fn set_river_mode(&mut self, mode: String) {
+        let reveal = self.visibility.reveal_modes.contains(&mode);
+        let mut outputs_contexts = self.outputs_contexts.lock().unwrap();
+        for output_context in outputs_contexts.iter_mut() {
+            // Only auto hidden bars are revealed, bars hidden on request stay hidden
+            if reveal && output_context.auto_hide && !output_context.visible {
+                output_context.set_visible(true);
+                output_context.revealed_by_mode = true;
+            } else if !reveal && output_context.revealed_by_mode {
+                output_context.set_visible(false);
+                output_context.revealed_by_mode = false;
+            }
+        }
+        self.revealed_by_mode = reveal;
+    }
+
+    /// Index and width of the output whose bar is under pointer
+    pub fn pointer_output(&self) -> Option<(usize, i32)> {
+        let surface = self.pointer_surface.as_ref()?;
+        self.outputs_contexts
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .find(|(_, context)| context.surface == *surface)
+            .map(|(index, context)| (index, context.width))
+    }
+}
+
+impl Dispatch<wl_pointer::WlPointer, ()> for Bar {
+    fn event(
+        state: &mut Self,
+        _: &wl_pointer::WlPointer,
+        event: wl_pointer::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_pointer::Event::Enter {
+                surface,
+                surface_x,
+                surface_y,
+                ..
+            } => {
+                state.pointer_x = surface_x;
+                state.pointer_y = surface_y;
+                state.pointer_surface = Some(surface.clone());
+                let mut outputs_contexts = state.outputs_contexts.lock().unwrap();
+                if let Some(output_context) = outputs_contexts
+                    .iter_mut()
+                    .find(|context| context.surface == surface)
+                {
+                    if output_context.auto_hide {
+                        output_context.set_visible(true);
+                    }
+                }
+                drop(outputs_contexts);
+                state.update_hover();
+            }
+            wl_pointer::Event::Leave { surface, .. } => {
+                state.pointer_x = -1.;
+                state.pointer_surface = None;
+                state.hovered = None;
+                state.hide_tooltip();
+                // Hiding the bar would take it's popup away too
+                if state.revealed_by_mode || state.popup.is_some() {
+                    return;
+                }
+                let mut outputs_contexts = state.outputs_contexts.lock().unwrap();
+                if let Some(output_context) = outputs_contexts
+                    .iter_mut()
+                    .find(|context| context.surface == surface)
+                {
+                    if output_context.auto_hide {
+                        output_context.set_visible(false);
+                    }
+                }
+            }
+            wl_pointer::Event::Motion {
+                surface_x,
+                surface_y,
+                ..
+            } => {
+                state.pointer_x = surface_x;
+                state.pointer_y = surface_y;
+                if state.is_pointer_on_popup() {
+                    let Some(popup::Content::Menu(menu)) =
+                        state.popup.as_mut().map(|popup| &mut popup.content)
+                    else {
+                        return;
+                    };
+                    if menu.hover(surface_y) {
+                        state.draw_popup();
+                    }
+                } else {
+                    state.update_hover();
+                }
+            }
+            wl_pointer::Event::Button {
+                serial,
+                button,
+                state: WEnum::Value(wl_pointer::ButtonState::Pressed),
+                ..
+            } => {
+                if state.is_pointer_on_popup() {
+                    state.menu_button(serial, qh);
+                    return;
+                }
+                state.hide_tooltip();
+                if !state.tray_button(serial, button)
+                    && !state.block_button(serial, button, qh)
+                    && !state.widget_pointer(PointerEvent::Button(button))
+                {
+                    state.taskbar_button(state.pointer_x, button);
+                }
+            }
+            wl_pointer::Event::Axis {
+                axis: WEnum::Value(wl_pointer::Axis::VerticalScroll),
+                value,
+                ..
+            } if !state.popup_scroll(value)
+                && !state.tray_scroll(value)
+                && !state.widget_pointer(PointerEvent::Scroll(value)) =>
+            {
+                state.taskbar_scroll(state.pointer_x, value);
+            }
+            _ => (),
+        }
+    }
+}
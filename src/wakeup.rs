@@ -0,0 +1,149 @@
+// Time shown by blocks can jump without any of their deadlines passing:
+// wall-clock time is set, timezone is changed or the system resumes from suspend.
+// Blocks thread updates all blocks when one of these happens. Resume and timezone
+// changes come from logind and timedated over the system bus, which is optional
+
+use std::{
+    io,
+    os::fd::RawFd,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::dbus::{self, Value};
+use crate::timer::TimerFd;
+
+const LOGIND_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const TIMEDATED_PATH: &str = "/org/freedesktop/timedate1";
+// Clock watch is armed this far in the future, it only matters when it's cancelled
+const YEAR: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+pub(crate) struct Wakeups {
+    // Realtime timer that is cancelled when the clock is set
+    clock: TimerFd,
+    bus: Option<dbus::Connection>,
+}
+
+impl Wakeups {
+    pub fn new() -> io::Result<Self> {
+        let clock = TimerFd::new(libc::CLOCK_REALTIME)?;
+        let bus = match connect_bus() {
+            Ok(bus) => Some(bus),
+            Err(err) => {
+                log::info!("No system bus, blocks aren't updated on resume: {err}");
+                None
+            }
+        };
+        let wakeups = Self { clock, bus };
+        wakeups.watch_clock();
+        Ok(wakeups)
+    }
+
+    pub fn clock_fd(&self) -> RawFd {
+        self.clock.fd()
+    }
+
+    pub fn bus_fd(&self) -> Option<RawFd> {
+        self.bus.as_ref().map(dbus::Connection::fd)
+    }
+
+    fn watch_clock(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.clock.set(
+            now + YEAR,
+            libc::TFD_TIMER_ABSTIME | libc::TFD_TIMER_CANCEL_ON_SET,
+        );
+    }
+
+    /// True if wall-clock time was set since the clock fd became readable
+    pub fn clock_changed(&self) -> bool {
+        let changed = matches!(
+            self.clock.read(),
+            Err(err) if err.raw_os_error() == Some(libc::ECANCELED)
+        );
+        // Cancelled or expired timer has to be armed again
+        self.watch_clock();
+        changed
+    }
+
+    /// True if the system resumed or timezone changed. Lost bus is dropped,
+    /// it's fd has to be removed from polling then
+    pub fn bus_changed(&mut self) -> bool {
+        let Some(bus) = &mut self.bus else {
+            return false;
+        };
+        match bus.read_messages() {
+            Ok(messages) => messages.iter().any(is_wakeup),
+            Err(err) => {
+                log::warn!("Lost connection to system bus: {err}");
+                self.bus = None;
+                false
+            }
+        }
+    }
+}
+
+fn is_wakeup(message: &dbus::Message) -> bool {
+    // Sent with true before suspend and false after resume
+    let resumed = message.is_signal(LOGIND_INTERFACE, "PrepareForSleep")
+        && message.body.first().and_then(Value::as_bool) == Some(false);
+    let timedated = message.path.as_deref() == Some(TIMEDATED_PATH)
+        && message.is_signal("org.freedesktop.DBus.Properties", "PropertiesChanged");
+    resumed || timedated
+}
+
+fn connect_bus() -> io::Result<dbus::Connection> {
+    let mut bus = dbus::Connection::system()?;
+    bus.add_match(&format!(
+        "type='signal',interface='{LOGIND_INTERFACE}',member='PrepareForSleep'"
+    ))?;
+    bus.add_match(&format!(
+        "type='signal',path='{TIMEDATED_PATH}',interface='org.freedesktop.DBus.Properties'"
+    ))?;
+    Ok(bus)
+}
+
+#[test]
+fn test_is_wakeup() {
+    let sleep = |start| {
+        dbus::Message::signal(
+            "/org/freedesktop/login1",
+            LOGIND_INTERFACE,
+            "PrepareForSleep",
+            vec![Value::Bool(start)],
+        )
+    };
+    assert!(!is_wakeup(&sleep(true)));
+    assert!(is_wakeup(&sleep(false)));
+
+    let properties_changed = |path| {
+        dbus::Message::signal(
+            path,
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+            Vec::new(),
+        )
+    };
+    assert!(is_wakeup(&properties_changed(TIMEDATED_PATH)));
+    let hostnamed = "/org/freedesktop/hostname1";
+    assert!(!is_wakeup(&properties_changed(hostnamed)));
+}
+
+#[test]
+fn test_clock_watch_stays_armed() {
+    let wakeups = Wakeups::new().unwrap();
+    let mut pfd = libc::pollfd {
+        fd: wakeups.clock_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // Nothing wakes up until the clock is set
+    assert_eq!(unsafe { libc::poll(&mut pfd, 1, 20) }, 0);
+    assert!(!wakeups.clock_changed());
+    assert_eq!(unsafe { libc::poll(&mut pfd, 1, 20) }, 0);
+    // Timer is rearmed about a year from now
+    let mut spec: libc::itimerspec = unsafe { std::mem::zeroed() };
+    unsafe { libc::timerfd_gettime(wakeups.clock_fd(), &mut spec) };
+    assert!(spec.it_value.tv_sec > YEAR.as_secs() as libc::time_t - 60);
+}
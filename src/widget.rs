@@ -0,0 +1,151 @@
+// Pieces of the bar. Tags, title and custom widgets are drawn by main thread
+// from left to right, blocks are drawn by blocks thread from right to left
+
+use std::os::fd::RawFd;
+
+use pangocairo::cairo as cr;
+use pangocairo::pango;
+
+use crate::decoration::Decorations;
+use crate::theme::Theme;
+use crate::{Bar, RiverStatus};
+
+/// What widget gets to measure and draw itself
+pub struct DrawContext<'a> {
+    pub cr: &'a cr::Context,
+    // Has the bar font set
+    pub pg_layout: &'a pango::Layout,
+    pub theme: &'a Theme,
+    pub decorations: &'a Decorations,
+    pub river: &'a RiverStatus,
+    // Height of the bar
+    pub height: i32,
+    // Width the widget may take, widgets that can shrink should fit into it
+    pub space: i32,
+}
+
+pub enum PointerEvent {
+    // Linux button code, e.g. 0x110 for left button
+    Button(u32),
+    // Positive is down
+    Scroll(f64),
+}
+
+pub trait Widget {
+    /// Width that widget takes on the bar
+    fn measure(&mut self, context: &DrawContext) -> i32;
+
+    /// Draws widget between x and x + width, width is the one returned by measure.
+    /// Background is not cleared before
+    fn draw(&mut self, context: &DrawContext, x: i32, width: i32);
+
+    /// x is relative to left edge of the widget. Returns true if event was handled
+    fn pointer(&mut self, _x: f64, _event: PointerEvent) -> bool {
+        false
+    }
+
+    /// Widget has changed since it was drawn and bar should be drawn again
+    fn is_dirty(&self) -> bool {
+        false
+    }
+
+    /// Descriptor that becomes readable when widget has something new, update is called then
+    fn fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    fn update(&mut self) {}
+}
+
+/// Title of focused view
+#[derive(Default)]
+pub struct Title;
+
+impl Title {
+    // Sets title on the layout, ellipsized when it doesn't fit into space
+    fn layout(context: &DrawContext) -> (i32, i32) {
+        let pg_layout = context.pg_layout;
+        pg_layout.set_attributes(None);
+        pg_layout.set_text(&context.river.title);
+        let max_width = (context.space - context.decorations.title.padding * 2).max(0);
+        if pg_layout.pixel_size().0 > max_width {
+            pg_layout.set_width(max_width * pango::SCALE);
+            pg_layout.set_ellipsize(pango::EllipsizeMode::End);
+        }
+        pg_layout.pixel_size()
+    }
+
+    // Layout is shared with the rest of the bar
+    fn reset_layout(context: &DrawContext) {
+        context.pg_layout.set_width(-1);
+        context.pg_layout.set_ellipsize(pango::EllipsizeMode::None);
+    }
+}
+
+impl Widget for Title {
+    fn measure(&mut self, context: &DrawContext) -> i32 {
+        let (text_width, _) = Self::layout(context);
+        Self::reset_layout(context);
+        context
+            .decorations
+            .title
+            .outer_width(text_width)
+            .min(context.space.max(0))
+    }
+
+    fn draw(&mut self, context: &DrawContext, x: i32, width: i32) {
+        // Blocks took all the space
+        if width == 0 {
+            return;
+        }
+        let decoration = &context.decorations.title;
+        let (_, text_height) = Self::layout(context);
+        decoration.draw_background(
+            context.cr,
+            x as f64,
+            width as f64,
+            context.height as f64,
+            &context.theme.widget_bg,
+        );
+        context.theme.title_fg.set_source(context.cr);
+        context.cr.move_to(
+            (x + decoration.padding) as f64,
+            (context.height - text_height) as f64 / 2.,
+        );
+        pangocairo::show_layout(context.cr, context.pg_layout);
+        Self::reset_layout(context);
+    }
+}
+
+impl Bar {
+    /// Passes pointer event to custom widget under pointer. Returns false if there is none
+    pub fn widget_pointer(&mut self, event: PointerEvent) -> bool {
+        let Some((output_index, _)) = self.pointer_output() else {
+            return false;
+        };
+        let x = self.pointer_x;
+        let outputs_contexts = self.outputs_contexts.lock().unwrap();
+        let Some((index, &(start, _))) = outputs_contexts[output_index]
+            .widget_spans
+            .iter()
+            .enumerate()
+            .find(|(_, (start, end))| (*start as f64..*end as f64).contains(&x))
+        else {
+            return false;
+        };
+        drop(outputs_contexts);
+        let handled = self.widgets[index].pointer(x - start as f64, event);
+        if self.widgets[index].is_dirty() {
+            self.draw_tags_and_title();
+        }
+        handled
+    }
+
+    /// Called when descriptor of the widget is readable
+    pub fn update_widget(&mut self, index: usize) {
+        self.widgets[index].update();
+        if self.widgets[index].is_dirty() {
+            self.draw_tags_and_title();
+        }
+    }
+}